@@ -0,0 +1,132 @@
+//! Loopback load benchmark of the NTP serving path, one run per worker
+//! count:
+//!
+//! ```text
+//! cargo bench --bench loopback
+//! ```
+
+#[macro_use]
+extern crate log;
+
+// The backend is a binary crate, the benchmark compiles its ntp module.
+#[allow(warnings, clippy::all)]
+#[path = "../src/ntp/mod.rs"]
+mod ntp;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ntp::acl::Acl;
+use ntp::broadcast::BroadcastConfig;
+use ntp::listen::ListenConfig;
+use ntp::local_clock::SystemClock;
+use ntp::rate_limit::RateLimitConfig;
+use ntp::{NtpAuth, NtpServer, NtpServerConfig, NtpSourcePriority};
+
+const DURATION: Duration = Duration::from_secs(3);
+const CLIENTS: usize = 8;
+// Requests each client keeps in flight.
+const WINDOW: usize = 16;
+
+fn config(port: u16, workers: usize) -> NtpServerConfig {
+    NtpServerConfig {
+        unsync_silent: false,
+        interleaved: true,
+        holdover_timeout: Duration::from_secs(60),
+        priority: NtpSourcePriority { gps: 1, ntp: 2, rtc: 3 },
+        holdover_drift: 1e-6,
+        holdover_max_error: 1e-2,
+        rate_limit: RateLimitConfig {
+            enable: false,
+            interval: Duration::from_secs(1),
+            burst: 1,
+            kod: false,
+            table_size: 1,
+        },
+        acl: Acl::new(&[]).unwrap(),
+        listen: ListenConfig {
+            addresses: vec![String::from("127.0.0.1")],
+            port,
+            workers,
+        },
+        cmdmon: ListenConfig {
+            addresses: vec![],
+            port: 0,
+            workers: 1,
+        },
+        broadcast: BroadcastConfig {
+            enable: false,
+            destinations: vec![],
+            port: 0,
+            interval: Duration::from_secs(64),
+            key_id: None,
+            ttl: 1,
+        },
+    }
+}
+
+/// Sends windows of client requests until `stop` is set, counting answers.
+fn client(server: SocketAddr, stop: Arc<AtomicBool>, answered: Arc<AtomicU64>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(server).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    let mut request = [0u8; 48];
+    request[0] = 0x23;
+    let mut response = [0u8; 1024];
+    let mut sequence: u64 = 1;
+    while !stop.load(Ordering::Relaxed) {
+        for _ in 0..WINDOW {
+            request[40..48].copy_from_slice(&sequence.to_be_bytes());
+            sequence += 1;
+            socket.send(&request).unwrap();
+        }
+        for _ in 0..WINDOW {
+            if socket.recv(&mut response).is_err() {
+                break;
+            }
+            answered.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn requests_per_second(port: u16, workers: usize) -> f64 {
+    let auth = Arc::new(RwLock::new(NtpAuth::new(vec![], vec![])));
+    let server = NtpServer::new(false, config(port, workers), auth, None, Arc::new(SystemClock)).await;
+    server.run().await;
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let stop = Arc::new(AtomicBool::new(false));
+    let answered = Arc::new(AtomicU64::new(0));
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            let stop = Arc::clone(&stop);
+            let answered = Arc::clone(&answered);
+            thread::spawn(move || client(addr, stop, answered))
+        })
+        .collect();
+
+    let start = Instant::now();
+    tokio::time::sleep(DURATION).await;
+    stop.store(true, Ordering::Relaxed);
+    let elapsed = start.elapsed();
+    for client in clients {
+        client.join().unwrap();
+    }
+    answered.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    let cpus = thread::available_parallelism().map_or(1, |count| count.get());
+    let mut counts = vec![1, 2, cpus];
+    counts.sort();
+    counts.dedup();
+    for (index, workers) in counts.into_iter().enumerate() {
+        let rate = requests_per_second(12_300 + index as u16, workers).await;
+        println!("{:>3} workers: {:>10.0} requests/s", workers, rate);
+    }
+}
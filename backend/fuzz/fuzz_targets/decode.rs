@@ -0,0 +1,27 @@
+//! Feeds arbitrary datagrams to the packet decoder. Nothing may panic, and
+//! whatever is accepted must encode back to the same bytes.
+//!
+//! Run from `backend/` with `cargo +nightly fuzz run decode`.
+
+#![no_main]
+
+#[macro_use]
+extern crate log;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../../src/ntp/mod.rs"]
+mod ntp;
+
+use std::net::SocketAddr;
+
+use libfuzzer_sys::fuzz_target;
+
+use ntp::codec::{decode, encode};
+use ntp::NtpTimestamp;
+
+fuzz_target!(|data: &[u8]| {
+    let addr = SocketAddr::from(([192, 0, 2, 1], 123));
+    if let Ok(packet) = decode(data, addr, NtpTimestamp::zero()) {
+        assert_eq!(encode(&packet), data);
+    }
+});
@@ -23,6 +23,9 @@ pub struct DiagnosticPacket {
     pub DownlinkSpeed: u64,
     pub DownlinkData: u64,
     pub Uptime: u64,
+    /// Packet timestamping of the NTP sockets, `none` when none is bound.
+    #[serde(rename = "Timestamping")]
+    pub timestamping: String,
 }
 
 impl MonitoringPacket {
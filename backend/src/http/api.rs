@@ -1,16 +1,15 @@
 
-use std::sync::Arc;
-
-
 use rocket::data::ByteUnit;
 use rocket::http::Status;
 use rocket::{routes, Route, State, get, post, Data};
 
 use crate::diagnostic::types::DiagnosticPacket;
-use crate::http::state::{AppState, self};
+use crate::http::state::AppState;
 use crate::services::login::{RequestPayload, ResponsePayload};
 use crate::services::network::{GetRequestPayload, SetRequestPayload, Config};
-use crate::settings::store::{Ntp, Display, RTC, Settings, Gps};
+use crate::settings::store::{Ntp, Display, Settings, Gps, Server, Listen, Broadcast, Cmdmon, Auth, Nts, RateLimit};
+use crate::ntp::acl::{Acl, AclRule};
+use crate::ntp::auth::{self, KeyConfig};
 
 
 
@@ -35,7 +34,29 @@ pub struct  Api{
                 get_network,
                 set_network,
                 get_monitor,
-                get_sys_info
+                get_sys_info,
+                get_server,
+                set_server,
+                get_sources,
+                get_auth,
+                set_auth,
+                get_keys,
+                set_keys,
+                get_nts,
+                set_nts,
+                get_rate_limit,
+                set_rate_limit,
+                get_rate_stats,
+                get_parse_errors,
+                get_acl,
+                set_acl,
+                get_listen,
+                set_listen,
+                get_listen_status,
+                get_broadcast,
+                set_broadcast,
+                get_cmdmon,
+                set_cmdmon
 
                 ];
             Self{list}
@@ -55,7 +76,8 @@ pub struct  Api{
 )]
 #[get("/settings")]
 pub async fn get_settings(state: &State<AppState>) -> Result<String, Status> {
-    let store = state.store.lock().await.get_settings();
+    let mut store = state.store.lock().await.get_settings();
+    store.nts.cookie_keys.clear();
     Ok(serde_json::to_string_pretty(&store).unwrap())
 } 
 /// Get NTP
@@ -124,6 +146,22 @@ pub async fn get_rtc(state: &State<AppState>) -> Result<String, Status> {
 
 
 
+/// Get NTP server settings
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Current server settings", body = Server)
+    )
+    ,
+    params(
+),
+)]
+#[get("/server")]
+pub async fn get_server(state: &State<AppState>) -> Result<String, Status> {
+    let server = state.store.lock().await.get_server();
+    Ok(serde_json::to_string_pretty(&server).unwrap())
+}
+
 /// Update settings
 #[utoipa::path(
     context_path = "/api/v1",
@@ -192,6 +230,28 @@ pub async fn set_ntp(values: Data<'_>,state: &State<AppState>) -> Result<String,
     state.driver.lock().await.Backup(store.clone()).await;
     Ok(serde_json::to_string_pretty(&values).unwrap())
 }
+/// Update NTP server settings, they are saved and take effect on the next restart
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = Server,
+    responses(
+        (status = 200, description = "Saved, applied on the next restart"),
+        (status = 400, description = "Malformed settings")
+    )
+    ,
+
+    params(
+        ),
+)]
+#[post("/server", data="<values>")]
+pub async fn set_server(values: Data<'_>,state: &State<AppState>) -> Result<String, Status> {
+    let payload = values.open(ByteUnit::MB).into_string().await.unwrap();
+    let payload_str = payload.as_str();
+    let values: Server = serde_json::from_str(payload_str).map_err(|_| Status::BadRequest)?;
+    state.store.lock().await.set_server(values.clone());
+    save_settings(state).await?;
+    Ok(serde_json::to_string_pretty(&values).unwrap())
+}
 /// Update Display
 #[utoipa::path(
     context_path = "/api/v1",
@@ -323,7 +383,9 @@ let result = state.network.lock().await.set_network(SetRequestPayload{type_:Stri
 ),)]
 #[get("/status")]
 pub async fn get_monitor(state: &State<AppState>) -> Result<String, Status> {
-    Ok(state.monitor.lock().await.get_json().await.unwrap())
+    let mut status = serde_json::to_value(&*state.monitor.lock().await).unwrap();
+    status["server"] = serde_json::to_value(state.server.lock().await.status().await).unwrap();
+    Ok(serde_json::to_string_pretty(&status).unwrap())
     }
 
 /// Get system info
@@ -350,8 +412,416 @@ let mut diag =state.info.lock().await;
             DownlinkSpeed: diag.DownlinkSpeed,
             DownlinkData: diag.DownlinkData,
             Uptime: diag.Uptime,
+            timestamping: state
+                .server
+                .lock()
+                .await
+                .timestamping()
+                .await
+                .map_or(String::from("none"), |mode| mode.to_string()),
         };
         Ok(serde_json::to_string_pretty(&packet).unwrap())
         }
     
    
+
+/// Get time source selection
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Candidates, their selection status and the system peer", body = Selection)
+    )
+    ,
+    params(
+),
+)]
+#[get("/ntp/sources")]
+pub async fn get_sources(state: &State<AppState>) -> Result<String, Status> {
+    let selection = state.server.lock().await.selection().await;
+    Ok(serde_json::to_string_pretty(&selection).unwrap())
+}
+
+
+/// Get NTP authentication settings
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Current authentication settings", body = Auth)
+    )
+    ,
+    params(
+),
+)]
+#[get("/auth")]
+pub async fn get_auth(state: &State<AppState>) -> Result<String, Status> {
+    let auth = state.store.lock().await.get_auth();
+    Ok(serde_json::to_string_pretty(&auth).unwrap())
+}
+
+/// Update NTP authentication settings, networks apply immediately
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = Auth,
+    responses(
+        (status = 200, description = "Update is Success")
+    )
+    ,
+
+    params(
+        ),
+)]
+#[post("/auth", data="<values>")]
+pub async fn set_auth(values: Data<'_>,state: &State<AppState>) -> Result<String, Status> {
+    let payload = values.open(ByteUnit::MB).into_string().await.unwrap();
+    let payload_str = payload.as_str();
+    let values: Auth = serde_json::from_str(payload_str).map_err(|_| Status::BadRequest)?;
+    state.store.lock().await.set_auth(values.clone());
+    save_settings(state).await?;
+    let networks = auth::parse_networks(&values.required_networks);
+    state.server.lock().await.auth().write().unwrap().set_required(networks);
+    Ok(serde_json::to_string_pretty(&values).unwrap())
+}
+
+/// Get configured symmetric keys, without their secrets
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Key IDs and digest types", body = [Key])
+    )
+    ,
+    params(
+),
+)]
+#[get("/ntp/keys")]
+pub async fn get_keys(state: &State<AppState>) -> Result<String, Status> {
+    let keys = state.server.lock().await.auth().read().unwrap().keys();
+    Ok(serde_json::to_string_pretty(&keys).unwrap())
+}
+
+/// Replace the symmetric keys and rewrite the keys file
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = [KeyConfig],
+    responses(
+        (status = 200, description = "Update is Success", body = [Key]),
+        (status = 400, description = "Invalid key")
+    )
+    ,
+
+    params(
+        ),
+)]
+#[post("/ntp/keys", data="<values>")]
+pub async fn set_keys(values: Data<'_>,state: &State<AppState>) -> Result<String, Status> {
+    let payload = values.open(ByteUnit::MB).into_string().await.unwrap();
+    let payload_str = payload.as_str();
+    let values: Vec<KeyConfig> = serde_json::from_str(payload_str).map_err(|_| Status::BadRequest)?;
+    let keys = values
+        .iter()
+        .map(auth::Key::new)
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|_| Status::BadRequest)?;
+    let path = state.store.lock().await.get_auth().keys_file;
+    if let Err(e) = auth::save_keys(&path, &keys).await {
+        error!("Failed to write keys file {}: {}", path, e);
+        return Err(Status::InternalServerError);
+    }
+    let auth = state.server.lock().await.auth();
+    auth.write().unwrap().set_keys(keys.clone());
+    Ok(serde_json::to_string_pretty(&keys).unwrap())
+}
+
+/// Get NTS settings, cookie keys are never returned
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Current NTS settings", body = Nts)
+    )
+    ,
+    params(
+),
+)]
+#[get("/nts")]
+pub async fn get_nts(state: &State<AppState>) -> Result<String, Status> {
+    let mut nts = state.store.lock().await.get_nts();
+    nts.cookie_keys.clear();
+    Ok(serde_json::to_string_pretty(&nts).unwrap())
+}
+
+/// Update NTS settings, applied on restart
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = Nts,
+    responses(
+        (status = 200, description = "Update is Success")
+    )
+    ,
+
+    params(
+        ),
+)]
+#[post("/nts", data="<values>")]
+pub async fn set_nts(values: Data<'_>,state: &State<AppState>) -> Result<String, Status> {
+    let payload = values.open(ByteUnit::MB).into_string().await.unwrap();
+    let payload_str = payload.as_str();
+    let mut values: Nts = serde_json::from_str(payload_str).map_err(|_| Status::BadRequest)?;
+    state.store.lock().await.set_nts(values.clone());
+    save_settings(state).await?;
+    values.cookie_keys.clear();
+    Ok(serde_json::to_string_pretty(&values).unwrap())
+}
+
+/// Get rate limiting settings
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Current rate limiting settings", body = RateLimit)
+    )
+    ,
+    params(
+),
+)]
+#[get("/ratelimit")]
+pub async fn get_rate_limit(state: &State<AppState>) -> Result<String, Status> {
+    let rate_limit = state.store.lock().await.get_rate_limit();
+    Ok(serde_json::to_string_pretty(&rate_limit).unwrap())
+}
+
+/// Update rate limiting settings, applied immediately
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = RateLimit,
+    responses(
+        (status = 200, description = "Update is Success")
+    )
+    ,
+
+    params(
+        ),
+)]
+#[post("/ratelimit", data="<values>")]
+pub async fn set_rate_limit(values: Data<'_>,state: &State<AppState>) -> Result<String, Status> {
+    let payload = values.open(ByteUnit::MB).into_string().await.unwrap();
+    let payload_str = payload.as_str();
+    let values: RateLimit = serde_json::from_str(payload_str).map_err(|_| Status::BadRequest)?;
+    state.store.lock().await.set_rate_limit(values.clone());
+    save_settings(state).await?;
+    state.server.lock().await.set_rate_limit(values.config()).await;
+    Ok(serde_json::to_string_pretty(&values).unwrap())
+}
+
+/// Get rate limiting counters
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Passed, dropped and kissed requests", body = RateStats)
+    )
+    ,
+    params(
+),
+)]
+#[get("/ntp/ratelimit")]
+pub async fn get_rate_stats(state: &State<AppState>) -> Result<String, Status> {
+    let stats = state.server.lock().await.rate_stats().await;
+    Ok(serde_json::to_string_pretty(&stats).unwrap())
+}
+
+/// Get counters of malformed NTP packets
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Dropped packets by parse error", body = ParseErrorStats)
+    )
+    ,
+    params(
+),
+)]
+#[get("/ntp/errors")]
+pub async fn get_parse_errors(state: &State<AppState>) -> Result<String, Status> {
+    let stats = state.server.lock().await.parse_errors();
+    Ok(serde_json::to_string_pretty(&stats).unwrap())
+}
+
+/// Get NTP access control rules
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Access control rules", body = [AclRule])
+    )
+    ,
+    params(
+),
+)]
+#[get("/ntp/acl")]
+pub async fn get_acl(state: &State<AppState>) -> Result<String, Status> {
+    let acl = state.store.lock().await.get_acl();
+    Ok(serde_json::to_string_pretty(&acl).unwrap())
+}
+
+/// Replace NTP access control rules, applied immediately
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = [AclRule],
+    responses(
+        (status = 200, description = "Update is Success", body = [AclRule]),
+        (status = 400, description = "Invalid network or action")
+    )
+    ,
+
+    params(
+        ),
+)]
+#[post("/ntp/acl", data="<values>")]
+pub async fn set_acl(values: Data<'_>,state: &State<AppState>) -> Result<String, Status> {
+    let payload = values.open(ByteUnit::MB).into_string().await.unwrap();
+    let payload_str = payload.as_str();
+    let values: Vec<AclRule> = serde_json::from_str(payload_str).map_err(|_| Status::BadRequest)?;
+    let acl = Acl::new(&values).map_err(|_| Status::BadRequest)?;
+    state.store.lock().await.set_acl(values.clone());
+    save_settings(state).await?;
+    state.server.lock().await.set_acl(acl).await;
+    Ok(serde_json::to_string_pretty(&values).unwrap())
+}
+
+/// Get NTP listen addresses
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Configured addresses and port", body = Listen)
+    )
+    ,
+    params(
+),
+)]
+#[get("/listen")]
+pub async fn get_listen(state: &State<AppState>) -> Result<String, Status> {
+    let listen = state.store.lock().await.get_listen();
+    Ok(serde_json::to_string_pretty(&listen).unwrap())
+}
+
+/// Update NTP listen addresses, sockets are rebound immediately
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = Listen,
+    responses(
+        (status = 200, description = "Result of binding each address", body = [BindStatus])
+    )
+    ,
+
+    params(
+        ),
+)]
+#[post("/listen", data="<values>")]
+pub async fn set_listen(values: Data<'_>,state: &State<AppState>) -> Result<String, Status> {
+    let payload = values.open(ByteUnit::MB).into_string().await.unwrap();
+    let payload_str = payload.as_str();
+    let values: Listen = serde_json::from_str(payload_str).map_err(|_| Status::BadRequest)?;
+    state.store.lock().await.set_listen(values.clone());
+    save_settings(state).await?;
+    let status = state.server.lock().await.set_listen(values.config()).await;
+    Ok(serde_json::to_string_pretty(&status).unwrap())
+}
+
+/// Get the bound NTP sockets and bind errors
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Result of binding each address", body = [BindStatus])
+    )
+    ,
+    params(
+),
+)]
+#[get("/ntp/listen")]
+pub async fn get_listen_status(state: &State<AppState>) -> Result<String, Status> {
+    let status = state.server.lock().await.listen_status().await;
+    Ok(serde_json::to_string_pretty(&status).unwrap())
+}
+
+/// Get broadcast server settings
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Current broadcast settings", body = Broadcast)
+    )
+    ,
+    params(
+),
+)]
+#[get("/broadcast")]
+pub async fn get_broadcast(state: &State<AppState>) -> Result<String, Status> {
+    let broadcast = state.store.lock().await.get_broadcast();
+    Ok(serde_json::to_string_pretty(&broadcast).unwrap())
+}
+
+/// Update broadcast server settings, applied immediately
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = Broadcast,
+    responses(
+        (status = 200, description = "Update is Success", body = Broadcast)
+    )
+    ,
+
+    params(
+        ),
+)]
+#[post("/broadcast", data="<values>")]
+pub async fn set_broadcast(values: Data<'_>,state: &State<AppState>) -> Result<String, Status> {
+    let payload = values.open(ByteUnit::MB).into_string().await.unwrap();
+    let payload_str = payload.as_str();
+    let values: Broadcast = serde_json::from_str(payload_str).map_err(|_| Status::BadRequest)?;
+    state.store.lock().await.set_broadcast(values.clone());
+    save_settings(state).await?;
+    state.server.lock().await.set_broadcast(values.config()).await;
+    Ok(serde_json::to_string_pretty(&values).unwrap())
+}
+
+/// Get chrony monitoring protocol settings
+#[utoipa::path(
+    context_path = "/api/v1",
+    responses(
+        (status = 200, description = "Current chrony monitoring settings", body = Cmdmon)
+    )
+    ,
+    params(
+),
+)]
+#[get("/cmdmon")]
+pub async fn get_cmdmon(state: &State<AppState>) -> Result<String, Status> {
+    let cmdmon = state.store.lock().await.get_cmdmon();
+    Ok(serde_json::to_string_pretty(&cmdmon).unwrap())
+}
+
+/// Update chrony monitoring protocol settings, sockets are rebound immediately
+#[utoipa::path(
+    context_path = "/api/v1",
+    request_body = Cmdmon,
+    responses(
+        (status = 200, description = "Result of binding each address", body = [BindStatus])
+    )
+    ,
+
+    params(
+        ),
+)]
+#[post("/cmdmon", data="<values>")]
+pub async fn set_cmdmon(values: Data<'_>,state: &State<AppState>) -> Result<String, Status> {
+    let payload = values.open(ByteUnit::MB).into_string().await.unwrap();
+    let payload_str = payload.as_str();
+    let values: Cmdmon = serde_json::from_str(payload_str).map_err(|_| Status::BadRequest)?;
+    state.store.lock().await.set_cmdmon(values.clone());
+    save_settings(state).await?;
+    let status = state.server.lock().await.set_cmdmon(values.config()).await;
+    Ok(serde_json::to_string_pretty(&status).unwrap())
+}
+
+/// Persists the current settings, failing the request if they can't be written.
+async fn save_settings(state: &State<AppState>) -> Result<(), Status> {
+    let settings = state.store.lock().await.get_settings();
+    if let Err(e) = state.driver.lock().await.Backup(settings).await {
+        error!("Failed to save settings: {}", e);
+        return Err(Status::InternalServerError);
+    }
+    Ok(())
+}
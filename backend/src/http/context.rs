@@ -9,19 +9,20 @@ use rocket::http::Method;
 
 use rocket::http::Header;
 use rocket::Request;
-use crate::{settings::interfaces::IStore, services::{login::LoginSRC, network::{NetworkSRC, GetRequestPayload}}, ntp::request::MonitorSender};
+use crate::{settings::interfaces::IStore, services::{login::LoginSRC, network::{NetworkSRC, GetRequestPayload}}, ntp::{request::MonitorSender, NtpServer}};
 
 use super::{swagger::ApiDoc, state::AppState, api::Api, interfaces::Iapi};
 
 
 
 
-pub async fn get_rocket(config:Config, store:Arc<Mutex<dyn Iapi>>,api:Api, driver: Arc<Mutex<dyn IStore>>,login_detector: Arc<Mutex<LoginSRC>>, network: Arc<Mutex<NetworkSRC>>, monitor: Arc<Mutex<MonitorSender>>)->Rocket<Build>{
+#[allow(clippy::too_many_arguments)]
+pub async fn get_rocket(config:Config, store:Arc<Mutex<dyn Iapi>>,api:Api, driver: Arc<Mutex<dyn IStore>>,login_detector: Arc<Mutex<LoginSRC>>, network: Arc<Mutex<NetworkSRC>>, monitor: Arc<Mutex<MonitorSender>>, server: Arc<Mutex<NtpServer>>)->Rocket<Build>{
     write_config_js(Arc::clone(&network)).await;
 
     rocket::custom(config)
     
-    .manage(AppState::new(Arc::clone(&store), driver,Arc::clone(&login_detector),Arc::clone(&network),Arc::clone(&monitor)))
+    .manage(AppState::new(Arc::clone(&store), driver,Arc::clone(&login_detector),Arc::clone(&network),Arc::clone(&monitor),Arc::clone(&server)))
     .mount(
         "/",
         SwaggerUi::new("/api/v1/swagger/<_..>").url("/api-docs/openapi.json", ApiDoc::openapi()),
@@ -3,7 +3,8 @@
 use serde::{Serialize, Deserialize};
 use utoipa::ToSchema;
 
-use crate::settings::{store::{Settings, Ntp, Gps, RTC, Display}, self};
+use crate::ntp::acl::AclRule;
+use crate::settings::{store::{Settings, Ntp, Gps, RTC, Display, Server, Listen, Broadcast, Cmdmon, Auth, Nts, CookieKey, RateLimit}, self};
 
 
 
@@ -19,5 +20,22 @@ fn set_ntp(&mut self, ntp:Ntp);
 fn set_gps(&mut self, gps:Gps);
 fn set_display(&mut self, display:Display);
 fn set_rtc(&mut self, rtc:RTC);
+fn get_server(&self)->Server;
+fn set_server(&mut self, server:Server);
+fn get_listen(&self)->Listen;
+fn set_listen(&mut self, listen:Listen);
+fn get_broadcast(&self)->Broadcast;
+fn set_broadcast(&mut self, broadcast:Broadcast);
+fn get_cmdmon(&self)->Cmdmon;
+fn set_cmdmon(&mut self, cmdmon:Cmdmon);
+fn get_auth(&self)->Auth;
+fn set_auth(&mut self, auth:Auth);
+fn get_nts(&self)->Nts;
+fn set_nts(&mut self, nts:Nts);
+fn set_cookie_keys(&mut self, keys:Vec<CookieKey>);
+fn get_rate_limit(&self)->RateLimit;
+fn set_rate_limit(&mut self, rate_limit:RateLimit);
+fn get_acl(&self)->Vec<AclRule>;
+fn set_acl(&mut self, acl:Vec<AclRule>);
 }
 
@@ -1,7 +1,7 @@
 use std::sync::{Arc};
 use tokio::sync::Mutex;
 
-use crate::{settings::interfaces::IStore, services::{login::LoginSRC, network::NetworkSRC}, ntp::request::MonitorSender, diagnostic::types::MonitoringPacket};
+use crate::{settings::interfaces::IStore, services::{login::LoginSRC, network::NetworkSRC}, ntp::{request::MonitorSender, NtpServer}, diagnostic::types::MonitoringPacket};
 
 use super::interfaces::Iapi;
 
@@ -13,13 +13,14 @@ pub struct AppState {
     pub login_detector: Arc<Mutex<LoginSRC>>,
     pub network: Arc<Mutex<NetworkSRC>>,
     pub monitor: Arc<Mutex<MonitorSender>>,
+    pub server: Arc<Mutex<NtpServer>>,
     pub info: Arc<Mutex<MonitoringPacket>>
 }
 
 impl AppState {
-    pub fn new(store: Arc<Mutex<dyn Iapi>>, driver: Arc<Mutex<dyn IStore>>,login_detector: Arc<Mutex<LoginSRC>>,network: Arc<Mutex<NetworkSRC>>, monitor: Arc<Mutex<MonitorSender>>)-> Self {
+    pub fn new(store: Arc<Mutex<dyn Iapi>>, driver: Arc<Mutex<dyn IStore>>,login_detector: Arc<Mutex<LoginSRC>>,network: Arc<Mutex<NetworkSRC>>, monitor: Arc<Mutex<MonitorSender>>, server: Arc<Mutex<NtpServer>>)-> Self {
         Self {
-            store: Arc::clone(&store), driver, login_detector,network,monitor,info:Arc::new(Mutex::new(MonitoringPacket::new()))
+            store: Arc::clone(&store), driver, login_detector,network,monitor,server,info:Arc::new(Mutex::new(MonitoringPacket::new()))
         }
     }
 }
@@ -1,5 +1,5 @@
 
-use crate::{http::api, settings::store::{Display, RTC, Gps, Ntp, Settings}, services::{login::RequestPayload as LoginRequestPayload, network::{GetResponsePayload, GetRequestPayload, Config}}, diagnostic::types::DiagnosticPacket};
+use crate::{http::api, settings::store::{Display, RTC, Gps, Ntp, Settings, Server, Listen, Broadcast, Cmdmon, Auth, Nts, CookieKey, RateLimit}, services::{login::RequestPayload as LoginRequestPayload, network::{GetResponsePayload, GetRequestPayload, Config}}, diagnostic::types::DiagnosticPacket, ntp::selection::{Selection, Candidate, CandidateStatus}, ntp::auth::{Key, KeyConfig, KeyType}, ntp::rate_limit::RateStats, ntp::codec::ParseErrorStats, ntp::acl::{AclRule, AclAction}, ntp::listen::BindStatus, ntp::timestamping::TimestampMode};
 use utoipa::OpenApi;
 #[derive(OpenApi)]
 #[openapi(
@@ -18,12 +18,34 @@ use utoipa::OpenApi;
      api::get_network,
      api::set_network,
      api::get_monitor,
-     api::get_sys_info
+     api::get_sys_info,
+     api::get_server,
+     api::set_server,
+     api::get_sources,
+     api::get_auth,
+     api::set_auth,
+     api::get_keys,
+     api::set_keys,
+     api::get_nts,
+     api::set_nts,
+     api::get_rate_limit,
+     api::set_rate_limit,
+     api::get_rate_stats,
+     api::get_parse_errors,
+     api::get_acl,
+     api::set_acl,
+     api::get_listen,
+     api::set_listen,
+     api::get_listen_status,
+     api::get_broadcast,
+     api::set_broadcast,
+     api::get_cmdmon,
+     api::set_cmdmon
 
 
     ),
     components(
-        schemas(Settings,Ntp,Gps,RTC,Display, LoginRequestPayload,GetResponsePayload,Config,DiagnosticPacket),
+        schemas(Settings,Ntp,Gps,RTC,Display,Server, LoginRequestPayload,GetResponsePayload,Config,DiagnosticPacket,Selection,Candidate,CandidateStatus,Auth,Key,KeyConfig,KeyType,Nts,CookieKey,RateLimit,RateStats,ParseErrorStats,AclRule,AclAction,Listen,BindStatus,TimestampMode,Broadcast,Cmdmon),
     ),
     tags(
         (name = "NTP Service Web API", description = "Integration API")
@@ -2,11 +2,17 @@
 extern crate log;
 
 use std::env;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use env_logger::Env;
 use ntp::request::MonitorSender;
+use ntp::NtpSample;
+use ntp::acl::Acl;
+use ntp::listen::ListenConfig;
+use ntp::local_clock::{Clock, SystemClock};
+use ntp::nts::{self, CookieJar, KeConfig, MasterKey};
+use ntp::{NtpAuth, NtpServerConfig, NtpSourcePriority};
 use ntp::NtpTimestamp;
 use rocket::data::N;
 use rocket::Config;
@@ -14,7 +20,6 @@ use settings::interfaces::IStore;
 use settings::store::Keeper;
 use tokio::sync::Mutex;
 use tokio::task;
-use tokio::time::sleep;
 
 use crate::diagnostic::types::DiagnosticPacket;
 use crate::http::api::Api;
@@ -30,6 +35,7 @@ mod ntp;
 mod services;
 mod settings;
 use settings::store::Settings;
+use settings::store::CookieKey;
 mod diagnostic;
 use diagnostic::types::MonitoringPacket;
 #[tokio::main]
@@ -41,26 +47,112 @@ async fn main() {
     )));
     let settings = drviver.lock().await.Restore().await.unwrap();
     let env = Env::default().filter_or("MY_LOG_LEVEL", "info");
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
     let monitor = Arc::new(Mutex::new(MonitorSender {
-        last_ntp: NtpTimestamp::new(1),
-        last_gps: NtpTimestamp::new(1),
-        actial: NtpTimestamp::new(1),
+        last_ntp: NtpTimestamp::zero(),
+        last_gps: NtpTimestamp::zero(),
+        actial: NtpTimestamp::zero(),
         satilite: 0,
     }));
 
     env_logger::init_from_env(env);
 
+    let keys = ntp::auth::load_keys(&settings.auth.keys_file)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to load keys file {}: {}", settings.auth.keys_file, e);
+            vec![]
+        });
+    let auth = Arc::new(RwLock::new(NtpAuth::new(
+        keys,
+        ntp::auth::parse_networks(&settings.auth.required_networks),
+    )));
+
+    let api: Arc<Mutex<dyn Iapi>> = Arc::new(Mutex::new(settings.clone()));
+    let nts = if settings.nts.enable {
+        let keys = settings
+            .nts
+            .cookie_keys
+            .iter()
+            .filter_map(|key| MasterKey::from_hex(key.id, &key.key, key.created))
+            .collect();
+        let jar = Arc::new(RwLock::new(CookieJar::new(
+            keys,
+            Duration::from_secs(settings.nts.rotation_hours as u64 * 3600),
+        )));
+        rotate_cookie_keys(&jar, &api, &drviver, &*clock).await;
+        Some(jar)
+    } else {
+        None
+    };
+
     let mut server = Arc::new(Mutex::new(
-        NtpServer::new(vec!["0.0.0.0".to_string()], true).await,
+        NtpServer::new(
+            true,
+            NtpServerConfig {
+                unsync_silent: settings.server.unsync_silent,
+                interleaved: settings.server.interleaved,
+                holdover_timeout: Duration::from_secs(settings.server.holdover_timeout as u64),
+                priority: NtpSourcePriority {
+                    gps: settings.server.gps_priority,
+                    ntp: settings.server.ntp_priority,
+                    rtc: settings.server.rtc_priority,
+                },
+                holdover_drift: settings.server.holdover_drift_ppm * 1e-6,
+                holdover_max_error: settings.server.holdover_max_error_ms * 1e-3,
+                rate_limit: settings.rate_limit.config(),
+                acl: Acl::new(&settings.acl).unwrap_or_else(|e| {
+                    error!("Ignoring access control list: {}", e);
+                    Acl::new(&[]).unwrap()
+                }),
+                listen: settings.listen.config(),
+                cmdmon: settings.cmdmon.config(),
+                broadcast: settings.broadcast.config(),
+            },
+            Arc::clone(&auth),
+            nts.clone(),
+            Arc::clone(&clock),
+        )
+        .await,
     ));
     server.lock().await.run().await;
+
+    if let Some(jar) = nts {
+        let config = KeConfig {
+            listen: ListenConfig {
+                addresses: settings.listen.addresses.clone(),
+                port: settings.nts.ke_port,
+                workers: 1,
+            },
+            cert_file: settings.nts.cert_file.clone(),
+            key_file: settings.nts.key_file.clone(),
+            ntp_server: Some(settings.nts.ntp_server.clone()).filter(|s| !s.is_empty()),
+            ntp_port: Some(settings.nts.ntp_port).filter(|&port| port != 123),
+        };
+        let ke_jar = Arc::clone(&jar);
+        task::spawn(async move {
+            if let Err(e) = nts::run_ke_server(config, ke_jar).await {
+                error!("NTS-KE server failed: {}", e);
+            }
+        });
+
+        let arc_api = Arc::clone(&api);
+        let arc_driver = Arc::clone(&drviver);
+        let nts_clock = Arc::clone(&clock);
+        task::spawn(async move {
+            loop {
+                nts_clock.sleep(Duration::from_secs(60)).await;
+                rotate_cookie_keys(&jar, &arc_api, &arc_driver, &*nts_clock).await;
+            }
+        });
+    }
     let ts = monitor.lock().await.get_actual_data().await;
     if let Ok(timestamp) = ts {
         if timestamp != 0 {
             server
                 .lock()
                 .await
-                .update_state(NtpTimestamp { ts: timestamp })
+                .update_state(NtpSample::from_rtc(timestamp, &*clock))
                 .await;
         }
     }
@@ -69,15 +161,25 @@ async fn main() {
     let mut gps_sub = gps.subscribe().await;
     let arc_server = Arc::clone(&server);
     let arc_01 = Arc::clone(&monitor);
+    let gps_clock = Arc::clone(&clock);
+    let gps_dispersion = settings.gps.dispersion_ms * 1e-3;
     task::spawn(async move {
         while let Some(event) = gps_sub.recv().await {
             match event.event_type {
-                ntp::events::EUdpEvents::NewGPSTimestamp(timestamp) => {
+                ntp::events::EUdpEvents::NewGPSTimestamp(timestamp, ept) => {
                     trace!("GPS:{:?}", timestamp);
                     let mut mon = arc_01.lock().await;
+                    let mut server = arc_server.lock().await;
+                    server
+                        .update_state(NtpSample::from_reference(
+                            ntp::NtpSource::Gps,
+                            timestamp,
+                            ntp::NtpSampleQuality::gps(ept, gps_dispersion),
+                            &*gps_clock,
+                        ))
+                        .await;
                     mon.last_gps = timestamp;
-                    mon.actial = timestamp;
-                    arc_server.lock().await.update_state(timestamp).await;
+                    mon.actial = server.now().await;
                     if rtc_enable_gps {
                         mon.save_actual_data();
                     }
@@ -97,7 +199,13 @@ async fn main() {
 
     let mut ntp = NtpClient::new(
         Arc::new(Mutex::new(settings.ntp.server_list.clone())),
-        settings.ntp.cycle as u16,
+        Arc::clone(&auth),
+        settings.auth.server_keys.clone(),
+        settings.ntp.cycle,
+        settings.ntp.minpoll,
+        settings.ntp.maxpoll,
+        settings.ntp.interleaved,
+        Arc::clone(&clock),
     );
     let rtc_enable_ntp = settings.rtc.enable;
     let arc_02 = Arc::clone(&monitor);
@@ -106,12 +214,13 @@ async fn main() {
     task::spawn(async move {
         while let Some(event) = ntp_sub.recv().await {
             match event.event_type {
-                ntp::events::EUdpEvents::NewRemoteTimestamp(timestamp) => {
-                    trace!("NTP:{:?}", timestamp);
+                ntp::events::EUdpEvents::NewRemoteTimestamp(sample) => {
+                    trace!("NTP:{:?}", sample);
                     let mut mon = arc_02.lock().await;
-                    mon.last_ntp = timestamp;
-                    mon.actial = timestamp;
-                    arc_server2.lock().await.update_state(timestamp).await;
+                    let mut server = arc_server2.lock().await;
+                    server.update_state(sample).await;
+                    mon.last_ntp = sample.reference_ts();
+                    mon.actial = server.now().await;
                     if rtc_enable_ntp {
                         mon.save_actual_data();
                     }
@@ -126,9 +235,10 @@ async fn main() {
 
     let monitor_enable = settings.display.enable;
     let arc_03 = Arc::clone(&monitor);
+    let display_clock = Arc::clone(&clock);
     task::spawn(async move {
         loop {
-            sleep(Duration::from_secs(5)).await;
+            display_clock.sleep(Duration::from_secs(5)).await;
             if monitor_enable {
                 let mut mon = arc_03.lock().await;
                 mon.print_oled().await;
@@ -140,9 +250,10 @@ async fn main() {
     let rtc_cycle = settings.rtc.cycle / 1000;
     let arc_04 = Arc::clone(&monitor);
     let arc_server = Arc::clone(&server);
+    let rtc_clock = Arc::clone(&clock);
     task::spawn(async move {
         loop {
-            sleep(Duration::from_secs(rtc_cycle as u64)).await;
+            rtc_clock.sleep(Duration::from_secs(rtc_cycle as u64)).await;
             if rtc_enable {
                 let mut mon = arc_04.lock().await;
 
@@ -152,7 +263,7 @@ async fn main() {
                         arc_server
                             .lock()
                             .await
-                            .update_state(NtpTimestamp { ts: timestamp })
+                            .update_state(NtpSample::from_rtc(timestamp, &*rtc_clock))
                             .await;
                     }
                 }
@@ -169,17 +280,44 @@ async fn main() {
 
         ..Default::default()
     };
-    let api: Arc<Mutex<dyn Iapi>> = Arc::new(Mutex::new(settings.clone()));
     tokio::select! {
 
-        _ = get_rocket(rocket_config,Arc::clone(&api),Api::new(),drviver,Arc::new(Mutex::new(LoginSRC::new())),Arc::new(Mutex::new(NetworkSRC::new())),Arc::clone(&monitor)).await.launch()=>{},
+        _ = get_rocket(rocket_config,Arc::clone(&api),Api::new(),drviver,Arc::new(Mutex::new(LoginSRC::new())),Arc::new(Mutex::new(NetworkSRC::new())),Arc::clone(&monitor),Arc::clone(&server)).await.launch()=>{},
     }
 
-    froze_task().await;
+    froze_task(&*clock).await;
+}
+
+/// Rotates the NTS cookie keys when due and persists them in the settings.
+async fn rotate_cookie_keys(
+    jar: &RwLock<CookieJar>,
+    store: &Arc<Mutex<dyn Iapi>>,
+    driver: &Arc<Mutex<dyn IStore>>,
+    clock: &dyn Clock,
+) {
+    let keys = {
+        let mut jar = jar.write().unwrap();
+        if !jar.rotate_if_due(clock.now().unix_seconds()) {
+            return;
+        }
+        jar.keys()
+            .iter()
+            .map(|key| CookieKey {
+                id: key.id,
+                key: key.to_hex(),
+                created: key.created,
+            })
+            .collect()
+    };
+    store.lock().await.set_cookie_keys(keys);
+    let settings = store.lock().await.get_settings();
+    if let Err(e) = driver.lock().await.Backup(settings).await {
+        error!("Failed to persist NTS cookie keys: {}", e);
+    }
 }
 
-async fn froze_task() {
+async fn froze_task(clock: &dyn Clock) {
     loop {
-        sleep(Duration::from_secs(5)).await;
+        clock.sleep(Duration::from_secs(5)).await;
     }
 }
@@ -0,0 +1,136 @@
+use std::io::{self, Error, ErrorKind};
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What to do with requests from a network, like ntpd `restrict` flags.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AclAction {
+    Allow,
+    /// Silently ignore every packet.
+    Deny,
+    /// Serve time but refuse control and monitoring queries.
+    NoQuery,
+    /// Only answer authenticated requests.
+    RequireAuth,
+    /// Answer with a DENY kiss-o'-death.
+    KodDeny,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AclRule {
+    /// IPv4 or IPv6 prefix in CIDR notation, a bare address matches itself.
+    pub network: String,
+    pub action: AclAction,
+}
+
+/// Access control list, the most specific matching prefix wins and
+/// addresses matching no rule are served time but not queries.
+pub struct Acl {
+    rules: Vec<(IpNet, AclAction)>,
+}
+
+impl Acl {
+    pub fn new(rules: &[AclRule]) -> io::Result<Acl> {
+        let mut parsed = rules
+            .iter()
+            .map(|rule| Ok((parse_network(&rule.network)?, rule.action)))
+            .collect::<io::Result<Vec<_>>>()?;
+        // Longest prefixes first so the first match is the most specific.
+        parsed.sort_by_key(|(net, _)| std::cmp::Reverse(net.prefix_len()));
+        Ok(Acl { rules: parsed })
+    }
+
+    pub fn lookup(&self, addr: &IpAddr) -> AclAction {
+        self.rule(addr).unwrap_or(AclAction::Allow)
+    }
+
+    /// Control and monitoring queries need an `Allow` rule, as if ntpd's
+    /// `restrict default noquery` were configured.
+    pub fn may_query(&self, addr: &IpAddr) -> bool {
+        self.rule(addr) == Some(AclAction::Allow)
+    }
+
+    fn rule(&self, addr: &IpAddr) -> Option<AclAction> {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*addr),
+            IpAddr::V4(_) => *addr,
+        };
+        self.rules
+            .iter()
+            .find(|(net, _)| net.contains(&addr))
+            .map(|(_, action)| *action)
+    }
+}
+
+fn parse_network(network: &str) -> io::Result<IpNet> {
+    network
+        .parse::<IpNet>()
+        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Invalid network {}", network)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(network: &str, action: AclAction) -> AclRule {
+        AclRule {
+            network: network.to_string(),
+            action,
+        }
+    }
+
+    fn lookup(acl: &Acl, addr: &str) -> AclAction {
+        acl.lookup(&addr.parse().unwrap())
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        // Rule order does not matter, only prefix length.
+        let acl = Acl::new(&[
+            rule("10.1.2.3", AclAction::Allow),
+            rule("10.0.0.0/8", AclAction::Deny),
+            rule("10.1.0.0/16", AclAction::KodDeny),
+            rule("2001:db8::/32", AclAction::RequireAuth),
+            rule("2001:db8:1::/48", AclAction::NoQuery),
+        ])
+        .unwrap();
+        assert_eq!(lookup(&acl, "10.9.9.9"), AclAction::Deny);
+        assert_eq!(lookup(&acl, "10.1.9.9"), AclAction::KodDeny);
+        assert_eq!(lookup(&acl, "10.1.2.3"), AclAction::Allow);
+        assert_eq!(lookup(&acl, "11.0.0.1"), AclAction::Allow);
+        assert_eq!(lookup(&acl, "2001:db8:2::1"), AclAction::RequireAuth);
+        assert_eq!(lookup(&acl, "2001:db8:1::1"), AclAction::NoQuery);
+        assert_eq!(lookup(&acl, "2001:db9::1"), AclAction::Allow);
+    }
+
+    #[test]
+    fn v4_mapped_addresses_match_v4_rules() {
+        let acl = Acl::new(&[rule("192.0.2.0/24", AclAction::Deny)]).unwrap();
+        assert_eq!(lookup(&acl, "::ffff:192.0.2.7"), AclAction::Deny);
+        assert_eq!(lookup(&acl, "::ffff:198.51.100.7"), AclAction::Allow);
+        // Only mapped addresses, not the deprecated compatible ones.
+        assert_eq!(lookup(&acl, "::c000:207"), AclAction::Allow);
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        assert!(Acl::new(&[rule("192.0.2.0/33", AclAction::Deny)]).is_err());
+        assert!(Acl::new(&[rule("example.com", AclAction::Deny)]).is_err());
+        assert!(Acl::new(&[rule("2001:db8::1", AclAction::Deny)]).is_ok());
+    }
+
+    #[test]
+    fn queries_need_an_allow_rule() {
+        let acl = Acl::new(&[rule("192.0.2.0/24", AclAction::Allow), rule("198.51.100.0/24", AclAction::NoQuery)]).unwrap();
+        assert!(acl.may_query(&"192.0.2.1".parse().unwrap()));
+        assert!(!acl.may_query(&"198.51.100.1".parse().unwrap()));
+        assert!(!acl.may_query(&"203.0.113.1".parse().unwrap()));
+        assert_eq!(acl.lookup(&"203.0.113.1".parse().unwrap()), AclAction::Allow);
+        assert!(!Acl::new(&[]).unwrap().may_query(&"127.0.0.1".parse().unwrap()));
+    }
+}
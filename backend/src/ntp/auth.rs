@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::net::IpAddr;
+
+use aes::Aes128;
+use cmac::{Cmac, Mac as _};
+use ipnet::IpNet;
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use tokio::io::AsyncWriteExt;
+use utoipa::ToSchema;
+
+use super::NtpPacket;
+
+// Secrets longer than this are written in hex, as in ntpd keys files.
+const MAX_ASCII_SECRET: usize = 20;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum KeyType {
+    #[serde(rename = "MD5")]
+    Md5,
+    #[serde(rename = "SHA1")]
+    Sha1,
+    #[serde(rename = "AES128CMAC")]
+    AesCmac,
+}
+
+impl KeyType {
+    fn parse(name: &str) -> Option<KeyType> {
+        match name.to_ascii_uppercase().as_str() {
+            "M" | "MD5" => Some(KeyType::Md5),
+            "SHA1" => Some(KeyType::Sha1),
+            "AES128CMAC" | "CMAC" => Some(KeyType::AesCmac),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            KeyType::Md5 => "MD5",
+            KeyType::Sha1 => "SHA1",
+            KeyType::AesCmac => "AES128CMAC",
+        }
+    }
+}
+
+/// Key as submitted through the REST API, the secret uses the keys file
+/// notation (ASCII up to 20 characters, hex otherwise).
+#[derive(Debug, Clone, Deserialize, ToSchema)]
+pub struct KeyConfig {
+    pub id: u32,
+    pub kind: KeyType,
+    pub secret: String,
+}
+
+/// Symmetric key; the secret is never serialized.
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Key {
+    pub id: u32,
+    pub kind: KeyType,
+    #[serde(skip)]
+    secret: Vec<u8>,
+    #[serde(skip)]
+    notation: String,
+}
+
+impl Key {
+    pub fn new(config: &KeyConfig) -> io::Result<Key> {
+        if config.id == 0 {
+            return Err(invalid("Key ID 0 is reserved for crypto-NAK"));
+        }
+        let secret = if config.secret.len() > MAX_ASCII_SECRET {
+            decode_hex(&config.secret).ok_or_else(|| invalid("Invalid hex secret"))?
+        } else {
+            config.secret.as_bytes().to_vec()
+        };
+        if secret.is_empty() {
+            return Err(invalid("Empty secret"));
+        }
+        if config.kind == KeyType::AesCmac && secret.len() != 16 {
+            return Err(invalid("AES128CMAC keys must be 16 bytes"));
+        }
+        Ok(Key {
+            id: config.id,
+            kind: config.kind,
+            secret,
+            notation: config.secret.clone(),
+        })
+    }
+
+    /// Computes the MAC digest over the authenticated part of a packet.
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.kind {
+            KeyType::Md5 => {
+                let mut hasher = Md5::new();
+                hasher.update(&self.secret);
+                hasher.update(data);
+                hasher.finalize().to_vec()
+            }
+            KeyType::Sha1 => {
+                let mut hasher = Sha1::new();
+                hasher.update(&self.secret);
+                hasher.update(data);
+                hasher.finalize().to_vec()
+            }
+            KeyType::AesCmac => {
+                let mut mac = <Cmac<Aes128>>::new_from_slice(&self.secret)
+                    .expect("AES128CMAC key length is checked on creation");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Appends a MAC made with this key to the packet.
+    pub fn sign(&self, packet: &mut NtpPacket) {
+        packet.mac = Some(Mac {
+            key_id: self.id,
+            digest: self.digest(&packet.authenticated_data()),
+        });
+    }
+
+    pub fn verify(&self, packet: &NtpPacket) -> bool {
+        match &packet.mac {
+            Some(mac) if mac.key_id == self.id => {
+                let expected = self.digest(&packet.authenticated_data());
+                expected.len() == mac.digest.len()
+                    && expected.iter().zip(&mac.digest).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Message authentication code trailing a packet (RFC 5905 section 7.3).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mac {
+    pub key_id: u32,
+    pub digest: Vec<u8>,
+}
+
+impl Mac {
+    /// A MAC with key ID zero and no digest tells the client its MAC failed.
+    pub fn crypto_nak() -> Mac {
+        Mac {
+            key_id: 0,
+            digest: vec![],
+        }
+    }
+
+    pub fn is_crypto_nak(&self) -> bool {
+        self.key_id == 0 && self.digest.is_empty()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AuthStatus {
+    /// The request carries no MAC.
+    None,
+    /// The MAC verified with the given key.
+    Valid(u32),
+    /// Unknown key or bad digest, answered with a crypto-NAK.
+    Invalid,
+}
+
+/// Key store and authentication policy shared by server and client.
+pub struct Auth {
+    keys: HashMap<u32, Key>,
+    required: Vec<IpNet>,
+}
+
+impl Auth {
+    pub fn new(keys: Vec<Key>, required: Vec<IpNet>) -> Auth {
+        Auth {
+            keys: keys.into_iter().map(|key| (key.id, key)).collect(),
+            required,
+        }
+    }
+
+    pub fn key(&self, id: u32) -> Option<&Key> {
+        self.keys.get(&id)
+    }
+
+    /// Configured keys sorted by ID, without their secrets.
+    pub fn keys(&self) -> Vec<Key> {
+        let mut keys: Vec<Key> = self.keys.values().cloned().collect();
+        keys.sort_by_key(|key| key.id);
+        keys
+    }
+
+    pub fn set_keys(&mut self, keys: Vec<Key>) {
+        self.keys = keys.into_iter().map(|key| (key.id, key)).collect();
+    }
+
+    pub fn set_required(&mut self, required: Vec<IpNet>) {
+        self.required = required;
+    }
+
+    /// Whether clients from `addr` must authenticate to be answered.
+    pub fn is_required(&self, addr: &IpAddr) -> bool {
+        self.required.iter().any(|net| net.contains(addr))
+    }
+
+    pub fn check(&self, packet: &NtpPacket) -> AuthStatus {
+        let Some(mac) = &packet.mac else {
+            return AuthStatus::None;
+        };
+        match self.keys.get(&mac.key_id) {
+            Some(key) if key.verify(packet) => AuthStatus::Valid(key.id),
+            _ => AuthStatus::Invalid,
+        }
+    }
+}
+
+/// Parses an ntpd style keys file: `id type secret` per line, `#` comments.
+pub fn parse_keys(text: &str) -> io::Result<Vec<Key>> {
+    let mut keys = vec![];
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 {
+            return Err(invalid(&format!("Malformed key line: {}", line)));
+        }
+        let id = fields[0]
+            .parse()
+            .map_err(|_| invalid(&format!("Invalid key ID: {}", fields[0])))?;
+        let kind = KeyType::parse(fields[1])
+            .ok_or_else(|| invalid(&format!("Unsupported key type: {}", fields[1])))?;
+        keys.push(Key::new(&KeyConfig {
+            id,
+            kind,
+            secret: fields[2].to_string(),
+        })?);
+    }
+    Ok(keys)
+}
+
+pub fn format_keys(keys: &[Key]) -> String {
+    keys.iter()
+        .map(|key| format!("{} {} {}\n", key.id, key.kind.name(), key.notation))
+        .collect()
+}
+
+/// Loads the keys file, a missing file means no keys.
+pub async fn load_keys(path: &str) -> io::Result<Vec<Key>> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(text) => parse_keys(&text),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the keys file readable by its owner only, through a temporary
+/// file so a failed write never leaves a truncated one behind.
+pub async fn save_keys(path: &str, keys: &[Key]) -> io::Result<()> {
+    let temp = format!("{}.tmp", path);
+    // A leftover could have other permissions, mode only applies on create.
+    if let Err(e) = tokio::fs::remove_file(&temp).await {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp)
+        .await?;
+    file.write_all(format_keys(keys).as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&temp, path).await
+}
+
+/// Parses CIDR strings, skipping invalid entries with a warning.
+pub fn parse_networks(networks: &[String]) -> Vec<IpNet> {
+    networks
+        .iter()
+        .filter_map(|net| match net.parse::<IpNet>() {
+            Ok(net) => Some(net),
+            Err(_) => match net.parse::<IpAddr>() {
+                Ok(addr) => Some(IpNet::from(addr)),
+                Err(_) => {
+                    warn!("Ignoring invalid network {}", net);
+                    None
+                }
+            },
+        })
+        .collect()
+}
+
+pub fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::ntp::NtpTimestamp;
+
+    fn key(id: u32, kind: KeyType, secret: &str) -> Key {
+        Key::new(&KeyConfig {
+            id,
+            kind,
+            secret: secret.to_string(),
+        })
+        .unwrap()
+    }
+
+    fn request() -> NtpPacket {
+        let mut buf = [0u8; 48];
+        buf[0] = 0x23;
+        buf[40..48].copy_from_slice(&0x0123_4567_89ab_cdef_u64.to_be_bytes());
+        decoded(&buf)
+    }
+
+    fn decoded(buf: &[u8]) -> NtpPacket {
+        NtpPacket::decode(buf, SocketAddr::from(([192, 0, 2, 1], 123)), NtpTimestamp::zero()).unwrap()
+    }
+
+    #[test]
+    fn digests() {
+        let hex = |digest: Vec<u8>| digest.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(key(1, KeyType::Md5, "key").digest(b"abc")), "52878f125814206651a24111280ec873");
+        assert_eq!(hex(key(1, KeyType::Sha1, "key").digest(b"abc")), "36096e109609be59938bcd2fde6252df0e1eded5");
+        // RFC 4493 example 1.
+        let cmac = key(1, KeyType::AesCmac, "2b7e151628aed2a6abf7158809cf4f3c");
+        assert_eq!(hex(cmac.digest(b"")), "bb1d6929e95937287fa37d129b756746");
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        for kind in [KeyType::Md5, KeyType::Sha1, KeyType::AesCmac] {
+            let signer = key(7, kind, "00112233445566778899aabbccddeeff");
+            let auth = Auth::new(vec![signer.clone()], vec![]);
+            let mut packet = request();
+            signer.sign(&mut packet);
+            let buf = packet.encode();
+            assert_eq!(auth.check(&decoded(&buf)), AuthStatus::Valid(7));
+
+            // Any change of the authenticated data breaks the MAC.
+            let mut tampered = buf.clone();
+            tampered[47] ^= 1;
+            assert_eq!(auth.check(&decoded(&tampered)), AuthStatus::Invalid);
+            let mut tampered = buf.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert_eq!(auth.check(&decoded(&tampered)), AuthStatus::Invalid);
+
+            // Same ID, different secret.
+            let other = Auth::new(vec![key(7, kind, "ffeeddccbbaa99887766554433221100")], vec![]);
+            assert_eq!(other.check(&decoded(&buf)), AuthStatus::Invalid);
+            assert_eq!(Auth::new(vec![], vec![]).check(&decoded(&buf)), AuthStatus::Invalid);
+        }
+        let auth = Auth::new(vec![key(7, KeyType::Md5, "key")], vec![]);
+        assert_eq!(auth.check(&request()), AuthStatus::None);
+    }
+
+    #[test]
+    fn crypto_nak() {
+        let mut packet = request();
+        packet.mac = Some(Mac::crypto_nak());
+        let buf = packet.encode();
+        // Just the zero key ID after the header.
+        assert_eq!(buf.len(), 52);
+        assert_eq!(&buf[48..], &[0; 4]);
+
+        let nak = decoded(&buf);
+        assert!(nak.mac.as_ref().is_some_and(Mac::is_crypto_nak));
+        assert_eq!(Auth::new(vec![key(1, KeyType::Md5, "key")], vec![]).check(&nak), AuthStatus::Invalid);
+        assert!(!Mac { key_id: 0, digest: vec![0; 16] }.is_crypto_nak());
+    }
+
+    #[test]
+    fn keys_file() {
+        let text = "# ntpd keys\n1 M secret\n2 SHA1 00112233445566778899aabbccddeeff00112233 # hex\n\n3 AES128CMAC 00112233445566778899aabbccddeeff\n";
+        let keys = parse_keys(text).unwrap();
+        let summary = |keys: &[Key]| keys.iter().map(|key| (key.id, key.kind, key.secret.clone())).collect::<Vec<_>>();
+        let parsed = summary(&keys);
+        assert_eq!(parsed[0], (1, KeyType::Md5, b"secret".to_vec()));
+        assert_eq!((parsed[1].1, parsed[1].2.len()), (KeyType::Sha1, 20));
+        assert_eq!((parsed[2].1, parsed[2].2.len()), (KeyType::AesCmac, 16));
+        assert_eq!(summary(&parse_keys(&format_keys(&keys)).unwrap()), parsed);
+
+        assert!(parse_keys("0 MD5 secret").is_err());
+        assert!(parse_keys("1 MD5").is_err());
+        assert!(parse_keys("1 DES secret").is_err());
+        assert!(parse_keys("1 AES128CMAC short").is_err());
+        assert!(parse_keys("1 MD5 0011223344556677889900112233445566778899zz").is_err());
+    }
+
+    #[tokio::test]
+    async fn keys_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("ntp-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ntp.keys");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "1 MD5 old\n").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        std::fs::write(format!("{}.tmp", path), "stale").unwrap();
+
+        let keys = parse_keys("1 MD5 secret\n2 SHA1 other").unwrap();
+        save_keys(path, &keys).await.unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(load_keys(path).await.unwrap().len(), 2);
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn required_networks() {
+        let auth = Auth::new(vec![], parse_networks(&["192.0.2.0/24".to_string(), "2001:db8::1".to_string(), "bogus".to_string()]));
+        assert!(auth.is_required(&"192.0.2.9".parse().unwrap()));
+        assert!(auth.is_required(&"2001:db8::1".parse().unwrap()));
+        assert!(!auth.is_required(&"2001:db8::2".parse().unwrap()));
+        assert!(!auth.is_required(&"198.51.100.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,261 @@
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use if_addrs::{IfAddr, Ifv4Addr, Interface};
+use net2::{UdpBuilder, UdpSocketExt};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use super::local_clock::Clock;
+use super::{NtpAuth, NtpPacket, NtpStateCell, NtpSyncStatus};
+
+#[derive(Debug, Clone)]
+pub struct BroadcastConfig {
+    pub enable: bool,
+    /// Broadcast or multicast addresses, optionally with `%interface`, or
+    /// interface names standing for their IPv4 broadcast address.
+    pub destinations: Vec<String>,
+    pub port: u16,
+    pub interval: Duration,
+    /// Key to sign the packets with, unauthenticated when not set.
+    pub key_id: Option<u32>,
+    /// TTL or hop limit of multicast packets.
+    pub ttl: u32,
+}
+
+/// Sends mode 5 packets to the configured destinations (RFC 5905 section
+/// 3, broadcast server).
+pub struct Broadcaster {
+    config: BroadcastConfig,
+    task: Option<JoinHandle<()>>,
+}
+
+struct Destination {
+    addr: SocketAddr,
+    socket: UdpSocket,
+}
+
+impl Broadcaster {
+    pub fn new(config: BroadcastConfig) -> Broadcaster {
+        Broadcaster { config, task: None }
+    }
+
+    pub fn config(&self) -> &BroadcastConfig {
+        &self.config
+    }
+
+    /// Stops broadcasting and starts over with `config` if it is enabled.
+    pub fn start(
+        &mut self,
+        config: BroadcastConfig,
+        state: Arc<NtpStateCell>,
+        auth: Arc<RwLock<NtpAuth>>,
+        clock: Arc<dyn Clock>,
+    ) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        self.config = config.clone();
+        if config.enable && !config.destinations.is_empty() {
+            self.task = Some(tokio::spawn(broadcast(config, state, auth, clock)));
+        }
+    }
+}
+
+async fn broadcast(
+    config: BroadcastConfig,
+    state: Arc<NtpStateCell>,
+    auth: Arc<RwLock<NtpAuth>>,
+    clock: Arc<dyn Clock>,
+) {
+    let mut destinations = vec![];
+    for entry in &config.destinations {
+        match open(entry, config.port, config.ttl) {
+            Ok(destination) => {
+                info!("Broadcasting to {} every {:?}", destination.addr, config.interval);
+                destinations.push(destination);
+            }
+            Err(e) => error!("Failed to set up broadcast to {}: {}", entry, e),
+        }
+    }
+    if destinations.is_empty() {
+        return;
+    }
+
+    let poll = config.interval.as_secs_f64().max(1.0).log2().round() as i8;
+    let interval = config.interval.max(Duration::from_secs(1));
+    loop {
+        clock.sleep(interval).await;
+        let state = state.load().state;
+        // Listeners cannot tell a stale clock, stay quiet until synchronized.
+        if state.sync == NtpSyncStatus::Unsynchronized {
+            continue;
+        }
+
+        let key = match config.key_id {
+            Some(key_id) => match auth.read().unwrap().key(key_id).cloned() {
+                Some(key) => Some(key),
+                None => {
+                    error!("Broadcast key {} not found, not broadcasting", key_id);
+                    continue;
+                }
+            },
+            None => None,
+        };
+        for destination in &destinations {
+            let mut packet = NtpPacket::make_broadcast(&state, destination.addr, poll, &*clock);
+            if let Some(key) = &key {
+                key.sign(&mut packet);
+            }
+            if let Err(e) = packet.send(&destination.socket).await {
+                error!("Failed to broadcast to {}: {}", destination.addr, e);
+            }
+        }
+    }
+}
+
+/// Resolves a destination entry and opens a socket sending to it.
+fn open(entry: &str, port: u16, ttl: u32) -> io::Result<Destination> {
+    let interfaces = if_addrs::get_if_addrs()?;
+    let (host, interface) = match entry.trim().split_once('%') {
+        Some((host, interface)) => (host, Some(interface)),
+        None => (entry.trim(), None),
+    };
+    let ip = match host.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) if interface.is_none() => {
+            let addr = interface_v4(&interfaces, host)?;
+            IpAddr::V4(addr.broadcast.unwrap_or(Ipv4Addr::BROADCAST))
+        }
+        Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "Invalid broadcast address")),
+    };
+
+    let (addr, socket) = match ip {
+        IpAddr::V4(ip) => {
+            let socket = UdpBuilder::new_v4()?.bind((Ipv4Addr::UNSPECIFIED, 0))?;
+            if ip.is_multicast() {
+                socket.set_multicast_ttl_v4(ttl)?;
+                if let Some(name) = interface {
+                    socket.set_multicast_if_v4(&interface_v4(&interfaces, name)?.ip)?;
+                }
+            } else {
+                socket.set_broadcast(true)?;
+            }
+            (SocketAddr::new(IpAddr::V4(ip), port), socket)
+        }
+        IpAddr::V6(ip) if ip.is_multicast() => {
+            let socket = UdpBuilder::new_v6()?.bind((Ipv6Addr::UNSPECIFIED, 0))?;
+            socket.set_multicast_hops_v6(ttl)?;
+            let index = match interface {
+                Some(name) => interface_index(&interfaces, name)?,
+                None => 0,
+            };
+            if index != 0 {
+                socket.set_multicast_if_v6(index)?;
+            }
+            (SocketAddr::V6(SocketAddrV6::new(ip, port, 0, index)), socket)
+        }
+        IpAddr::V6(_) => {
+            return Err(Error::new(ErrorKind::InvalidInput, "IPv6 has no broadcast, use a multicast group"));
+        }
+    };
+    socket.set_nonblocking(true)?;
+    Ok(Destination {
+        addr,
+        socket: UdpSocket::from_std(socket)?,
+    })
+}
+
+fn interface_v4<'a>(interfaces: &'a [Interface], name: &str) -> io::Result<&'a Ifv4Addr> {
+    interfaces
+        .iter()
+        .filter(|interface| interface.name == name)
+        .find_map(|interface| match &interface.addr {
+            IfAddr::V4(addr) => Some(addr),
+            IfAddr::V6(_) => None,
+        })
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No such interface {}", name)))
+}
+
+fn interface_index(interfaces: &[Interface], name: &str) -> io::Result<u32> {
+    interfaces
+        .iter()
+        .find(|interface| interface.name == name)
+        .and_then(|interface| interface.index)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No such interface {}", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntp::auth::{AuthStatus, Key, KeyConfig, KeyType};
+    use crate::ntp::local_clock::ManualClock;
+    use crate::ntp::{NtpClockModel, NtpFracValue, NtpServerState, NtpSource, NtpTimestamp};
+
+    const INTERVAL: Duration = Duration::from_secs(64);
+
+    fn state() -> NtpServerState {
+        NtpServerState {
+            leap: NtpSource::None.leap(),
+            stratum: NtpSource::None.stratum(),
+            precision: 0,
+            ref_id: NtpSource::None.ref_id(),
+            ref_ts: NtpTimestamp::zero(),
+            dispersion: NtpFracValue::zero(),
+            delay: NtpFracValue::zero(),
+            clock: NtpClockModel::new(),
+            sync: NtpSyncStatus::Unsynchronized,
+            dispersion_rate: 0.0,
+        }
+    }
+
+    async fn interval_passes(clock: &ManualClock) {
+        for _ in 0..4 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(INTERVAL);
+        for _ in 0..4 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    async fn receive(socket: &UdpSocket) -> Option<NtpPacket> {
+        let mut buf = [0u8; 128];
+        let (len, addr) = tokio::time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await.ok()?.unwrap();
+        Some(NtpPacket::decode(&buf[..len], addr, NtpTimestamp::zero()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn signed_once_synchronized() {
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let key = Key::new(&KeyConfig { id: 3, kind: KeyType::Sha1, secret: String::from("broadcast") }).unwrap();
+        let auth = Arc::new(RwLock::new(NtpAuth::new(vec![key], vec![])));
+        let clock = Arc::new(ManualClock::new(NtpTimestamp::from_unix_secs(1_700_000_000)));
+        let state = Arc::new(NtpStateCell::new(state()));
+        let config = BroadcastConfig {
+            enable: true,
+            destinations: vec![String::from("127.0.0.1")],
+            port: listener.local_addr().unwrap().port(),
+            interval: INTERVAL,
+            key_id: Some(3),
+            ttl: 1,
+        };
+        let mut broadcaster = Broadcaster::new(config.clone());
+        broadcaster.start(config, Arc::clone(&state), Arc::clone(&auth), clock.clone());
+
+        interval_passes(&clock).await;
+        assert!(receive(&listener).await.is_none());
+
+        {
+            let mut state = state.lock().await;
+            state.sync = NtpSyncStatus::Synchronized;
+            state.stratum = 1;
+        }
+        interval_passes(&clock).await;
+        let packet = receive(&listener).await.unwrap();
+        assert_eq!((packet.mode, packet.stratum, packet.poll), (5, 1, 6));
+        assert_eq!(auth.read().unwrap().check(&packet), AuthStatus::Valid(3));
+    }
+}
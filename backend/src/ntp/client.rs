@@ -1,26 +1,76 @@
-use std::net::UdpSocket;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
+use tokio::net::{lookup_host, UdpSocket};
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tokio::time::timeout;
 
 use super::events::{Event, EventManager, EUdpEvents};
-use super::NtpTimestamp;
+use super::local_clock::Clock;
+use super::packet::MAX_PACKET_LEN;
+use super::source::{LEAP_ALARM, PHI, UNSYNC_STRATUM};
+use super::{NtpAuth, NtpKey, NtpPacket, NtpPeer, NtpSample, NtpSampleQuality, NtpSource, NtpTimestamp};
+
+// How long to wait for an upstream answer.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(2);
+// log2 of the local clock reading precision (about a microsecond).
+const LOCAL_PRECISION: i8 = -20;
+
 #[derive(Clone)]
 pub struct Client {
     list: Arc<Mutex<Vec<String>>>,
+    peers: Arc<Mutex<Vec<NtpPeer>>>,
     event_manager: Arc<Mutex<EventManager>>,
-    cycle: u16,
+    auth: Arc<RwLock<NtpAuth>>,
+    server_keys: HashMap<String, u32>,
+    cycle: u32,
+    minpoll: i8,
+    maxpoll: i8,
+    interleaved: bool,
+    clock: Arc<dyn Clock>,
+}
+
+/// Timestamps of the last exchange with a server, the base of the next
+/// interleaved request.
+#[derive(Debug, Copy, Clone)]
+pub struct Exchange {
+    addr: SocketAddr,
+    t1: NtpTimestamp,
+    t2: NtpTimestamp,
+    t4: NtpTimestamp,
 }
 
 impl Client {
-    pub fn new(list: Arc<Mutex<Vec<String>>>, cycle: u16) -> Self {
+    /// `server_keys` maps entries of `list` to the key ID used with them.
+    /// With `interleaved` the servers are asked for the transmit time of
+    /// their previous response, which servers without support ignore.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        list: Arc<Mutex<Vec<String>>>,
+        auth: Arc<RwLock<NtpAuth>>,
+        server_keys: HashMap<String, u32>,
+        cycle: u32,
+        minpoll: i8,
+        maxpoll: i8,
+        interleaved: bool,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         Self {
             list,
+            peers: Arc::new(Mutex::new(Vec::new())),
             event_manager: Arc::new(Mutex::new(EventManager::new())),
+            auth,
+            server_keys,
             cycle,
+            minpoll,
+            maxpoll,
+            interleaved,
+            clock,
         }
     }
 
@@ -29,53 +79,205 @@ impl Client {
     }
 
     pub async fn start(&mut self) {
-        let cycle_time= self.cycle;
-        let event_manager = Arc::clone(&self.event_manager);
-        let arc_list = Arc::clone(&self.list);
-        tokio::spawn(async move {
-            loop {
-                let result = get_ntp(Arc::clone(&arc_list)).await;
-                debug!("{:?}",result);
-                if let Some(timestamp) = result {
-                    event_manager.lock().await.notify(Event {
-                        event_type: EUdpEvents::NewRemoteTimestamp(
-                            NtpTimestamp::new(timestamp.ts))})
-                    };
-                    sleep(Duration::from_millis(cycle_time.into())).await;
-                }
-               
-            }
-        
-        );
+        let list = self.list.lock().await.clone();
+        let mut peers = self.peers.lock().await;
+        *peers = list
+            .into_iter()
+            .map(|url| {
+                let mut peer = NtpPeer::new(url, self.cycle, self.minpoll, self.maxpoll);
+                peer.key_id = self.server_keys.get(&peer.url).copied();
+                peer
+            })
+            .collect();
+
+        for index in 0..peers.len() {
+            let peers = Arc::clone(&self.peers);
+            let event_manager = Arc::clone(&self.event_manager);
+            let auth = Arc::clone(&self.auth);
+            let interleaved = self.interleaved;
+            let clock = Arc::clone(&self.clock);
+            tokio::spawn(async move {
+                poll_peer(index, peers, event_manager, auth, interleaved, clock).await;
+            });
+        }
     }
 }
 
+async fn poll_peer(
+    index: usize,
+    peers: Arc<Mutex<Vec<NtpPeer>>>,
+    event_manager: Arc<Mutex<EventManager>>,
+    auth: Arc<RwLock<NtpAuth>>,
+    interleaved: bool,
+    clock: Arc<dyn Clock>,
+) {
+    let mut previous: Option<Exchange> = None;
+    loop {
+        let (url, addr, reachable, key_id) = {
+            let peers = peers.lock().await;
+            let peer = &peers[index];
+            (peer.url.clone(), peer.addr, peer.is_reachable(), peer.key_id)
+        };
+        let key = match key_id {
+            Some(id) => auth.read().unwrap().key(id).cloned(),
+            None => None,
+        };
+
+        // Re-resolve names of unreachable peers, pool entries move around.
+        let addr = match addr {
+            Some(addr) if reachable => Some(addr),
+            _ => resolve(&url).await.or(addr),
+        };
+        let result = match (addr, key_id, &key) {
+            (_, Some(id), None) => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("Key {} is not configured", id),
+            )),
+            (Some(addr), _, key) => {
+                let previous = previous.as_ref().filter(|exchange| interleaved && exchange.addr == addr);
+                query(addr, key.as_ref(), previous, &*clock).await
+            }
+            (None, _, _) => Err(Error::new(ErrorKind::NotFound, "Unable to resolve")),
+        };
+
+        let mut peers = peers.lock().await;
+        let peer = &mut peers[index];
+        peer.addr = addr;
+        let answered = match result {
+            Ok((sample, exchange)) => {
+                peer.on_response(sample);
+                previous = Some(exchange);
+                true
+            }
+            Err(e) => {
+                debug!("NTP query to {} failed: {}", url, e);
+                previous = None;
+                peer.on_timeout();
+                false
+            }
+        };
+        let interval = peer.poll_interval();
+        debug!("{:?}", peer);
+
+        // Every peer feeds the server, source selection sorts them out.
+        if answered {
+            if let Some(sample) = peer.sample() {
+                event_manager.lock().await.notify(Event {
+                    event_type: EUdpEvents::NewRemoteTimestamp(sample),
+                });
+            }
+        }
+        drop(peers);
 
-pub fn _get_ntp(url: String) -> Option<NtpTimestamp> {
-    let socket = UdpSocket::bind("0.0.0.0:0").expect("Unable to crate UDP socket");
-    socket
-        .set_read_timeout(Some(Duration::from_secs(2)))
-        .expect("Unable to set UDP socket read timeout");
-    let result = sntpc::simple_get_time(url.as_str(), socket);
-    match result {
-        Ok(time) => Some(NtpTimestamp::new(time.seconds.into())),
-        Err(err) => None,
+        clock.sleep(interval).await;
     }
 }
 
-async fn get_ntp(list: Arc<Mutex<Vec<String>>>) -> Option<NtpTimestamp> {
-    let mut result = None;
-    let mut i = 0;
-    let list = list.lock().await;
-    while result.is_none() && i < list.len() {
-        let item = list.get(i);
-        if let Some(url) = item {
-            result = _get_ntp(url.to_string());
-        } else {
-            result = None;
+async fn resolve(url: &str) -> Option<SocketAddr> {
+    lookup_host(url).await.ok()?.next()
+}
+
+/// Performs one client/server exchange and measures offset and delay from
+/// the four timestamps (RFC 5905 section 8). With a key the request is
+/// signed and only responses carrying a valid MAC are accepted.
+///
+/// Given the `previous` exchange the request is interleaved: a capable
+/// server answers with the time its previous response actually left, and
+/// the measurement is made from the previous exchange instead.
+pub async fn query(
+    addr: SocketAddr,
+    key: Option<&NtpKey>,
+    previous: Option<&Exchange>,
+    clock: &dyn Clock,
+) -> io::Result<(NtpSample, Exchange)> {
+    let bind_addr = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = UdpSocket::bind(bind_addr).await?;
+
+    let mut request = NtpPacket::new_request(addr, clock).await;
+    if let Some(previous) = previous {
+        request.orig_ts = previous.t2;
+        request.rx_ts = previous.t4;
+    }
+    if let Some(key) = key {
+        key.sign(&mut request);
+    }
+    request.send(&socket).await?;
+
+    let response = timeout(RESPONSE_TIMEOUT, async {
+        let mut buf = [0; MAX_PACKET_LEN];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await?;
+            let response = match NtpPacket::decode(&buf[..len], from, clock.now()) {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!("Ignoring malformed packet from {}: {}", from, e);
+                    continue;
+                }
+            };
+            let interleaved = previous.is_some_and(|previous| {
+                response.remote_addr == addr && response.mode == 4 && response.orig_ts == previous.t4
+            });
+            if interleaved || response.is_valid_response(&request) {
+                return Ok::<NtpPacket, Error>(response);
+            }
+            debug!("Ignoring unexpected packet from {}", response.remote_addr);
         }
-        i += 1;
+    })
+    .await
+    .map_err(|_| Error::new(ErrorKind::TimedOut, "No response"))??;
+
+    if let Some(key) = key {
+        if response.mac.as_ref().is_some_and(|mac| mac.is_crypto_nak()) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Server sent crypto-NAK"));
+        }
+        if !key.verify(&response) {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Response failed authentication"));
+        }
+    }
+
+    if response.stratum == 0 || response.stratum >= UNSYNC_STRATUM || response.leap == LEAP_ALARM {
+        return Err(Error::other("Server is not synchronized"));
     }
 
-    result
+    let exchange = Exchange {
+        addr,
+        t1: request.local_ts,
+        t2: response.rx_ts,
+        t4: response.local_ts,
+    };
+    // An interleaved response completes the previous exchange.
+    let (t1, t2, t3, t4) = match previous {
+        Some(previous) if response.orig_ts == previous.t4 => {
+            if response.tx_ts.diff_to_sec(&previous.t2) < 0.0 {
+                return Err(Error::new(ErrorKind::InvalidData, "Interleaved transmit time precedes receive time"));
+            }
+            (previous.t1, previous.t2, response.tx_ts, previous.t4)
+        }
+        _ => (request.local_ts, response.rx_ts, response.tx_ts, response.local_ts),
+    };
+
+    let offset = (t2.diff_to_sec(&t1) + t3.diff_to_sec(&t4)) / 2.0;
+    let delay = (t4.diff_to_sec(&t1) - t3.diff_to_sec(&t2)).max(0.0);
+    let dispersion = 2f64.powi(response.precision.into())
+        + 2f64.powi(LOCAL_PRECISION.into())
+        + PHI * t4.diff_to_sec(&t1);
+
+    let sample = NtpSample {
+        source: NtpSource::Ntp {
+            addr,
+            stratum: response.stratum,
+            leap: response.leap,
+        },
+        offset,
+        local_ts: t4,
+        quality: NtpSampleQuality {
+            delay,
+            dispersion,
+            jitter: 0.0,
+            root_delay: response.delay.to_secs(),
+            root_dispersion: response.dispersion.to_secs(),
+        },
+        poll: None,
+    };
+    Ok((sample, exchange))
 }
@@ -0,0 +1,184 @@
+use super::NtpTimestamp;
+
+// Offsets above this are stepped instead of slewed (same default as ntpd).
+const STEP_THRESHOLD: f64 = 0.128;
+// Frequency is measured over at least this long a baseline, so the noise
+// of 1 Hz samples is averaged instead of training it.
+const MIN_FREQ_INTERVAL: f64 = 64.0;
+const MAX_FREQUENCY: f64 = 500e-6;
+const PHASE_GAIN: f64 = 0.5;
+const FREQ_GAIN: f64 = 0.25;
+// Coarse sources (RTC) only correct the model when it is this far off.
+const COARSE_THRESHOLD: f64 = 1.0;
+
+/// In-process model of the reference clock relative to the system clock.
+///
+/// The served time is `system + offset + frequency * (system - epoch)`, so
+/// it keeps following the last reference even when the kernel clock is off.
+#[derive(Debug, Copy, Clone)]
+pub struct ClockModel {
+    offset: f64,
+    frequency: f64,
+    jitter: f64,
+    epoch: NtpTimestamp,
+    /// System time and measured offset the next frequency measurement is
+    /// taken against.
+    baseline: Option<(NtpTimestamp, f64)>,
+    samples: u32,
+}
+
+impl ClockModel {
+    pub fn new() -> ClockModel {
+        ClockModel {
+            offset: 0.0,
+            frequency: 0.0,
+            jitter: 0.0,
+            epoch: NtpTimestamp::zero(),
+            baseline: None,
+            samples: 0,
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.samples > 0
+    }
+
+    pub fn frequency(&self) -> f64 {
+        self.frequency
+    }
+
+    /// Replaces the frequency estimate without a phase jump at `local`.
+    pub fn set_frequency(&mut self, frequency: f64, local: NtpTimestamp) {
+        if self.is_set() {
+            self.offset = self.offset_at(&local);
+            self.epoch = local;
+        }
+        self.frequency = frequency.clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+    }
+
+    /// RMS of recent prediction errors, in seconds.
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    /// Estimated reference minus system time at the given system time.
+    pub fn offset_at(&self, local: &NtpTimestamp) -> f64 {
+        if !self.is_set() {
+            return 0.0;
+        }
+        self.offset + self.frequency * local.diff_to_sec(&self.epoch)
+    }
+
+    /// Maps a raw system clock reading onto the model's timescale.
+    pub fn convert(&self, local: NtpTimestamp) -> NtpTimestamp {
+        local.add_sec(self.offset_at(&local))
+    }
+
+    /// Feeds a reference sample taken at system time `local`.
+    pub fn update(&mut self, reference: NtpTimestamp, local: NtpTimestamp) {
+        let measured = reference.diff_to_sec(&local);
+
+        if !self.is_set() {
+            self.offset = measured;
+            self.frequency = 0.0;
+            self.jitter = 0.0;
+            self.epoch = local;
+            self.baseline = Some((local, measured));
+            self.samples = 1;
+            return;
+        }
+
+        let interval = local.diff_to_sec(&self.epoch);
+        let predicted = self.offset_at(&local);
+        let error = measured - predicted;
+
+        if error.abs() > STEP_THRESHOLD || interval <= 0.0 {
+            debug!("Clock model step {:.6} s", error);
+            self.offset = measured;
+            self.baseline = Some((local, measured));
+        } else {
+            self.train_frequency(measured, local);
+            self.offset = predicted + PHASE_GAIN * error;
+            self.jitter = (self.jitter.powi(2) + (error.powi(2) - self.jitter.powi(2)) / 4.0).sqrt();
+        }
+        self.epoch = local;
+        self.samples += 1;
+    }
+
+    /// Moves the frequency towards the drift of the measured offset since
+    /// the baseline once that is long enough.
+    fn train_frequency(&mut self, measured: f64, local: NtpTimestamp) {
+        let (start, start_offset) = *self.baseline.get_or_insert((local, measured));
+        let span = local.diff_to_sec(&start);
+        if span < MIN_FREQ_INTERVAL {
+            return;
+        }
+        let drift = (measured - start_offset) / span;
+        self.frequency = (self.frequency + FREQ_GAIN * (drift - self.frequency)).clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+        self.baseline = Some((local, measured));
+    }
+
+    /// Feeds a low-resolution sample; it only sets or steps the model when
+    /// the model is unset or clearly wrong.
+    pub fn coarse_update(&mut self, reference: NtpTimestamp, local: NtpTimestamp) {
+        let measured = reference.diff_to_sec(&local);
+        if !self.is_set() || (measured - self.offset_at(&local)).abs() > COARSE_THRESHOLD {
+            self.offset = measured;
+            self.epoch = local;
+            self.samples += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start() -> NtpTimestamp {
+        NtpTimestamp::from_unix_secs(1_700_000_000)
+    }
+
+    /// Feeds one sample per second from a reference running `drift` faster
+    /// than the system clock, starting `offset` seconds ahead.
+    fn feed(model: &mut ClockModel, seconds: std::ops::Range<u64>, offset: f64, drift: f64) {
+        for second in seconds {
+            let local = start().add_sec(second as f64);
+            model.update(local.add_sec(offset + drift * second as f64), local);
+        }
+    }
+
+    #[test]
+    fn learns_frequency_from_1hz_samples() {
+        let mut model = ClockModel::new();
+        feed(&mut model, 0..3600, 0.25, 20e-6);
+        assert!((model.frequency() - 20e-6).abs() < 1e-8);
+
+        // The model keeps predicting the reference between samples.
+        let local = start().add_sec(4000.0);
+        let reference = local.add_sec(0.25 + 20e-6 * 4000.0);
+        assert!(model.convert(local).diff_to_sec(&reference).abs() < 1e-5);
+    }
+
+    #[test]
+    fn short_baselines_leave_frequency_alone() {
+        let mut model = ClockModel::new();
+        feed(&mut model, 0..MIN_FREQ_INTERVAL as u64, 0.0, 20e-6);
+        assert_eq!(model.frequency(), 0.0);
+        feed(&mut model, MIN_FREQ_INTERVAL as u64..MIN_FREQ_INTERVAL as u64 + 1, 0.0, 20e-6);
+        assert!((model.frequency() - FREQ_GAIN * 20e-6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn step_restarts_the_baseline() {
+        let mut model = ClockModel::new();
+        feed(&mut model, 0..600, 0.0, 10e-6);
+        let before = model.frequency();
+        assert!(before > 5e-6);
+
+        // A one second step is not mistaken for drift.
+        feed(&mut model, 600..1200, 1.0, 10e-6);
+        assert!((model.frequency() - 10e-6).abs() < (before - 10e-6).abs());
+        let local = start().add_sec(1200.0);
+        assert!((model.offset_at(&local) - (1.0 + 10e-6 * 1200.0)).abs() < 1e-5);
+    }
+}
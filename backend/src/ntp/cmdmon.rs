@@ -0,0 +1,538 @@
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::control::Snapshot;
+use super::rate_limit::RateStats;
+use super::selection::CandidateStatus;
+use super::{NtpSource, NtpSourceReport, NtpTimestamp};
+
+// chrony command and monitoring protocol, version 6 (candm.h). Only the
+// read-only monitoring requests are answered.
+const PROTO_VERSION: u8 = 6;
+const PKT_TYPE_REQUEST: u8 = 1;
+const PKT_TYPE_REPLY: u8 = 2;
+const REQUEST_HEADER_LEN: usize = 20;
+const REPLY_HEADER_LEN: usize = 28;
+
+const REQ_NULL: u16 = 0;
+const REQ_N_SOURCES: u16 = 14;
+const REQ_SOURCE_DATA: u16 = 15;
+const REQ_TRACKING: u16 = 33;
+const REQ_SOURCESTATS: u16 = 34;
+const REQ_SERVER_STATS: u16 = 54;
+
+const RPY_NULL: u16 = 1;
+const RPY_N_SOURCES: u16 = 2;
+const RPY_SOURCE_DATA: u16 = 3;
+const RPY_TRACKING: u16 = 5;
+const RPY_SOURCESTATS: u16 = 6;
+const RPY_SERVER_STATS4: u16 = 25;
+
+const STT_SUCCESS: u16 = 0;
+const STT_UNAUTH: u16 = 2;
+const STT_NOSUCHSOURCE: u16 = 4;
+
+const IPADDR_UNSPEC: u16 = 0;
+const IPADDR_INET4: u16 = 1;
+const IPADDR_INET6: u16 = 2;
+
+// Source states and modes of the sources report.
+const SD_ST_SELECTED: u16 = 0;
+const SD_ST_NONSELECTABLE: u16 = 1;
+const SD_ST_FALSETICKER: u16 = 2;
+const SD_ST_UNSELECTED: u16 = 4;
+const SD_ST_SELECTABLE: u16 = 5;
+const SD_MD_CLIENT: u16 = 0;
+const SD_MD_REF: u16 = 2;
+
+// chrony's 32-bit floating point: 7 bit exponent, 25 bit coefficient.
+const FLOAT_EXP_BITS: i32 = 7;
+const FLOAT_COEF_BITS: i32 = 32 - FLOAT_EXP_BITS;
+const FLOAT_EXP_MIN: i32 = -(1 << (FLOAT_EXP_BITS - 1));
+const FLOAT_EXP_MAX: i32 = -FLOAT_EXP_MIN - 1;
+const FLOAT_COEF_MAX: i32 = (1 << (FLOAT_COEF_BITS - 1)) - 1;
+
+#[derive(Debug, Clone)]
+pub struct CommandRequest {
+    pub command: u16,
+    pub sequence: u32,
+    pub data: Vec<u8>,
+    /// Received length including padding, replies must not be longer.
+    pub len: usize,
+}
+
+/// Requests answered and dropped on the command port.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CommandStats {
+    pub hits: u64,
+    pub drops: u64,
+}
+
+impl CommandRequest {
+    pub fn parse(buf: &[u8]) -> io::Result<CommandRequest> {
+        if buf.len() < REQUEST_HEADER_LEN {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Command request too short"));
+        }
+        if buf[0] != PROTO_VERSION || buf[1] != PKT_TYPE_REQUEST {
+            return Err(Error::new(ErrorKind::InvalidData, "Unsupported command packet"));
+        }
+        Ok(CommandRequest {
+            command: BigEndian::read_u16(&buf[4..6]),
+            sequence: BigEndian::read_u32(&buf[8..12]),
+            data: buf[REQUEST_HEADER_LEN..].to_vec(),
+            len: buf.len(),
+        })
+    }
+
+    fn index(&self) -> Option<usize> {
+        self.data.get(0..4).map(|data| BigEndian::read_u32(data) as usize)
+    }
+}
+
+/// Builds the reply, or nothing when the request was not padded to the
+/// reply length as chronyc does to prevent amplification.
+pub fn respond(
+    request: &CommandRequest,
+    snapshot: &Snapshot,
+    rate: &RateStats,
+    commands: &CommandStats,
+) -> Option<Vec<u8>> {
+    let (reply, status, data) = match request.command {
+        REQ_NULL => (RPY_NULL, STT_SUCCESS, vec![]),
+        REQ_N_SOURCES => {
+            let mut data = vec![];
+            put_u32(&mut data, snapshot.sources.len() as u32);
+            (RPY_N_SOURCES, STT_SUCCESS, data)
+        }
+        REQ_SOURCE_DATA => match request.index().and_then(|index| snapshot.sources.get(index)) {
+            Some(report) => (RPY_SOURCE_DATA, STT_SUCCESS, source_data(report)),
+            None => (RPY_NULL, STT_NOSUCHSOURCE, vec![]),
+        },
+        REQ_TRACKING => (RPY_TRACKING, STT_SUCCESS, tracking(snapshot)),
+        REQ_SOURCESTATS => match request.index().and_then(|index| snapshot.sources.get(index)) {
+            Some(report) => (RPY_SOURCESTATS, STT_SUCCESS, source_stats(report)),
+            None => (RPY_NULL, STT_NOSUCHSOURCE, vec![]),
+        },
+        REQ_SERVER_STATS => (RPY_SERVER_STATS4, STT_SUCCESS, server_stats(rate, commands)),
+        _ => (RPY_NULL, STT_UNAUTH, vec![]),
+    };
+
+    let mut buf = vec![0; REPLY_HEADER_LEN];
+    buf[0] = PROTO_VERSION;
+    buf[1] = PKT_TYPE_REPLY;
+    BigEndian::write_u16(&mut buf[4..6], request.command);
+    BigEndian::write_u16(&mut buf[6..8], reply);
+    BigEndian::write_u16(&mut buf[8..10], status);
+    BigEndian::write_u32(&mut buf[16..20], request.sequence);
+    // The EOR field of candm.h only marks the end of the structures, it is
+    // not sent.
+    buf.extend_from_slice(&data);
+
+    if buf.len() > request.len {
+        return None;
+    }
+    Some(buf)
+}
+
+fn tracking(snapshot: &Snapshot) -> Vec<u8> {
+    let state = &snapshot.state;
+    let status = &snapshot.status;
+    let interval = snapshot
+        .sources
+        .iter()
+        .find(|report| report.status == CandidateStatus::SystemPeer)
+        .and_then(|report| report.interval)
+        .map_or(0.0, |interval| interval.as_secs_f64());
+
+    let mut data = vec![];
+    put_u32(&mut data, state.ref_id);
+    match status.source {
+        NtpSource::Ntp { addr, .. } => put_addr(&mut data, Some(addr)),
+        _ => put_addr(&mut data, None),
+    }
+    put_u16(&mut data, state.stratum as u16);
+    put_u16(&mut data, state.leap as u16);
+    put_timespec(&mut data, &state.ref_ts);
+    // chrony counts offsets positive when the system clock is ahead and
+    // frequencies positive when it runs fast, the opposite of the model.
+    put_float(&mut data, state.clock.offset_at(&snapshot.local));
+    put_float(&mut data, -status.offset);
+    put_float(&mut data, status.jitter);
+    put_float(&mut data, -state.clock.frequency() * 1e6);
+    put_float(&mut data, 0.0);
+    put_float(&mut data, 0.0);
+    put_float(&mut data, status.root_delay);
+    put_float(&mut data, status.root_dispersion);
+    put_float(&mut data, interval);
+    data
+}
+
+fn source_data(report: &NtpSourceReport) -> Vec<u8> {
+    let state = match report.status {
+        CandidateStatus::SystemPeer => SD_ST_SELECTED,
+        CandidateStatus::Survivor => SD_ST_SELECTABLE,
+        CandidateStatus::Outlier => SD_ST_UNSELECTED,
+        CandidateStatus::Falseticker => SD_ST_FALSETICKER,
+        CandidateStatus::Rejected => SD_ST_NONSELECTABLE,
+    };
+    let reach: u16 = if report.fresh {
+        (1 << report.samples.min(8)) - 1
+    } else {
+        0
+    };
+    let poll = report
+        .interval
+        .map_or(0, |interval| interval.as_secs_f64().max(1.0).log2().round() as i16);
+    let quality = &report.quality;
+
+    let mut data = vec![];
+    match report.source {
+        NtpSource::Ntp { addr, .. } => {
+            put_addr(&mut data, Some(addr));
+        }
+        source => {
+            // Reference clocks are shown by their reference ID.
+            put_u32(&mut data, source.ref_id());
+            data.extend_from_slice(&[0; 12]);
+            put_u16(&mut data, IPADDR_INET4);
+            put_u16(&mut data, 0);
+        }
+    }
+    put_u16(&mut data, poll as u16);
+    put_u16(&mut data, report.source.stratum().saturating_sub(1) as u16);
+    put_u16(&mut data, state);
+    put_u16(
+        &mut data,
+        match report.source {
+            NtpSource::Ntp { .. } => SD_MD_CLIENT,
+            _ => SD_MD_REF,
+        },
+    );
+    put_u16(&mut data, 0);
+    put_u16(&mut data, reach);
+    put_u32(&mut data, report.age.as_secs() as u32);
+    put_float(&mut data, -report.offset);
+    put_float(&mut data, -report.offset);
+    put_float(&mut data, quality.delay / 2.0 + quality.dispersion);
+    data
+}
+
+fn source_stats(report: &NtpSourceReport) -> Vec<u8> {
+    let span = report
+        .interval
+        .map_or(0, |interval| interval.as_secs() as u32 * report.samples.saturating_sub(1));
+
+    let mut data = vec![];
+    match report.source {
+        NtpSource::Ntp { addr, .. } => {
+            put_u32(&mut data, report.source.ref_id());
+            put_addr(&mut data, Some(addr));
+        }
+        source => {
+            put_u32(&mut data, source.ref_id());
+            put_addr(&mut data, None);
+        }
+    }
+    put_u32(&mut data, report.samples);
+    put_u32(&mut data, 0);
+    put_u32(&mut data, span);
+    put_float(&mut data, report.quality.jitter);
+    // No per-source frequency is estimated.
+    put_float(&mut data, 0.0);
+    put_float(&mut data, 0.0);
+    put_float(&mut data, -report.offset);
+    put_float(&mut data, report.quality.jitter);
+    data
+}
+
+fn server_stats(rate: &RateStats, commands: &CommandStats) -> Vec<u8> {
+    let counters = [
+        rate.passed + rate.dropped + rate.kod,
+        0,
+        commands.hits,
+        rate.dropped,
+        0,
+        commands.drops,
+    ];
+    let mut data = vec![];
+    for counter in counters {
+        put_u64(&mut data, counter);
+    }
+    // Log drops, authenticated and interleaved hits, timestamp counters and
+    // reserved fields are not tracked.
+    for _ in counters.len()..21 {
+        put_u64(&mut data, 0);
+    }
+    data
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    put_u32(buf, (value >> 32) as u32);
+    put_u32(buf, value as u32);
+}
+
+fn put_addr(buf: &mut Vec<u8>, addr: Option<SocketAddr>) {
+    let mut octets = [0; 16];
+    let family = match addr.map(|addr| addr.ip()) {
+        Some(IpAddr::V4(ip)) => {
+            octets[..4].copy_from_slice(&ip.octets());
+            IPADDR_INET4
+        }
+        Some(IpAddr::V6(ip)) => {
+            octets.copy_from_slice(&ip.octets());
+            IPADDR_INET6
+        }
+        None => IPADDR_UNSPEC,
+    };
+    buf.extend_from_slice(&octets);
+    put_u16(buf, family);
+    put_u16(buf, 0);
+}
+
+fn put_timespec(buf: &mut Vec<u8>, ts: &NtpTimestamp) {
+    let (secs, nanos) = if *ts == NtpTimestamp::zero() {
+        (0, 0)
+    } else {
+        (ts.unix_seconds(), ts.subsec_nanos())
+    };
+    put_u32(buf, (secs >> 32) as u32);
+    put_u32(buf, secs as u32);
+    put_u32(buf, nanos);
+}
+
+fn put_float(buf: &mut Vec<u8>, value: f64) {
+    put_u32(buf, encode_float(value));
+}
+
+/// Same rounding as chrony's UTI_FloatHostToNetwork.
+fn encode_float(value: f64) -> u32 {
+    let negative = value < 0.0;
+    let x = if value.is_nan() { 0.0 } else { value.abs() };
+    let limit = FLOAT_COEF_MAX + negative as i32;
+
+    let (mut exp, mut coef) = if x < 1e-100 {
+        (0, 0)
+    } else if x > 1e100 {
+        (FLOAT_EXP_MAX, limit)
+    } else {
+        let exp = (x.log2() + 1.0) as i32;
+        (exp, (x * 2f64.powi(FLOAT_COEF_BITS - exp) + 0.5) as i32)
+    };
+    while coef > limit {
+        coef >>= 1;
+        exp += 1;
+    }
+    if exp > FLOAT_EXP_MAX {
+        exp = FLOAT_EXP_MAX;
+        coef = limit;
+    } else if exp < FLOAT_EXP_MIN {
+        if exp + FLOAT_COEF_BITS >= FLOAT_EXP_MIN {
+            coef >>= FLOAT_EXP_MIN - exp;
+            exp = FLOAT_EXP_MIN;
+        } else {
+            exp = 0;
+            coef = 0;
+        }
+    }
+
+    let mut coef = coef as u32;
+    if negative {
+        coef = coef.wrapping_neg() & ((1 << FLOAT_COEF_BITS) - 1);
+    }
+    (exp as u32) << FLOAT_COEF_BITS | coef
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::ntp::{NtpClockModel, NtpFracValue, NtpSampleQuality, NtpServerState, NtpServerStatus, NtpSyncStatus};
+
+    // offsetof(CMD_Reply, data) + sizeof(RPY_...) in candm.h, the length
+    // chronyc pads each request to.
+    const N_SOURCES_LEN: usize = 32;
+    const SOURCE_DATA_LEN: usize = 76;
+    const TRACKING_LEN: usize = 104;
+    const SOURCESTATS_LEN: usize = 84;
+    const SERVER_STATS_LEN: usize = 196;
+
+    fn request(command: u16, index: Option<u32>, padded_len: usize) -> CommandRequest {
+        let mut buf = vec![PROTO_VERSION, PKT_TYPE_REQUEST, 0, 0];
+        put_u16(&mut buf, command);
+        put_u16(&mut buf, 0);
+        put_u32(&mut buf, 0x1234_5678);
+        buf.extend_from_slice(&[0; 8]);
+        if let Some(index) = index {
+            put_u32(&mut buf, index);
+        }
+        buf.resize(padded_len.max(buf.len()), 0);
+        CommandRequest::parse(&buf).unwrap()
+    }
+
+    fn snapshot() -> Snapshot {
+        let upstream = NtpSource::Ntp {
+            addr: SocketAddr::from(([192, 0, 2, 1], 123)),
+            stratum: 1,
+            leap: 0,
+        };
+        Snapshot {
+            state: NtpServerState {
+                leap: 0,
+                stratum: 2,
+                precision: -20,
+                ref_id: 0xc000_0201,
+                ref_ts: NtpTimestamp::from_unix_secs(1_700_000_000),
+                dispersion: NtpFracValue::zero(),
+                delay: NtpFracValue::zero(),
+                clock: NtpClockModel::new(),
+                sync: NtpSyncStatus::Synchronized,
+                dispersion_rate: 0.0,
+            },
+            status: NtpServerStatus {
+                sync: NtpSyncStatus::Synchronized,
+                source: upstream,
+                stratum: 2,
+                leap: 0,
+                offset: 0.0,
+                jitter: 0.0,
+                root_delay: 0.0,
+                root_dispersion: 0.0,
+                holdover_secs: None,
+                holdover_error: None,
+                holdover_frequency_ppm: None,
+            },
+            sources: vec![NtpSourceReport {
+                source: upstream,
+                status: CandidateStatus::SystemPeer,
+                offset: 0.0,
+                quality: NtpSampleQuality::default(),
+                age: Duration::from_secs(3),
+                interval: Some(Duration::from_secs(64)),
+                samples: 8,
+                fresh: true,
+            }],
+            now: NtpTimestamp::from_unix_secs(1_700_000_010),
+            local: NtpTimestamp::from_unix_secs(1_700_000_010),
+        }
+    }
+
+    fn reply(request: &CommandRequest) -> Option<Vec<u8>> {
+        let rate = RateStats::default();
+        let commands = CommandStats { hits: 5, drops: 1 };
+        respond(request, &snapshot(), &rate, &commands)
+    }
+
+    fn u16_at(buf: &[u8], offset: usize) -> u16 {
+        BigEndian::read_u16(&buf[offset..offset + 2])
+    }
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        BigEndian::read_u32(&buf[offset..offset + 4])
+    }
+
+    #[test]
+    fn replies_fill_padded_requests() {
+        let cases = [
+            (REQ_NULL, None, REPLY_HEADER_LEN, RPY_NULL),
+            (REQ_N_SOURCES, None, N_SOURCES_LEN, RPY_N_SOURCES),
+            (REQ_SOURCE_DATA, Some(0), SOURCE_DATA_LEN, RPY_SOURCE_DATA),
+            (REQ_TRACKING, None, TRACKING_LEN, RPY_TRACKING),
+            (REQ_SOURCESTATS, Some(0), SOURCESTATS_LEN, RPY_SOURCESTATS),
+            (REQ_SERVER_STATS, None, SERVER_STATS_LEN, RPY_SERVER_STATS4),
+        ];
+        for (command, index, len, reply_code) in cases {
+            let request = request(command, index, len);
+            let reply = reply(&request).unwrap();
+            assert_eq!(reply.len(), len, "command {}", command);
+            assert_eq!((reply[0], reply[1]), (PROTO_VERSION, PKT_TYPE_REPLY));
+            assert_eq!(u16_at(&reply, 4), command);
+            assert_eq!(u16_at(&reply, 6), reply_code);
+            assert_eq!(u16_at(&reply, 8), STT_SUCCESS);
+            assert_eq!(u32_at(&reply, 16), 0x1234_5678);
+        }
+    }
+
+    #[test]
+    fn unpadded_requests_are_dropped() {
+        assert!(reply(&request(REQ_TRACKING, None, TRACKING_LEN - 1)).is_none());
+        assert!(reply(&request(REQ_SOURCE_DATA, Some(0), REQUEST_HEADER_LEN + 4)).is_none());
+    }
+
+    #[test]
+    fn field_layout() {
+        let buf = reply(&request(REQ_N_SOURCES, None, N_SOURCES_LEN)).unwrap();
+        assert_eq!(u32_at(&buf, 28), 1);
+
+        // ref_id, ip_addr, stratum and leap_status of RPY_Tracking.
+        let buf = reply(&request(REQ_TRACKING, None, TRACKING_LEN)).unwrap();
+        assert_eq!(u32_at(&buf, 28), 0xc000_0201);
+        assert_eq!(&buf[32..36], &[192, 0, 2, 1]);
+        assert_eq!(u16_at(&buf, 48), IPADDR_INET4);
+        assert_eq!(u16_at(&buf, 52), 2);
+        assert_eq!(u16_at(&buf, 54), 0);
+        // ref_time as Unix seconds.
+        assert_eq!(u32_at(&buf, 60), 1_700_000_000);
+
+        // poll, stratum, state, mode, flags, reachability, since_sample.
+        let buf = reply(&request(REQ_SOURCE_DATA, Some(0), SOURCE_DATA_LEN)).unwrap();
+        assert_eq!(u16_at(&buf, 48), 6);
+        assert_eq!(u16_at(&buf, 50), 1);
+        assert_eq!(u16_at(&buf, 52), SD_ST_SELECTED);
+        assert_eq!(u16_at(&buf, 54), SD_MD_CLIENT);
+        assert_eq!(u16_at(&buf, 58), 0xff);
+        assert_eq!(u32_at(&buf, 60), 3);
+
+        // n_samples follows ref_id and ip_addr.
+        let buf = reply(&request(REQ_SOURCESTATS, Some(0), SOURCESTATS_LEN)).unwrap();
+        assert_eq!(u32_at(&buf, 52), 8);
+
+        // cmd_hits and cmd_drops are the third and sixth counters.
+        let buf = reply(&request(REQ_SERVER_STATS, None, SERVER_STATS_LEN)).unwrap();
+        assert_eq!(u32_at(&buf, 28 + 2 * 8 + 4), 5);
+        assert_eq!(u32_at(&buf, 28 + 5 * 8 + 4), 1);
+    }
+
+    #[test]
+    fn unknown_source_index() {
+        let reply = reply(&request(REQ_SOURCE_DATA, Some(7), SOURCE_DATA_LEN)).unwrap();
+        assert_eq!(u16_at(&reply, 8), STT_NOSUCHSOURCE);
+        assert_eq!(reply.len(), REPLY_HEADER_LEN);
+    }
+
+    // UTI_FloatNetworkToHost() of chronyc.
+    fn decode_float(f: u32) -> f64 {
+        let mut exp = (f >> FLOAT_COEF_BITS) as i32;
+        if exp >= 1 << (FLOAT_EXP_BITS - 1) {
+            exp -= 1 << FLOAT_EXP_BITS;
+        }
+        exp -= FLOAT_COEF_BITS;
+        let mut coef = (f % (1 << FLOAT_COEF_BITS)) as i32;
+        if coef >= 1 << (FLOAT_COEF_BITS - 1) {
+            coef -= 1 << FLOAT_COEF_BITS;
+        }
+        coef as f64 * 2f64.powi(exp)
+    }
+
+    #[test]
+    fn floats_match_chrony() {
+        assert_eq!(encode_float(0.0), 0);
+        // 1.0 = 2^23 * 2^(2 - 25)
+        assert_eq!(encode_float(1.0), 2 << FLOAT_COEF_BITS | 1 << 23);
+        for value in [1.0, -1.0, 0.5, 1e-6, -3.25e-3, 64.0, 1234.5678] {
+            let decoded = decode_float(encode_float(value));
+            assert!((decoded - value).abs() <= value.abs() * 1e-7, "{} decoded as {}", value, decoded);
+        }
+    }
+}
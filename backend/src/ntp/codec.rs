@@ -0,0 +1,354 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use byteorder::{BigEndian, ByteOrder};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::extension::{self, MIN_LAST_FIELD_LEN};
+use super::{NtpFracValue, NtpPacket, NtpTimestamp};
+
+// Fixed header of every packet (RFC 5905 section 7.3).
+pub const HEADER_LEN: usize = 48;
+
+/// Why a datagram is not an NTP packet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Shorter than the 48 byte header.
+    TooShort,
+    BadVersion(u8),
+    /// Control (6) and private (7) messages, or mode 0 after NTPv1.
+    BadMode(u8),
+    /// Malformed extension field.
+    BadExtension,
+    /// Trailer of an NTPv3 packet that is not a MAC.
+    BadMac,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::TooShort => write!(f, "Packet too short"),
+            ParseError::BadVersion(version) => write!(f, "Unsupported version {}", version),
+            ParseError::BadMode(mode) => write!(f, "Unsupported mode {}", mode),
+            ParseError::BadExtension => write!(f, "Malformed extension field"),
+            ParseError::BadMac => write!(f, "Malformed MAC"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(error: ParseError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Parses a packet received from `remote_addr` at `local_ts`.
+pub fn decode(buf: &[u8], remote_addr: SocketAddr, local_ts: NtpTimestamp) -> Result<NtpPacket, ParseError> {
+    if buf.len() < HEADER_LEN {
+        return Err(ParseError::TooShort);
+    }
+
+    let leap = buf[0] >> 6;
+    let version = (buf[0] >> 3) & 0x7;
+    let mode = buf[0] & 0x7;
+
+    if !(1..=4).contains(&version) {
+        return Err(ParseError::BadVersion(version));
+    }
+    if mode > 5 || (mode == 0 && version != 1) {
+        return Err(ParseError::BadMode(mode));
+    }
+
+    // Extension fields come first; a trailing key ID alone is a crypto-NAK,
+    // otherwise it is followed by an MD5, CMAC or SHA-1 digest.
+    let (extensions, mac) = extension::parse_trailer(&buf[HEADER_LEN..], version)?;
+
+    Ok(NtpPacket {
+        remote_addr,
+        local_ts,
+        leap,
+        version,
+        mode,
+        stratum: buf[1],
+        poll: buf[2] as i8,
+        precision: buf[3] as i8,
+        delay: NtpFracValue::read(&buf[4..8]),
+        dispersion: NtpFracValue::read(&buf[8..12]),
+        ref_id: BigEndian::read_u32(&buf[12..16]),
+        ref_ts: NtpTimestamp::read(&buf[16..24]),
+        orig_ts: NtpTimestamp::read(&buf[24..32]),
+        rx_ts: NtpTimestamp::read(&buf[32..40]),
+        tx_ts: NtpTimestamp::read(&buf[40..48]),
+        extensions,
+        mac,
+    })
+}
+
+/// Wire format; without a MAC the last extension field is padded so it
+/// cannot be taken for one.
+pub fn encode(packet: &NtpPacket) -> Vec<u8> {
+    match (&packet.mac, packet.extensions.last()) {
+        (Some(mac), _) => {
+            let mut buf = encode_prefix(packet, packet.extensions.len());
+            buf.extend_from_slice(&mac.key_id.to_be_bytes());
+            buf.extend_from_slice(&mac.digest);
+            buf
+        }
+        (None, Some(last)) => {
+            let mut buf = encode_prefix(packet, packet.extensions.len() - 1);
+            last.write(&mut buf, MIN_LAST_FIELD_LEN);
+            buf
+        }
+        (None, None) => encode_prefix(packet, 0),
+    }
+}
+
+/// Header followed by the first `fields` extension fields.
+pub fn encode_prefix(packet: &NtpPacket, fields: usize) -> Vec<u8> {
+    let mut buf = vec![0; HEADER_LEN];
+
+    buf[0] = packet.leap << 6 | packet.version << 3 | packet.mode;
+    buf[1] = packet.stratum;
+    buf[2] = packet.poll as u8;
+    buf[3] = packet.precision as u8;
+    packet.delay.write(&mut buf[4..8]);
+    packet.dispersion.write(&mut buf[8..12]);
+    BigEndian::write_u32(&mut buf[12..16], packet.ref_id);
+    packet.ref_ts.write(&mut buf[16..24]);
+    packet.orig_ts.write(&mut buf[24..32]);
+    packet.rx_ts.write(&mut buf[32..40]);
+    packet.tx_ts.write(&mut buf[40..48]);
+    extension::write_fields(&packet.extensions[..fields], &mut buf);
+    buf
+}
+
+/// Packets dropped because they did not parse, by reason.
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct ParseErrorStats {
+    pub too_short: u64,
+    pub bad_version: u64,
+    pub bad_mode: u64,
+    pub bad_extension: u64,
+    pub bad_mac: u64,
+}
+
+/// Counters shared by all request workers.
+#[derive(Default)]
+pub struct ParseErrorCounters {
+    too_short: AtomicU64,
+    bad_version: AtomicU64,
+    bad_mode: AtomicU64,
+    bad_extension: AtomicU64,
+    bad_mac: AtomicU64,
+}
+
+impl ParseErrorCounters {
+    pub fn count(&self, error: ParseError) {
+        let counter = match error {
+            ParseError::TooShort => &self.too_short,
+            ParseError::BadVersion(_) => &self.bad_version,
+            ParseError::BadMode(_) => &self.bad_mode,
+            ParseError::BadExtension => &self.bad_extension,
+            ParseError::BadMac => &self.bad_mac,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ParseErrorStats {
+        ParseErrorStats {
+            too_short: self.too_short.load(Ordering::Relaxed),
+            bad_version: self.bad_version.load(Ordering::Relaxed),
+            bad_mode: self.bad_mode.load(Ordering::Relaxed),
+            bad_extension: self.bad_extension.load(Ordering::Relaxed),
+            bad_mac: self.bad_mac.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::ntp::extension::FieldType;
+    use crate::ntp::{NtpExtensionField, NtpMac};
+
+    const CASES: usize = 2000;
+
+    fn addr() -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], 123))
+    }
+
+    fn bytes(rng: &mut StdRng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    /// Any packet the server could build or receive.
+    fn random_packet(rng: &mut StdRng) -> NtpPacket {
+        let version = rng.gen_range(1, 5);
+        let mode = if version == 1 { rng.gen_range(0, 6) } else { rng.gen_range(1, 6) };
+        let mut extensions = vec![];
+        if version == 4 {
+            for _ in 0..rng.gen_range(0, 4) {
+                // Shorter fields followed by a MAC can't be told apart from
+                // the MAC itself (RFC 7822 section 7.5).
+                let len = 4 * rng.gen_range(6, 12);
+                let field_type = FieldType::from_code(rng.gen());
+                extensions.push(NtpExtensionField::new(field_type, bytes(rng, len)));
+            }
+        }
+        let mac = match rng.gen_range(0, 4) {
+            0 => None,
+            digest => Some(NtpMac {
+                key_id: rng.gen(),
+                digest: bytes(rng, [0, 16, 20][digest - 1]),
+            }),
+        };
+        NtpPacket {
+            remote_addr: addr(),
+            local_ts: NtpTimestamp::zero(),
+            leap: rng.gen_range(0, 4),
+            version,
+            mode,
+            stratum: rng.gen(),
+            poll: rng.gen(),
+            precision: rng.gen(),
+            delay: NtpFracValue::read(&bytes(rng, 4)),
+            dispersion: NtpFracValue::read(&bytes(rng, 4)),
+            ref_id: rng.gen(),
+            ref_ts: NtpTimestamp::new(rng.gen()),
+            orig_ts: NtpTimestamp::new(rng.gen()),
+            rx_ts: NtpTimestamp::new(rng.gen()),
+            tx_ts: NtpTimestamp::new(rng.gen()),
+            extensions,
+            mac,
+        }
+    }
+
+    fn parse(buf: &[u8]) -> Result<NtpPacket, ParseError> {
+        decode(buf, addr(), NtpTimestamp::zero())
+    }
+
+    #[test]
+    fn round_trip_keeps_fields() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..CASES {
+            let packet = random_packet(&mut rng);
+            let decoded = parse(&encode(&packet)).unwrap();
+
+            assert_eq!(decoded.leap, packet.leap);
+            assert_eq!(decoded.version, packet.version);
+            assert_eq!(decoded.mode, packet.mode);
+            assert_eq!(decoded.stratum, packet.stratum);
+            assert_eq!(decoded.poll, packet.poll);
+            assert_eq!(decoded.precision, packet.precision);
+            assert_eq!(decoded.delay, packet.delay);
+            assert_eq!(decoded.dispersion, packet.dispersion);
+            assert_eq!(decoded.ref_id, packet.ref_id);
+            assert_eq!(decoded.ref_ts, packet.ref_ts);
+            assert_eq!(decoded.orig_ts, packet.orig_ts);
+            assert_eq!(decoded.rx_ts, packet.rx_ts);
+            assert_eq!(decoded.tx_ts, packet.tx_ts);
+            assert_eq!(decoded.mac, packet.mac);
+            assert_eq!(decoded.extensions.len(), packet.extensions.len());
+            for (decoded, field) in decoded.extensions.iter().zip(&packet.extensions) {
+                assert_eq!(decoded.field_type, field.field_type);
+                assert_eq!(decoded.value, field.value);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_bytes() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..CASES {
+            let buf = encode(&random_packet(&mut rng));
+            assert_eq!(encode(&parse(&buf).unwrap()), buf);
+        }
+    }
+
+    #[test]
+    fn errors_by_kind() {
+        let mut buf = vec![0u8; HEADER_LEN];
+        assert_eq!(parse(&buf[..HEADER_LEN - 1]).unwrap_err(), ParseError::TooShort);
+
+        buf[0] = 5 << 3 | 3;
+        assert_eq!(parse(&buf).unwrap_err(), ParseError::BadVersion(5));
+        buf[0] = 3;
+        assert_eq!(parse(&buf).unwrap_err(), ParseError::BadVersion(0));
+
+        for (version, mode) in [(4, 6), (4, 7), (2, 0), (4, 0)] {
+            buf[0] = version << 3 | mode;
+            assert_eq!(parse(&buf).unwrap_err(), ParseError::BadMode(mode));
+        }
+        buf[0] = 1 << 3;
+        assert!(parse(&buf).is_ok());
+
+        // Field length not a multiple of four.
+        buf[0] = 4 << 3 | 3;
+        let mut field = buf.clone();
+        field.extend_from_slice(&[0x01, 0x04, 0x00, 0x1e]);
+        field.resize(HEADER_LEN + 32, 0);
+        assert_eq!(parse(&field).unwrap_err(), ParseError::BadExtension);
+        // Field longer than the packet.
+        field[HEADER_LEN + 3] = 0x40;
+        assert_eq!(parse(&field).unwrap_err(), ParseError::BadExtension);
+        // Last field shorter than a MAC could be.
+        field[HEADER_LEN + 3] = 0x10;
+        field.truncate(HEADER_LEN + 16);
+        assert_eq!(parse(&field).unwrap_err(), ParseError::BadExtension);
+
+        buf[0] = 3 << 3 | 3;
+        buf.resize(HEADER_LEN + 12, 0);
+        assert_eq!(parse(&buf).unwrap_err(), ParseError::BadMac);
+        buf.resize(HEADER_LEN + 20, 0);
+        assert!(parse(&buf).is_ok());
+    }
+
+    #[test]
+    fn counts_errors_by_kind() {
+        let counters = ParseErrorCounters::default();
+        counters.count(ParseError::TooShort);
+        counters.count(ParseError::BadMode(6));
+        counters.count(ParseError::BadMode(7));
+        let stats = counters.stats();
+        assert_eq!((stats.too_short, stats.bad_mode, stats.bad_mac), (1, 2, 0));
+    }
+
+    /// Seeded run of the property the `decode` fuzz target in `fuzz/`
+    /// checks: whatever is accepted encodes back to the same bytes.
+    #[test]
+    fn mutated_packets_round_trip() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..20_000 {
+            let mut buf = if rng.gen() {
+                let len = rng.gen_range(0, 200);
+                bytes(&mut rng, len)
+            } else {
+                encode(&random_packet(&mut rng))
+            };
+            for _ in 0..rng.gen_range(0, 4) {
+                match rng.gen_range(0, 3) {
+                    0 if !buf.is_empty() => {
+                        let index = rng.gen_range(0, buf.len());
+                        buf[index] ^= 1 << rng.gen_range(0, 8);
+                    }
+                    1 => buf.truncate(rng.gen_range(0, buf.len() + 1)),
+                    _ => {
+                        let extra = rng.gen_range(1, 32);
+                        buf.extend(bytes(&mut rng, extra));
+                    }
+                }
+            }
+            if let Ok(packet) = parse(&buf) {
+                assert_eq!(encode(&packet), buf);
+            }
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use std::io::{self, Error, ErrorKind};
+use std::net::Ipv4Addr;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::selection::CandidateStatus;
+use super::{NtpServerState, NtpServerStatus, NtpSource, NtpSourceReport, NtpTimestamp};
+
+// Mode 6 control messages as used by ntpq (RFC 1305 appendix B).
+pub const MODE_CONTROL: u8 = 6;
+const HEADER_LEN: usize = 12;
+// Data carried by one response fragment, as in ntpd.
+const MAX_DATA_LEN: usize = 468;
+
+const OP_READSTAT: u8 = 1;
+const OP_READVAR: u8 = 2;
+
+const FLAG_RESPONSE: u8 = 0x80;
+const FLAG_ERROR: u8 = 0x40;
+const FLAG_MORE: u8 = 0x20;
+
+// System status clock sources.
+const SOURCE_UNSPEC: u16 = 0;
+const SOURCE_UHF: u16 = 4;
+const SOURCE_LOCAL: u16 = 5;
+const SOURCE_NTP: u16 = 6;
+
+// Peer status bits, with the selection code in the low three bits.
+const PEER_CONFIG: u16 = 0x80;
+const PEER_REACH: u16 = 0x10;
+
+// Pseudo addresses ntpq shows as GPS_NMEA(0) and LOCAL(0).
+const GPS_ADDR: Ipv4Addr = Ipv4Addr::new(127, 127, 20, 0);
+const RTC_ADDR: Ipv4Addr = Ipv4Addr::new(127, 127, 1, 0);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ControlError {
+    BadOp,
+    BadAssociation,
+}
+
+impl ControlError {
+    fn code(&self) -> u16 {
+        match self {
+            ControlError::BadOp => 3,
+            ControlError::BadAssociation => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ControlRequest {
+    pub version: u8,
+    pub opcode: u8,
+    pub sequence: u16,
+    pub association: u16,
+    pub data: Vec<u8>,
+}
+
+/// Everything a response is built from, collected once per request.
+pub struct Snapshot {
+    pub state: NtpServerState,
+    pub status: NtpServerStatus,
+    pub sources: Vec<NtpSourceReport>,
+    pub now: NtpTimestamp,
+    /// Local clock reading `now` was derived from.
+    pub local: NtpTimestamp,
+}
+
+pub fn is_control(buf: &[u8]) -> bool {
+    buf.first().is_some_and(|byte| byte & 0x7 == MODE_CONTROL)
+}
+
+impl ControlRequest {
+    pub fn parse(buf: &[u8]) -> io::Result<ControlRequest> {
+        if buf.len() < HEADER_LEN || !is_control(buf) {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a control message"));
+        }
+        if buf[1] & FLAG_RESPONSE != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Control response received"));
+        }
+        let count = BigEndian::read_u16(&buf[10..12]) as usize;
+        if HEADER_LEN + count > buf.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Control message truncated"));
+        }
+        Ok(ControlRequest {
+            version: (buf[0] >> 3) & 0x7,
+            opcode: buf[1] & 0x1f,
+            sequence: BigEndian::read_u16(&buf[2..4]),
+            association: BigEndian::read_u16(&buf[6..8]),
+            data: buf[HEADER_LEN..HEADER_LEN + count].to_vec(),
+        })
+    }
+
+    /// Names of the requested variables, empty when all are wanted.
+    pub fn variables(&self) -> Vec<String> {
+        String::from_utf8_lossy(&self.data)
+            .split(',')
+            .filter_map(|item| item.split('=').next())
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+}
+
+/// Builds the response to a request, split into fragments.
+pub fn respond(request: &ControlRequest, snapshot: &Snapshot) -> Vec<Vec<u8>> {
+    let leap = snapshot.state.leap;
+    let result = match request.opcode {
+        OP_READSTAT => read_status(request, snapshot),
+        OP_READVAR => read_variables(request, snapshot),
+        _ => Err(ControlError::BadOp),
+    };
+    match result {
+        Ok((status, data)) => fragments(request, leap, status, &data),
+        Err(e) => vec![encode(request, leap, FLAG_ERROR, e.code() << 8, 0, &[])],
+    }
+}
+
+fn read_status(request: &ControlRequest, snapshot: &Snapshot) -> Result<(u16, Vec<u8>), ControlError> {
+    if request.association != 0 {
+        let report = association(snapshot, request.association)?;
+        return Ok((peer_status(report), vec![]));
+    }
+    let mut data = vec![];
+    for (index, report) in snapshot.sources.iter().enumerate() {
+        data.extend_from_slice(&association_id(index).to_be_bytes());
+        data.extend_from_slice(&peer_status(report).to_be_bytes());
+    }
+    Ok((system_status(snapshot), data))
+}
+
+fn read_variables(request: &ControlRequest, snapshot: &Snapshot) -> Result<(u16, Vec<u8>), ControlError> {
+    let (status, variables) = if request.association == 0 {
+        (system_status(snapshot), system_variables(snapshot))
+    } else {
+        let report = association(snapshot, request.association)?;
+        (peer_status(report), peer_variables(report, &snapshot.now))
+    };
+
+    // Unknown names are skipped, ntpq asks for more than is tracked here.
+    let wanted = request.variables();
+    let text = variables
+        .iter()
+        .filter(|(name, _)| wanted.is_empty() || wanted.iter().any(|w| w == name))
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Ok((status, format!("{}\r\n", text).into_bytes()))
+}
+
+fn system_variables(snapshot: &Snapshot) -> Vec<(&'static str, String)> {
+    let state = &snapshot.state;
+    let status = &snapshot.status;
+    let peer = snapshot
+        .sources
+        .iter()
+        .position(|report| report.status == CandidateStatus::SystemPeer)
+        .map_or(0, association_id);
+    vec![
+        ("version", format!("\"{} {}\"", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+        ("leap", state.leap.to_string()),
+        ("stratum", state.stratum.to_string()),
+        ("precision", state.precision.to_string()),
+        ("rootdelay", millis(status.root_delay)),
+        ("rootdisp", millis(status.root_dispersion)),
+        ("refid", ref_id(state.ref_id, &status.source)),
+        ("reftime", hex_timestamp(&state.ref_ts)),
+        ("clock", hex_timestamp(&snapshot.now)),
+        ("peer", peer.to_string()),
+        ("offset", millis(status.offset)),
+        ("frequency", format!("{:.3}", state.clock.frequency() * 1e6)),
+        ("sys_jitter", millis(status.jitter)),
+    ]
+}
+
+fn peer_variables(report: &NtpSourceReport, now: &NtpTimestamp) -> Vec<(&'static str, String)> {
+    let (address, port) = match report.source {
+        NtpSource::Ntp { addr, .. } => (addr.ip().to_string(), addr.port()),
+        NtpSource::Gps => (GPS_ADDR.to_string(), 123),
+        _ => (RTC_ADDR.to_string(), 123),
+    };
+    let reach: u16 = if report.fresh {
+        (1 << report.samples.min(8)) - 1
+    } else {
+        0
+    };
+    let poll = report
+        .interval
+        .map_or(0, |interval| interval.as_secs_f64().max(1.0).log2().round() as i8);
+    let quality = &report.quality;
+
+    let mut variables = vec![
+        ("srcadr", address),
+        ("srcport", port.to_string()),
+        ("leap", report.source.leap().to_string()),
+        ("stratum", report.source.stratum().saturating_sub(1).to_string()),
+        ("rootdelay", millis(quality.root_delay)),
+        ("rootdisp", millis(quality.root_dispersion)),
+    ];
+    // The reference of an upstream server is not known.
+    if !matches!(report.source, NtpSource::Ntp { .. }) {
+        variables.push(("refid", ref_id(report.source.ref_id(), &report.source)));
+    }
+    variables.extend([
+        ("rec", hex_timestamp(&now.add_sec(-report.age.as_secs_f64()))),
+        ("reach", format!("{:o}", reach)),
+        ("hmode", String::from("3")),
+        ("pmode", String::from("4")),
+        ("hpoll", poll.to_string()),
+        ("ppoll", poll.to_string()),
+        ("offset", millis(report.offset)),
+        ("delay", millis(quality.delay)),
+        ("dispersion", millis(quality.dispersion)),
+        ("jitter", millis(quality.jitter)),
+    ]);
+    variables
+}
+
+fn association(snapshot: &Snapshot, id: u16) -> Result<&NtpSourceReport, ControlError> {
+    snapshot
+        .sources
+        .get((id as usize).wrapping_sub(1))
+        .ok_or(ControlError::BadAssociation)
+}
+
+// Sources are never forgotten, so their position is a stable ID.
+fn association_id(index: usize) -> u16 {
+    index as u16 + 1
+}
+
+fn system_status(snapshot: &Snapshot) -> u16 {
+    let source = match snapshot.status.source {
+        NtpSource::None => SOURCE_UNSPEC,
+        NtpSource::Gps => SOURCE_UHF,
+        NtpSource::Ntp { .. } => SOURCE_NTP,
+        NtpSource::Rtc => SOURCE_LOCAL,
+    };
+    (snapshot.state.leap as u16) << 14 | source << 8
+}
+
+fn peer_status(report: &NtpSourceReport) -> u16 {
+    let select = match report.status {
+        CandidateStatus::Rejected => 0,
+        CandidateStatus::Falseticker => 1,
+        CandidateStatus::Outlier => 3,
+        CandidateStatus::Survivor => 4,
+        CandidateStatus::SystemPeer => 6,
+    };
+    let mut status = PEER_CONFIG | select;
+    if report.fresh {
+        status |= PEER_REACH;
+    }
+    status << 8
+}
+
+fn ref_id(ref_id: u32, source: &NtpSource) -> String {
+    match source {
+        NtpSource::Ntp { .. } => Ipv4Addr::from(ref_id).to_string(),
+        _ => String::from_utf8_lossy(&ref_id.to_be_bytes()).trim_end_matches('\0').to_string(),
+    }
+}
+
+fn hex_timestamp(ts: &NtpTimestamp) -> String {
+    format!("0x{:08x}.{:08x}", ts.seconds(), ts.fraction())
+}
+
+fn millis(secs: f64) -> String {
+    format!("{:.3}", secs * 1e3)
+}
+
+fn fragments(request: &ControlRequest, leap: u8, status: u16, data: &[u8]) -> Vec<Vec<u8>> {
+    if data.is_empty() {
+        return vec![encode(request, leap, 0, status, 0, &[])];
+    }
+    data.chunks(MAX_DATA_LEN)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let offset = index * MAX_DATA_LEN;
+            let flags = if offset + chunk.len() < data.len() { FLAG_MORE } else { 0 };
+            encode(request, leap, flags, status, offset, chunk)
+        })
+        .collect()
+}
+
+fn encode(request: &ControlRequest, leap: u8, flags: u8, status: u16, offset: usize, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; HEADER_LEN];
+    buf[0] = leap << 6 | request.version << 3 | MODE_CONTROL;
+    buf[1] = FLAG_RESPONSE | flags | request.opcode;
+    BigEndian::write_u16(&mut buf[2..4], request.sequence);
+    BigEndian::write_u16(&mut buf[4..6], status);
+    BigEndian::write_u16(&mut buf[6..8], request.association);
+    BigEndian::write_u16(&mut buf[8..10], offset as u16);
+    BigEndian::write_u16(&mut buf[10..12], data.len() as u16);
+    buf.extend_from_slice(data);
+    buf.resize(buf.len().div_ceil(4) * 4, 0);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::ntp::{NtpClockModel, NtpFracValue, NtpSampleQuality, NtpSyncStatus};
+
+    fn request(opcode: u8, association: u16, data: &str) -> ControlRequest {
+        let mut buf = vec![0; HEADER_LEN];
+        buf[0] = 2 << 3 | MODE_CONTROL;
+        buf[1] = opcode;
+        BigEndian::write_u16(&mut buf[2..4], 7);
+        BigEndian::write_u16(&mut buf[6..8], association);
+        BigEndian::write_u16(&mut buf[10..12], data.len() as u16);
+        buf.extend_from_slice(data.as_bytes());
+        ControlRequest::parse(&buf).unwrap()
+    }
+
+    fn snapshot(sources: usize) -> Snapshot {
+        let upstream = |index: usize| NtpSource::Ntp {
+            addr: SocketAddr::from(([192, 0, 2, index as u8], 123)),
+            stratum: 1,
+            leap: 0,
+        };
+        Snapshot {
+            state: NtpServerState {
+                leap: 0,
+                stratum: 2,
+                precision: -20,
+                ref_id: 0xc000_0201,
+                ref_ts: NtpTimestamp::from_unix_secs(1_700_000_000),
+                dispersion: NtpFracValue::zero(),
+                delay: NtpFracValue::zero(),
+                clock: NtpClockModel::new(),
+                sync: NtpSyncStatus::Synchronized,
+                dispersion_rate: 0.0,
+            },
+            status: NtpServerStatus {
+                sync: NtpSyncStatus::Synchronized,
+                source: upstream(0),
+                stratum: 2,
+                leap: 0,
+                offset: 0.0,
+                jitter: 0.0,
+                root_delay: 0.0,
+                root_dispersion: 0.0,
+                holdover_secs: None,
+                holdover_error: None,
+                holdover_frequency_ppm: None,
+            },
+            sources: (0..sources)
+                .map(|index| NtpSourceReport {
+                    source: upstream(index),
+                    status: if index == 0 { CandidateStatus::SystemPeer } else { CandidateStatus::Survivor },
+                    offset: 0.0,
+                    quality: NtpSampleQuality::default(),
+                    age: Duration::from_secs(3),
+                    interval: Some(Duration::from_secs(64)),
+                    samples: 8,
+                    fresh: true,
+                })
+                .collect(),
+            now: NtpTimestamp::from_unix_secs(1_700_000_010),
+            local: NtpTimestamp::from_unix_secs(1_700_000_010),
+        }
+    }
+
+    fn data(response: &[u8]) -> &[u8] {
+        let count = BigEndian::read_u16(&response[10..12]) as usize;
+        &response[HEADER_LEN..HEADER_LEN + count]
+    }
+
+    #[test]
+    fn readstat_fragments() {
+        // Four bytes per association, 200 of them need two fragments.
+        let responses = respond(&request(OP_READSTAT, 0, ""), &snapshot(200));
+        assert_eq!(responses.len(), 2);
+
+        let (first, last) = (&responses[0], &responses[1]);
+        assert_eq!(first[1], FLAG_RESPONSE | FLAG_MORE | OP_READSTAT);
+        assert_eq!(last[1], FLAG_RESPONSE | OP_READSTAT);
+        assert_eq!(BigEndian::read_u16(&first[8..10]), 0);
+        assert_eq!(BigEndian::read_u16(&last[8..10]) as usize, MAX_DATA_LEN);
+        assert_eq!(data(first).len(), MAX_DATA_LEN);
+        assert_eq!(data(last).len(), 800 - MAX_DATA_LEN);
+        for response in &responses {
+            assert_eq!(response.len() % 4, 0);
+            assert_eq!(BigEndian::read_u16(&response[2..4]), 7);
+        }
+
+        let all = [data(first), data(last)].concat();
+        assert_eq!(BigEndian::read_u16(&all[0..2]), 1);
+        assert_eq!(all[2], (PEER_CONFIG | PEER_REACH | 6) as u8);
+        assert_eq!(BigEndian::read_u16(&all[796..798]), 200);
+        assert_eq!(all[798], (PEER_CONFIG | PEER_REACH | 4) as u8);
+    }
+
+    #[test]
+    fn short_response_is_one_fragment() {
+        let responses = respond(&request(OP_READSTAT, 0, ""), &snapshot(1));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0][1], FLAG_RESPONSE | OP_READSTAT);
+        assert_eq!(data(&responses[0]).len(), 4);
+    }
+
+    #[test]
+    fn readvar_filters_variables() {
+        let snapshot = snapshot(1);
+        let text = |association, names| {
+            let responses = respond(&request(OP_READVAR, association, names), &snapshot);
+            assert_eq!(responses.len(), 1);
+            String::from_utf8(data(&responses[0]).to_vec()).unwrap()
+        };
+
+        assert_eq!(text(0, "stratum, offset,bogus"), "stratum=2, offset=0.000\r\n");
+        assert_eq!(text(0, "leap=3"), "leap=0\r\n");
+        assert_eq!(text(1, "srcadr,stratum,reach"), "srcadr=192.0.2.0, stratum=1, reach=377\r\n");
+        assert_eq!(text(0, "bogus"), "\r\n");
+
+        let all = text(0, "");
+        assert!(all.starts_with("version="));
+        assert!(all.ends_with("sys_jitter=0.000\r\n"));
+        assert_eq!(all.matches('=').count(), 13);
+    }
+
+    #[test]
+    fn errors() {
+        let snapshot = snapshot(1);
+        let bad_op = respond(&request(9, 0, ""), &snapshot);
+        assert_eq!(bad_op[0][1], FLAG_RESPONSE | FLAG_ERROR | 9);
+        assert_eq!(BigEndian::read_u16(&bad_op[0][4..6]), ControlError::BadOp.code() << 8);
+
+        let bad_association = respond(&request(OP_READVAR, 2, ""), &snapshot);
+        assert_eq!(bad_association[0][1], FLAG_RESPONSE | FLAG_ERROR | OP_READVAR);
+        assert_eq!(BigEndian::read_u16(&bad_association[0][4..6]), ControlError::BadAssociation.code() << 8);
+    }
+
+    #[test]
+    fn parse_rejects() {
+        let mut buf = vec![0; HEADER_LEN];
+        buf[0] = 2 << 3 | MODE_CONTROL;
+        buf[11] = 4;
+        assert!(ControlRequest::parse(&buf).is_err());
+        buf.extend_from_slice(b"leap");
+        assert!(ControlRequest::parse(&buf).is_ok());
+        buf[1] = FLAG_RESPONSE;
+        assert!(ControlRequest::parse(&buf).is_err());
+        buf[0] = 2 << 3 | 3;
+        buf[1] = 0;
+        assert!(ControlRequest::parse(&buf).is_err());
+    }
+}
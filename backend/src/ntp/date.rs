@@ -0,0 +1,208 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{TimeZone, Utc};
+
+use super::timestamp::{fraction_to_nanos, nanos_to_fraction};
+use super::NtpTimestamp;
+
+// Seconds between the NTP (1900) and Unix (1970) epochs.
+pub(super) const UNIX_OFFSET: i64 = 2208988800;
+// Length of an NTP era, the range of the 32-bit timestamp seconds.
+const ERA_SECS: i64 = 1 << 32;
+
+/// NTP date format (RFC 5905 section 6): signed seconds since the prime
+/// epoch, 1900-01-01 00:00 UTC, plus a binary fraction in units of 2^-32 s.
+///
+/// A timestamp only holds the offset into its era; the date keeps the era
+/// too, so it stays unambiguous past the 2036 rollover.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    secs: i64,
+    fraction: u32,
+}
+
+impl DateTime {
+    pub const fn new(secs: i64, fraction: u32) -> DateTime {
+        DateTime { secs, fraction }
+    }
+
+    pub fn now() -> DateTime {
+        DateTime::from_system_time(SystemTime::now())
+    }
+
+    pub fn from_unix(secs: i64, fraction: u32) -> DateTime {
+        DateTime::new(secs + UNIX_OFFSET, fraction)
+    }
+
+    pub fn from_unix_nanos(secs: i64, nanos: u32) -> DateTime {
+        DateTime::from_unix(secs, nanos_to_fraction(nanos))
+    }
+
+    pub fn from_system_time(time: SystemTime) -> DateTime {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(dur) => DateTime::from_unix_nanos(dur.as_secs() as i64, dur.subsec_nanos()),
+            Err(err) => {
+                let dur = err.duration();
+                DateTime::from_unix(-(dur.as_secs() as i64), 0).add_sec(-(dur.subsec_nanos() as f64 / 1e9))
+            }
+        }
+    }
+
+    pub fn seconds(&self) -> i64 {
+        self.secs
+    }
+
+    /// Era number: 0 from 1900 until 2036-02-07 06:28:16 UTC, 1 after it,
+    /// negative before 1900.
+    pub fn era(&self) -> i32 {
+        self.secs.div_euclid(ERA_SECS) as i32
+    }
+
+    /// Seconds into the era, what the timestamp carries.
+    pub fn era_offset(&self) -> u32 {
+        self.secs.rem_euclid(ERA_SECS) as u32
+    }
+
+    /// The on-wire timestamp, which drops the era.
+    pub fn timestamp(&self) -> NtpTimestamp {
+        NtpTimestamp::new((self.era_offset() as u64) << 32 | self.fraction as u64)
+    }
+
+    pub fn unix_seconds(&self) -> i64 {
+        self.secs - UNIX_OFFSET
+    }
+
+    pub fn subsec_nanos(&self) -> u32 {
+        fraction_to_nanos(self.fraction)
+    }
+
+    pub fn to_unix_secs(self) -> f64 {
+        self.unix_seconds() as f64 + self.fraction as f64 / 4294967296.0
+    }
+
+    pub fn to_datetime(self) -> chrono::DateTime<Utc> {
+        Utc.timestamp_opt(self.unix_seconds(), self.subsec_nanos())
+            .single()
+            .unwrap_or_default()
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        let secs = self.unix_seconds();
+        let nanos = Duration::from_nanos(self.subsec_nanos() as u64);
+        if secs >= 0 {
+            UNIX_EPOCH + Duration::from_secs(secs as u64) + nanos
+        } else {
+            UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + nanos
+        }
+    }
+
+    /// Exact difference, however far apart the dates are.
+    pub fn diff_to_sec(&self, date: &DateTime) -> f64 {
+        (self.secs - date.secs) as f64 + (self.fraction as f64 - date.fraction as f64) / 4294967296.0
+    }
+
+    pub fn add_sec(&self, sec: f64) -> DateTime {
+        let total = ((self.secs as i128) << 32 | self.fraction as i128) + (sec * 4294967296.0) as i128;
+        DateTime::new((total >> 32) as i64, total as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2036-02-07T06:28:16Z, where era 1 begins.
+    const ROLLOVER_UNIX: i64 = ERA_SECS - UNIX_OFFSET;
+
+    fn date(rfc3339: &str) -> DateTime {
+        let datetime = chrono::DateTime::parse_from_rfc3339(rfc3339).unwrap();
+        DateTime::from_unix_nanos(datetime.timestamp(), datetime.timestamp_subsec_nanos())
+    }
+
+    #[test]
+    fn era_numbers() {
+        assert_eq!(date("1900-01-01T00:00:00Z"), DateTime::default());
+        assert_eq!((date("1899-12-31T23:59:59Z").era(), date("1899-12-31T23:59:59Z").era_offset()), (-1, u32::MAX));
+        assert_eq!(date("1970-01-01T00:00:00Z").era_offset(), UNIX_OFFSET as u32);
+
+        let last = date("2036-02-07T06:28:15Z");
+        let first = date("2036-02-07T06:28:16Z");
+        assert_eq!((last.era(), last.era_offset()), (0, u32::MAX));
+        assert_eq!((first.era(), first.era_offset()), (1, 0));
+        assert_eq!(first.unix_seconds(), ROLLOVER_UNIX);
+        assert_eq!((date("2172-03-15T12:56:32Z").era(), date("2172-03-15T12:56:32Z").era_offset()), (2, 0));
+    }
+
+    #[test]
+    fn timestamp_round_trip() {
+        for era in -2..4 {
+            for ts in [0, 1, 0x8000_0000_0000_0000, u64::MAX] {
+                let date = DateTime::new(era * ERA_SECS + (ts >> 32) as i64, ts as u32);
+                assert_eq!(date.era() as i64, era);
+                assert_eq!(date.timestamp(), NtpTimestamp::new(ts));
+            }
+        }
+    }
+
+    #[test]
+    fn timestamps_after_rollover() {
+        let date = date("2040-01-01T00:00:00.5Z");
+        let ts = NtpTimestamp::from_unix_nanos(date.unix_seconds(), 500_000_000);
+        assert_eq!(ts, date.timestamp());
+        assert!(ts.seconds() < 0x8000_0000);
+        assert_eq!(ts.date(), date);
+        assert_eq!(ts.unix_seconds(), date.unix_seconds());
+        assert_eq!(ts.to_datetime().to_rfc3339(), "2040-01-01T00:00:00.500+00:00");
+
+        let json = serde_json::to_value(ts).unwrap();
+        assert_eq!(json["era"], 1);
+        assert_eq!(json["utc"], "2040-01-01T00:00:00.500+00:00");
+        assert_eq!(json["unix"], 2208988800.5);
+    }
+
+    #[test]
+    fn rfc4330_window() {
+        // Timestamps map to 1968-01-20T03:14:08Z up to 2104-02-26T09:42:24Z.
+        assert_eq!(NtpTimestamp::new(0x8000_0000 << 32).date().to_datetime().to_rfc3339(), "1968-01-20T03:14:08+00:00");
+        assert_eq!(NtpTimestamp::new(0x7fff_ffff << 32).date().to_datetime().to_rfc3339(), "2104-02-26T09:42:23+00:00");
+        assert_eq!(NtpTimestamp::from_unix_secs(0).date(), date("1970-01-01T00:00:00Z"));
+        // Zero means unknown, not 2036.
+        assert_eq!(NtpTimestamp::zero().date(), DateTime::default());
+    }
+
+    #[test]
+    fn date_near_pivot() {
+        let ts = date("2036-02-07T06:28:20Z").timestamp();
+        assert_eq!(ts.date_near(&date("2100-01-01T00:00:00Z")), date("2036-02-07T06:28:20Z"));
+        assert_eq!(ts.date_near(&date("2000-01-01T00:00:00Z")), date("2036-02-07T06:28:20Z"));
+        assert_eq!(ts.date_near(&date("1950-01-01T00:00:00Z")), date("1900-01-01T00:00:04Z"));
+        assert_eq!(ts.date_near(&date("2200-01-01T00:00:00Z")), date("2172-03-15T12:56:36Z"));
+    }
+
+    #[test]
+    fn differences_across_rollover() {
+        let before = date("2036-02-07T06:28:15.75Z");
+        let after = date("2036-02-07T06:28:16.25Z");
+        assert_eq!(after.diff_to_sec(&before), 0.5);
+        assert_eq!(after.timestamp().diff_to_sec(&before.timestamp()), 0.5);
+        assert_eq!(before.timestamp().diff_to_sec(&after.timestamp()), -0.5);
+        assert_eq!(before.timestamp().add_sec(0.5), after.timestamp());
+        assert_eq!(before.add_sec(0.5), after);
+        assert_eq!(after.add_sec(-0.5), before);
+
+        // Beyond half an era only dates get it right.
+        let far = date("2100-01-01T00:00:00Z");
+        let old = date("1990-01-01T00:00:00Z");
+        assert_eq!(far.diff_to_sec(&old), 3471292800.0);
+        assert_eq!(old.diff_to_sec(&far), -3471292800.0);
+        assert_eq!(far.timestamp().date().diff_to_sec(&old.timestamp().date()), 3471292800.0);
+    }
+
+    #[test]
+    fn system_time() {
+        for rfc3339 in ["1969-12-31T23:59:59.25Z", "2026-10-18T12:00:00Z", "2050-06-01T00:00:00.125Z"] {
+            let date = date(rfc3339);
+            assert_eq!(DateTime::from_system_time(date.to_system_time()), date);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use tokio::sync::mpsc::{unbounded_channel,UnboundedReceiver,UnboundedSender};
 
-use super::NtpTimestamp;
+use super::{NtpSample, NtpTimestamp};
 
 
 
@@ -39,7 +39,8 @@ impl EventManager {
 #[derive(Debug, Clone)]
 pub enum EUdpEvents{
     NewPackets(Vec<u8>),
-    NewGPSTimestamp(NtpTimestamp),
-    NewRemoteTimestamp(NtpTimestamp),
+    /// Fix time and gpsd's estimated error (ept) in seconds.
+    NewGPSTimestamp(NtpTimestamp, Option<f64>),
+    NewRemoteTimestamp(NtpSample),
     NewGpsSky(u16),
 }
@@ -0,0 +1,255 @@
+use byteorder::{BigEndian, ByteOrder};
+
+use super::codec::ParseError;
+use super::NtpMac;
+
+// Smallest extension field, and smallest last field when no MAC follows so
+// that it cannot be mistaken for a MAC (RFC 7822 sections 3 and 7.5).
+const MIN_FIELD_LEN: usize = 16;
+pub const MIN_LAST_FIELD_LEN: usize = 28;
+
+/// Extension field types known to the server, anything else is kept as
+/// `Unknown` so it survives parsing and re-encoding.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FieldType {
+    UniqueIdentifier,
+    NtsCookie,
+    NtsCookiePlaceholder,
+    NtsAuthenticator,
+    Unknown(u16),
+}
+
+impl FieldType {
+    pub fn from_code(code: u16) -> FieldType {
+        match code {
+            0x0104 => FieldType::UniqueIdentifier,
+            0x0204 => FieldType::NtsCookie,
+            0x0304 => FieldType::NtsCookiePlaceholder,
+            0x0404 => FieldType::NtsAuthenticator,
+            code => FieldType::Unknown(code),
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            FieldType::UniqueIdentifier => 0x0104,
+            FieldType::NtsCookie => 0x0204,
+            FieldType::NtsCookiePlaceholder => 0x0304,
+            FieldType::NtsAuthenticator => 0x0404,
+            FieldType::Unknown(code) => *code,
+        }
+    }
+}
+
+/// NTPv4 extension field (RFC 7822). Parsed values keep their padding so a
+/// received packet re-encodes to the same bytes for MAC checks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtensionField {
+    pub field_type: FieldType,
+    pub value: Vec<u8>,
+}
+
+/// Body of an NTS Authenticator and Encrypted Extension Fields field
+/// (RFC 8915 section 5.6).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Authenticator {
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl ExtensionField {
+    pub fn new(field_type: FieldType, value: Vec<u8>) -> ExtensionField {
+        ExtensionField { field_type, value }
+    }
+
+    pub fn authenticator(authenticator: &Authenticator) -> ExtensionField {
+        let mut value = vec![];
+        value.extend_from_slice(&(authenticator.nonce.len() as u16).to_be_bytes());
+        value.extend_from_slice(&(authenticator.ciphertext.len() as u16).to_be_bytes());
+        value.extend_from_slice(&authenticator.nonce);
+        value.resize(padded(value.len()), 0);
+        value.extend_from_slice(&authenticator.ciphertext);
+        value.resize(padded(value.len()), 0);
+        ExtensionField::new(FieldType::NtsAuthenticator, value)
+    }
+
+    pub fn to_authenticator(&self) -> Option<Authenticator> {
+        if self.field_type != FieldType::NtsAuthenticator || self.value.len() < 4 {
+            return None;
+        }
+        let nonce_len = BigEndian::read_u16(&self.value[0..2]) as usize;
+        let ciphertext_len = BigEndian::read_u16(&self.value[2..4]) as usize;
+        let ciphertext_start = 4 + padded(nonce_len);
+        if self.value.len() < ciphertext_start + ciphertext_len {
+            return None;
+        }
+        Some(Authenticator {
+            nonce: self.value[4..4 + nonce_len].to_vec(),
+            ciphertext: self.value[ciphertext_start..ciphertext_start + ciphertext_len].to_vec(),
+        })
+    }
+
+    /// Encoded length with padding.
+    pub fn encoded_len(&self, min_len: usize) -> usize {
+        padded(4 + self.value.len()).max(min_len)
+    }
+
+    /// Reads one field from the start of `buf`, returns it with the number
+    /// of bytes it occupies including padding.
+    pub fn read(buf: &[u8]) -> Result<(ExtensionField, usize), ParseError> {
+        if buf.len() < MIN_FIELD_LEN {
+            return Err(ParseError::BadExtension);
+        }
+        let field_type = FieldType::from_code(BigEndian::read_u16(&buf[0..2]));
+        let len = BigEndian::read_u16(&buf[2..4]) as usize;
+        // Too short, unpadded, or longer than the packet.
+        if len < MIN_FIELD_LEN || !len.is_multiple_of(4) || len > buf.len() {
+            return Err(ParseError::BadExtension);
+        }
+        Ok((ExtensionField::new(field_type, buf[4..len].to_vec()), len))
+    }
+
+    /// Appends the field, zero padded to a multiple of four bytes and to at
+    /// least `min_len` bytes.
+    pub fn write(&self, buf: &mut Vec<u8>, min_len: usize) {
+        let len = self.encoded_len(min_len.max(MIN_FIELD_LEN));
+        buf.extend_from_slice(&self.field_type.code().to_be_bytes());
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+        buf.extend_from_slice(&self.value);
+        buf.resize(buf.len() + len - 4 - self.value.len(), 0);
+    }
+}
+
+/// Splits what follows the 48 byte header into extension fields and an
+/// optional MAC. A remainder of 4, 20 or 24 bytes is always a MAC, so a last
+/// field without MAC must be at least 28 bytes long. Before NTPv4 anything
+/// else than a MAC is an error.
+pub fn parse_trailer(mut buf: &[u8], version: u8) -> Result<(Vec<ExtensionField>, Option<NtpMac>), ParseError> {
+    let mut fields = vec![];
+    loop {
+        match buf.len() {
+            0 => {
+                if fields.last().is_some_and(|f: &ExtensionField| f.encoded_len(0) < MIN_LAST_FIELD_LEN) {
+                    return Err(ParseError::BadExtension);
+                }
+                return Ok((fields, None));
+            }
+            4 | 20 | 24 => {
+                let mac = NtpMac {
+                    key_id: BigEndian::read_u32(&buf[0..4]),
+                    digest: buf[4..].to_vec(),
+                };
+                return Ok((fields, Some(mac)));
+            }
+            _ if version < 4 => return Err(ParseError::BadMac),
+            _ => {
+                let (field, size) = ExtensionField::read(buf)?;
+                fields.push(field);
+                buf = &buf[size..];
+            }
+        }
+    }
+}
+
+/// Parses a run of extension fields, such as the plaintext of an NTS
+/// authenticator.
+pub fn parse_fields(mut buf: &[u8]) -> Result<Vec<ExtensionField>, ParseError> {
+    let mut fields = vec![];
+    while !buf.is_empty() {
+        let (field, size) = ExtensionField::read(buf)?;
+        fields.push(field);
+        buf = &buf[size..];
+    }
+    Ok(fields)
+}
+
+pub fn write_fields(fields: &[ExtensionField], buf: &mut Vec<u8>) {
+    for field in fields {
+        field.write(buf, MIN_FIELD_LEN);
+    }
+}
+
+fn padded(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(len: usize) -> ExtensionField {
+        ExtensionField::new(FieldType::UniqueIdentifier, vec![0xab; len])
+    }
+
+    #[test]
+    fn write_pads() {
+        let mut buf = vec![];
+        field(5).write(&mut buf, 0);
+        assert_eq!(buf.len(), MIN_FIELD_LEN);
+        assert_eq!(BigEndian::read_u16(&buf[2..4]) as usize, MIN_FIELD_LEN);
+
+        let mut buf = vec![];
+        field(13).write(&mut buf, 0);
+        assert_eq!(buf.len(), 20);
+        assert!(buf[17..].iter().all(|&b| b == 0));
+
+        let mut buf = vec![];
+        field(5).write(&mut buf, MIN_LAST_FIELD_LEN);
+        assert_eq!(buf.len(), MIN_LAST_FIELD_LEN);
+
+        let (read, size) = ExtensionField::read(&buf).unwrap();
+        assert_eq!(size, MIN_LAST_FIELD_LEN);
+        assert_eq!(read.value.len(), MIN_LAST_FIELD_LEN - 4);
+        assert_eq!(&read.value[..5], &field(5).value[..]);
+    }
+
+    #[test]
+    fn read_rejects_bad_lengths() {
+        let mut buf = vec![];
+        field(16).write(&mut buf, 0);
+        for len in [12, 18, 24] {
+            let mut bad = buf.clone();
+            bad[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            assert_eq!(ExtensionField::read(&bad), Err(ParseError::BadExtension));
+        }
+        assert_eq!(ExtensionField::read(&buf[..12]), Err(ParseError::BadExtension));
+    }
+
+    #[test]
+    fn short_last_field() {
+        let mut buf = vec![];
+        field(12).write(&mut buf, 0);
+        assert_eq!(buf.len(), 16);
+        assert_eq!(parse_trailer(&buf, 4), Err(ParseError::BadExtension));
+
+        let mut buf = vec![];
+        field(12).write(&mut buf, MIN_LAST_FIELD_LEN);
+        let (fields, mac) = parse_trailer(&buf, 4).unwrap();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(mac, None);
+
+        // A short field is fine when a MAC follows it.
+        let mut buf = vec![];
+        field(12).write(&mut buf, 0);
+        buf.extend_from_slice(&[0; 20]);
+        let (fields, mac) = parse_trailer(&buf, 4).unwrap();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(mac.unwrap().digest.len(), 16);
+    }
+
+    #[test]
+    fn fields_need_v4() {
+        let mut buf = vec![];
+        field(24).write(&mut buf, 0);
+        assert_eq!(parse_trailer(&buf, 3), Err(ParseError::BadMac));
+        assert!(parse_trailer(&buf, 4).is_ok());
+    }
+
+    #[test]
+    fn authenticator_round_trip() {
+        let authenticator = Authenticator { nonce: vec![1; 16], ciphertext: vec![2; 33] };
+        let field = ExtensionField::authenticator(&authenticator);
+        assert!(field.value.len().is_multiple_of(4));
+        assert_eq!(field.to_authenticator(), Some(authenticator));
+    }
+}
@@ -1,6 +1,9 @@
+use std::ops::Add;
+
 use byteorder::{BigEndian, ByteOrder};
 
-#[derive(Debug, Copy, Clone)]
+/// NTP short format: unsigned 16.16 fixed-point seconds.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct FracValue {
     val: u32,
 }
@@ -18,7 +21,54 @@ impl FracValue {
         FracValue{val: 0}
     }
 
-    pub fn increment(&mut self) {
-        self.val += 1;
+    /// Converts seconds, saturating at 0 and at the 65536 s format limit.
+    pub fn from_secs(secs: f64) -> FracValue {
+        let val = (secs * 65536.0).round();
+        if val.is_nan() || val <= 0.0 {
+            FracValue::zero()
+        } else if val >= u32::MAX as f64 {
+            FracValue{val: u32::MAX}
+        } else {
+            FracValue{val: val as u32}
+        }
+    }
+
+    pub fn to_secs(self) -> f64 {
+        self.val as f64 / 65536.0
+    }
+}
+
+impl Add for FracValue {
+    type Output = FracValue;
+
+    fn add(self, other: FracValue) -> FracValue {
+        FracValue{val: self.val.saturating_add(other.val)}
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for secs in [0.0, 1.0 / 65536.0, 0.125, 1.5, 3600.25] {
+            assert_eq!(FracValue::from_secs(secs).to_secs(), secs);
+        }
+        let value = FracValue::from_secs(0.0123);
+        assert!((value.to_secs() - 0.0123).abs() <= 0.5 / 65536.0);
+
+        let mut buf = [0u8; 4];
+        value.write(&mut buf);
+        assert_eq!(FracValue::read(&buf), value);
+    }
+
+    #[test]
+    fn saturation() {
+        assert_eq!(FracValue::from_secs(-1.0), FracValue::zero());
+        assert_eq!(FracValue::from_secs(f64::NAN), FracValue::zero());
+        assert_eq!(FracValue::from_secs(1e9).to_secs(), u32::MAX as f64 / 65536.0);
+        assert_eq!(FracValue::from_secs(f64::INFINITY), FracValue::from_secs(1e9));
+        assert_eq!(FracValue::from_secs(60000.0) + FracValue::from_secs(60000.0), FracValue::from_secs(1e9));
+    }
+}
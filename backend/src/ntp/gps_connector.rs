@@ -5,7 +5,6 @@ use super::events::EUdpEvents;
 use super::events::Event;
 use super::events::EventManager;
 use super::NtpTimestamp;
-use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 use gpsd_proto::UnifiedResponse;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
@@ -66,14 +65,9 @@ impl ConnectorGPS {
                             UnifiedResponse::Tpv(t) => {
                                 let time = t.time;
                                 if let Some(time) = time {
-                                    let datetime = DateTime::parse_from_rfc3339(&time);
-                                    if let Ok(datetime) = datetime {
+                                    if let Some(timestamp) = NtpTimestamp::from_rfc3339(&time) {
                                         event_manager.lock().await.notify(Event {
-                                            event_type: EUdpEvents::NewGPSTimestamp(
-                                                NtpTimestamp::new(
-                                                    datetime.timestamp().try_into().unwrap(),
-                                                ),
-                                            ),
+                                            event_type: EUdpEvents::NewGPSTimestamp(timestamp, t.ept.map(f64::from)),
                                         });
                                     }
                                 }
@@ -0,0 +1,121 @@
+use super::source::PHI;
+
+// Weight of a new frequency estimate in the long-term average.
+const LEARN_GAIN: f64 = 1.0 / 16.0;
+// Frequency estimates needed before the learned value is trusted.
+const MIN_LEARNED: u32 = 16;
+
+/// Learns the oscillator frequency error while GPS is locked and bounds the
+/// time error accumulated after it is lost.
+#[derive(Debug, Copy, Clone)]
+pub struct Holdover {
+    frequency: f64,
+    stability: f64,
+    learned: u32,
+    drift_rate: f64,
+    error_threshold: f64,
+}
+
+impl Holdover {
+    /// `drift_rate` (s/s) is how fast the error bound grows on top of the
+    /// learned frequency stability, `error_threshold` (s) is the bound after
+    /// which the advertised stratum is degraded.
+    pub fn new(drift_rate: f64, error_threshold: f64) -> Holdover {
+        Holdover {
+            frequency: 0.0,
+            stability: 0.0,
+            learned: 0,
+            drift_rate,
+            error_threshold,
+        }
+    }
+
+    /// Feeds the frequency estimate of the clock model while locked.
+    pub fn learn(&mut self, frequency: f64) {
+        if self.learned == 0 {
+            self.frequency = frequency;
+        } else {
+            let error = frequency - self.frequency;
+            let variance = self.stability.powi(2);
+            self.frequency += LEARN_GAIN * error;
+            self.stability = (variance + LEARN_GAIN * (error.powi(2) - variance)).sqrt();
+        }
+        self.learned = self.learned.saturating_add(1);
+    }
+
+    /// Learned frequency to extrapolate with, once enough was learned.
+    pub fn frequency(&self) -> Option<f64> {
+        if self.learned >= MIN_LEARNED {
+            Some(self.frequency)
+        } else {
+            None
+        }
+    }
+
+    /// Rate at which the root dispersion grows while in holdover; without a
+    /// learned frequency the clock is no better than the PHI tolerance.
+    pub fn dispersion_rate(&self) -> f64 {
+        if self.frequency().is_some() {
+            self.drift_rate + self.stability
+        } else {
+            PHI
+        }
+    }
+
+    /// Estimated time error after `secs` seconds in holdover.
+    pub fn estimated_error(&self, secs: f64) -> f64 {
+        secs * self.dispersion_rate()
+    }
+
+    pub fn is_degraded(&self, secs: f64) -> bool {
+        self.estimated_error(secs) > self.error_threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn learns_frequency() {
+        let mut holdover = Holdover::new(1e-7, 1e-3);
+        for _ in 0..MIN_LEARNED - 1 {
+            holdover.learn(5e-6);
+        }
+        assert_eq!(holdover.frequency(), None);
+        assert_eq!(holdover.dispersion_rate(), PHI);
+
+        holdover.learn(5e-6);
+        assert_eq!(holdover.frequency(), Some(5e-6));
+        // A perfectly stable oscillator only drifts at the configured rate.
+        assert_eq!(holdover.dispersion_rate(), 1e-7);
+
+        // Estimates converge on a new frequency and the spread shows up as
+        // instability.
+        for _ in 0..200 {
+            holdover.learn(6e-6);
+        }
+        assert!((holdover.frequency().unwrap() - 6e-6).abs() < 1e-10);
+        let mut noisy = holdover;
+        for step in 0..200 {
+            noisy.learn(if step % 2 == 0 { 5e-6 } else { 7e-6 });
+        }
+        assert!((noisy.frequency().unwrap() - 6e-6).abs() < 1e-7);
+        assert!(noisy.dispersion_rate() > 1e-7 + 5e-7);
+    }
+
+    #[test]
+    fn error_bound_grows() {
+        let mut holdover = Holdover::new(1e-6, 1e-3);
+        // Without a learned frequency the bound grows at PHI, 15 ppm.
+        assert!((holdover.estimated_error(10.0) - 10.0 * PHI).abs() < 1e-12);
+        assert!(holdover.is_degraded(100.0));
+
+        for _ in 0..MIN_LEARNED {
+            holdover.learn(0.0);
+        }
+        assert!((holdover.estimated_error(500.0) - 5e-4).abs() < 1e-12);
+        assert!(!holdover.is_degraded(999.0));
+        assert!(holdover.is_degraded(1001.0));
+    }
+}
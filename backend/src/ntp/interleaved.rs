@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use super::shard::{Sharded, SHARDS};
+use super::{NtpPacket, NtpTimestamp};
+
+// Clients whose last transmit timestamp is remembered.
+const LOG_SIZE: usize = 4096;
+
+#[derive(Debug, Copy, Clone)]
+struct Transmission {
+    rx_ts: NtpTimestamp,
+    tx_ts: NtpTimestamp,
+}
+
+/// Actual transmit times of the last response to each client, for the
+/// interleaved client/server mode (chrony and ntpd `xleave`).
+///
+/// A client asks for an interleaved response by echoing the receive
+/// timestamp of our previous response in its origin timestamp; the reply
+/// then carries the transmit time of that previous response, taken after it
+/// was sent rather than before it was serialized.
+pub struct TimestampLog {
+    enable: bool,
+    entries: Sharded<HashMap<IpAddr, Transmission>>,
+}
+
+impl TimestampLog {
+    pub fn new(enable: bool) -> TimestampLog {
+        TimestampLog {
+            enable,
+            entries: Sharded::new(HashMap::new),
+        }
+    }
+
+    /// Transmit time of the previous response when `request` is an
+    /// interleaved request matching it.
+    pub fn previous_transmit(&self, request: &NtpPacket) -> Option<NtpTimestamp> {
+        if !self.enable || request.orig_ts == NtpTimestamp::zero() || request.orig_ts == request.tx_ts {
+            return None;
+        }
+        let addr = request.remote_addr.ip();
+        let entry = *self.entries.get(&addr).get(&addr)?;
+        (entry.rx_ts == request.orig_ts).then_some(entry.tx_ts)
+    }
+
+    /// Remembers when a response carrying `rx_ts` actually left.
+    pub fn record(&self, addr: IpAddr, rx_ts: NtpTimestamp, tx_ts: NtpTimestamp) {
+        if !self.enable {
+            return;
+        }
+        let mut entries = self.entries.get(&addr);
+        if entries.len() >= LOG_SIZE / SHARDS && !entries.contains_key(&addr) {
+            // Any entry will do, evicted clients just get a basic response.
+            if let Some(evicted) = entries.keys().next().copied() {
+                entries.remove(&evicted);
+            }
+        }
+        entries.insert(addr, Transmission { rx_ts, tx_ts });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn ts(secs: u64) -> NtpTimestamp {
+        NtpTimestamp::from_unix_secs(1_700_000_000 + secs)
+    }
+
+    fn request(client: u8, orig_ts: NtpTimestamp, tx_ts: NtpTimestamp) -> NtpPacket {
+        let mut buf = [0u8; 48];
+        buf[0] = 0x23;
+        let addr = SocketAddr::from(([192, 0, 2, client], 123));
+        let mut packet = NtpPacket::decode(&buf, addr, NtpTimestamp::zero()).unwrap();
+        packet.orig_ts = orig_ts;
+        packet.tx_ts = tx_ts;
+        packet
+    }
+
+    #[test]
+    fn previous_transmit_lookup() {
+        let log = TimestampLog::new(true);
+        let client = IpAddr::from([192, 0, 2, 1]);
+        log.record(client, ts(1), ts(2));
+
+        assert_eq!(log.previous_transmit(&request(1, ts(1), ts(5))), Some(ts(2)));
+        // Not an echo of our last receive timestamp.
+        assert_eq!(log.previous_transmit(&request(1, ts(3), ts(5))), None);
+        // Basic mode requests.
+        assert_eq!(log.previous_transmit(&request(1, NtpTimestamp::zero(), ts(5))), None);
+        assert_eq!(log.previous_transmit(&request(1, ts(1), ts(1))), None);
+        // Another client.
+        assert_eq!(log.previous_transmit(&request(2, ts(1), ts(5))), None);
+
+        // Only the latest response counts.
+        log.record(client, ts(6), ts(7));
+        assert_eq!(log.previous_transmit(&request(1, ts(1), ts(8))), None);
+        assert_eq!(log.previous_transmit(&request(1, ts(6), ts(8))), Some(ts(7)));
+    }
+
+    #[test]
+    fn disabled_log_is_empty() {
+        let log = TimestampLog::new(false);
+        log.record(IpAddr::from([192, 0, 2, 1]), ts(1), ts(2));
+        assert_eq!(log.previous_transmit(&request(1, ts(1), ts(5))), None);
+    }
+
+    #[test]
+    fn size_is_bounded() {
+        let log = TimestampLog::new(true);
+        for index in 0..2 * LOG_SIZE as u32 {
+            log.record(IpAddr::from((0xc000_0000 + index).to_be_bytes()), ts(1), ts(2));
+        }
+        let mut total = 0;
+        log.entries.for_each(|entries| {
+            assert!(entries.len() <= LOG_SIZE / SHARDS);
+            total += entries.len();
+        });
+        assert!(total > LOG_SIZE / 2);
+
+        // The latest client is always remembered.
+        let latest = IpAddr::from((0xc000_0000 + 2 * LOG_SIZE as u32 - 1).to_be_bytes());
+        assert!(log.entries.get(&latest).contains_key(&latest));
+    }
+}
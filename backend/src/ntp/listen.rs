@@ -0,0 +1,202 @@
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+
+use net2::{TcpBuilder, UdpBuilder};
+#[cfg(unix)]
+use net2::unix::UnixUdpBuilderExt;
+use serde::Serialize;
+use tokio::net::{TcpListener, UdpSocket};
+use utoipa::ToSchema;
+
+use super::timestamping::TimestampMode;
+
+/// Local addresses the server answers on. Entries are IP addresses or
+/// interface names, an interface stands for all of its addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenConfig {
+    pub addresses: Vec<String>,
+    pub port: u16,
+    /// Sockets bound to each address, the kernel spreads clients over them.
+    /// 0 binds one per CPU.
+    pub workers: usize,
+}
+
+impl ListenConfig {
+    /// Sockets bound to each address, with 0 resolved to the CPU count.
+    pub fn workers(&self) -> usize {
+        match self.workers {
+            0 => std::thread::available_parallelism().map_or(1, |count| count.get()),
+            workers => workers,
+        }
+    }
+}
+
+/// Result of binding one local address, reported by the REST API.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct BindStatus {
+    /// Configured entry the address was derived from.
+    pub entry: String,
+    /// Bound socket address, empty when the entry did not resolve.
+    pub address: String,
+    pub error: Option<String>,
+    /// Packet timestamping of NTP sockets.
+    pub timestamping: Option<TimestampMode>,
+}
+
+impl BindStatus {
+    pub fn bound(entry: &str, addr: SocketAddr, timestamping: Option<TimestampMode>) -> BindStatus {
+        BindStatus {
+            entry: entry.to_string(),
+            address: addr.to_string(),
+            error: None,
+            timestamping,
+        }
+    }
+
+    pub fn failed(entry: &str, addr: Option<SocketAddr>, error: &Error) -> BindStatus {
+        BindStatus {
+            entry: entry.to_string(),
+            address: addr.map(|addr| addr.to_string()).unwrap_or_default(),
+            error: Some(error.to_string()),
+            timestamping: None,
+        }
+    }
+}
+
+/// Expands the configured entries into socket addresses, keeping the entry
+/// each one came from. Duplicates are bound only once.
+pub fn resolve(config: &ListenConfig) -> Vec<(String, io::Result<SocketAddr>)> {
+    let mut interfaces = None;
+    let mut resolved: Vec<(String, io::Result<SocketAddr>)> = vec![];
+
+    for entry in &config.addresses {
+        let entry = entry.trim();
+        if let Ok(ip) = entry.parse::<IpAddr>() {
+            push_unique(&mut resolved, entry, SocketAddr::new(ip, config.port));
+            continue;
+        }
+
+        let interfaces = match interfaces.get_or_insert_with(if_addrs::get_if_addrs) {
+            Ok(interfaces) => interfaces,
+            Err(e) => {
+                resolved.push((entry.to_string(), Err(Error::new(e.kind(), e.to_string()))));
+                continue;
+            }
+        };
+        let mut found = false;
+        for interface in interfaces.iter().filter(|interface| interface.name == entry) {
+            found = true;
+            let addr = match interface.ip() {
+                // Link-local addresses are only unique together with their scope.
+                IpAddr::V6(ip) if interface.is_link_local() => SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    config.port,
+                    0,
+                    interface.index.unwrap_or(0),
+                )),
+                ip => SocketAddr::new(ip, config.port),
+            };
+            push_unique(&mut resolved, entry, addr);
+        }
+        if !found {
+            let error = Error::new(ErrorKind::NotFound, "No such address or interface");
+            resolved.push((entry.to_string(), Err(error)));
+        }
+    }
+    resolved
+}
+
+fn push_unique(resolved: &mut Vec<(String, io::Result<SocketAddr>)>, entry: &str, addr: SocketAddr) {
+    if !resolved.iter().any(|(_, result)| matches!(result, Ok(a) if *a == addr)) {
+        resolved.push((entry.to_string(), Ok(addr)));
+    }
+}
+
+/// Binds a UDP socket. IPv6 sockets are IPv6 only, so that `::` and
+/// `0.0.0.0` can be listed side by side, and address reuse lets specific
+/// addresses coexist with the wildcard ones. With `shared` further sockets
+/// can bind the same address (`SO_REUSEPORT`) and split its traffic.
+pub fn bind(addr: SocketAddr, shared: bool) -> io::Result<UdpSocket> {
+    let builder = match addr {
+        SocketAddr::V4(_) => UdpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = UdpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    builder.reuse_address(true)?;
+    #[cfg(unix)]
+    if shared {
+        builder.reuse_port(true)?;
+    }
+    #[cfg(not(unix))]
+    if shared {
+        return Err(Error::new(ErrorKind::Unsupported, "Sharing addresses needs SO_REUSEPORT"));
+    }
+    let socket = builder.bind(addr)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// Binds a TCP listener, IPv6 only like the UDP sockets.
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    builder.reuse_address(true)?;
+    builder.bind(addr)?;
+    let listener = builder.listen(128)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(addresses: &[&str], workers: usize) -> ListenConfig {
+        ListenConfig {
+            addresses: addresses.iter().map(|address| address.to_string()).collect(),
+            port: 123,
+            workers,
+        }
+    }
+
+    fn addresses(config: &ListenConfig) -> Vec<(String, Option<SocketAddr>)> {
+        resolve(config).into_iter().map(|(entry, result)| (entry, result.ok())).collect()
+    }
+
+    #[test]
+    fn resolves_entries() {
+        let resolved = addresses(&config(&["0.0.0.0", " ::1 ", "0.0.0.0", "no-such-interface"], 1));
+        assert_eq!(
+            resolved,
+            [
+                (String::from("0.0.0.0"), Some(SocketAddr::from(([0, 0, 0, 0], 123)))),
+                (String::from("::1"), Some("[::1]:123".parse().unwrap())),
+                (String::from("no-such-interface"), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn follows_address_list() {
+        let before = addresses(&config(&["127.0.0.1", "127.0.0.2"], 1));
+        let after = addresses(&config(&["127.0.0.2", "127.0.0.3"], 1));
+        assert_eq!(before[1], after[0]);
+        assert!(!after.contains(&before[0]));
+        assert_eq!(after[1].1, Some(SocketAddr::from(([127, 0, 0, 3], 123))));
+    }
+
+    #[test]
+    fn workers_default_to_cpus() {
+        assert_eq!(config(&[], 3).workers(), 3);
+        assert!(config(&[], 0).workers() >= 1);
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+#[cfg(test)]
+use tokio::sync::watch;
+
+use super::NtpTimestamp;
+
+/// Local time as the NTP core sees it: the system clock in production, a
+/// shifted or simulated one in tests. Everything that reads the time,
+/// measures an age or waits goes through it.
+#[async_trait]
+pub trait Clock: Send + Sync {
+    /// Current reading of the local clock.
+    fn now(&self) -> NtpTimestamp;
+
+    /// Monotonic time for timeouts and rate limits.
+    fn instant(&self) -> Instant;
+
+    /// Waits until `duration` has passed on this clock.
+    async fn sleep(&self, duration: Duration);
+
+    /// Maps a timestamp the kernel took with the system clock onto this
+    /// clock, keeping its age.
+    fn system_to_local(&self, ts: NtpTimestamp) -> NtpTimestamp {
+        self.now().add_sec(ts.diff_to_sec(&NtpTimestamp::now()))
+    }
+}
+
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> NtpTimestamp {
+        NtpTimestamp::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    fn system_to_local(&self, ts: NtpTimestamp) -> NtpTimestamp {
+        ts
+    }
+}
+
+/// System clock shifted by a fixed number of seconds, to run a server or
+/// client that is off next to one that is not.
+#[cfg(test)]
+pub struct OffsetClock {
+    offset: f64,
+}
+
+#[cfg(test)]
+impl OffsetClock {
+    pub fn new(offset: f64) -> OffsetClock {
+        OffsetClock { offset }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Clock for OffsetClock {
+    fn now(&self) -> NtpTimestamp {
+        NtpTimestamp::now().add_sec(self.offset)
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    fn system_to_local(&self, ts: NtpTimestamp) -> NtpTimestamp {
+        ts.add_sec(self.offset)
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Copy, Clone)]
+struct Reading {
+    /// Monotonic time since the clock was created.
+    elapsed: Duration,
+    /// Local time since the clock was created, off by the drift.
+    local: f64,
+    drift: f64,
+}
+
+/// Simulated clock that only moves when advanced. Sleepers wake once it
+/// passes their deadline, so hours go by as fast as the tasks can run.
+#[cfg(test)]
+pub struct ManualClock {
+    start: NtpTimestamp,
+    base: Instant,
+    reading: watch::Sender<Reading>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(start: NtpTimestamp) -> ManualClock {
+        let (reading, _) = watch::channel(Reading {
+            elapsed: Duration::ZERO,
+            local: 0.0,
+            drift: 0.0,
+        });
+        ManualClock {
+            start,
+            base: Instant::now(),
+            reading,
+        }
+    }
+
+    /// Lets the local clock run fast (positive) or slow by `drift` s/s
+    /// from now on.
+    pub fn set_drift(&self, drift: f64) {
+        self.reading.send_modify(|reading| reading.drift = drift);
+    }
+
+    /// Steps the local clock without moving monotonic time.
+    pub fn step(&self, secs: f64) {
+        self.reading.send_modify(|reading| reading.local += secs);
+    }
+
+    /// Moves time forward, waking the sleepers whose deadline passed.
+    pub fn advance(&self, duration: Duration) {
+        self.reading.send_modify(|reading| {
+            reading.elapsed += duration;
+            reading.local += duration.as_secs_f64() * (1.0 + reading.drift);
+        });
+    }
+
+    /// Monotonic time since the clock was created; true time when the
+    /// local clock drifts.
+    pub fn elapsed(&self) -> Duration {
+        self.reading.borrow().elapsed
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Clock for ManualClock {
+    fn now(&self) -> NtpTimestamp {
+        self.start.add_sec(self.reading.borrow().local)
+    }
+
+    fn instant(&self) -> Instant {
+        self.base + self.elapsed()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        let mut reading = self.reading.subscribe();
+        let deadline = reading.borrow().elapsed + duration;
+        while reading.borrow_and_update().elapsed < deadline {
+            // The sender lives as long as the clock.
+            let _ = reading.changed().await;
+        }
+    }
+}
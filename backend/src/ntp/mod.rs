@@ -1,13 +1,55 @@
 mod timestamp;
 pub use timestamp::Timestamp as NtpTimestamp;
+mod date;
+pub use date::DateTime as NtpDateTime;
+mod clock;
+pub use clock::ClockModel as NtpClockModel;
 mod frac_value;
 pub use frac_value::FracValue as NtpFracValue;
+pub mod auth;
+pub use auth::Auth as NtpAuth;
+pub use auth::Key as NtpKey;
+pub use auth::Mac as NtpMac;
+pub mod extension;
+pub mod codec;
+pub use extension::ExtensionField as NtpExtensionField;
+pub mod nts;
 mod packet;
 pub use packet::Packet as NtpPacket;
+mod source;
+pub use source::Source as NtpSource;
+pub use source::Sources as NtpSources;
+pub use source::SampleQuality as NtpSampleQuality;
+pub use source::Sample as NtpSample;
+pub use source::SourcePriority as NtpSourcePriority;
+pub use source::SourceReport as NtpSourceReport;
+pub mod selection;
+mod holdover;
+pub use holdover::Holdover as NtpHoldover;
+mod sync;
+pub use sync::SyncMachine as NtpSyncMachine;
+pub use sync::SyncStatus as NtpSyncStatus;
 mod server_state;
-pub use server_state::ServerState as NtpServerState; 
+pub use server_state::ServerState as NtpServerState;
+pub use server_state::ServerStatus as NtpServerStatus;
+pub use server_state::ServerConfig as NtpServerConfig;
+pub use server_state::StateCell as NtpStateCell;
+pub mod acl;
+pub mod rate_limit;
+pub mod listen;
+pub mod broadcast;
+pub mod control;
+pub mod cmdmon;
+pub mod interleaved;
+mod shard;
+pub mod timestamping;
+pub mod local_clock;
+#[cfg(test)]
+mod simulation;
 mod  server;
 pub use server::Server as NtpServer;
+pub mod peer;
+pub use peer::Peer as NtpPeer;
 mod client;
 pub use client::Client as NtpClient;
 pub mod events;
@@ -0,0 +1,768 @@
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use aes_siv::siv::Aes128Siv;
+use aes_siv::KeyInit;
+use byteorder::{BigEndian, ByteOrder};
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio_rustls::TlsAcceptor;
+
+use super::auth::decode_hex;
+use super::extension::{self, Authenticator, FieldType};
+use super::listen::{self, ListenConfig};
+use super::{NtpExtensionField, NtpPacket};
+
+// NTS-KE record types (RFC 8915 section 4).
+const RECORD_END: u16 = 0;
+const RECORD_NEXT_PROTOCOL: u16 = 1;
+const RECORD_ERROR: u16 = 2;
+const RECORD_WARNING: u16 = 3;
+const RECORD_AEAD: u16 = 4;
+const RECORD_NEW_COOKIE: u16 = 5;
+const RECORD_SERVER: u16 = 6;
+const RECORD_PORT: u16 = 7;
+const CRITICAL: u16 = 0x8000;
+
+const ERROR_UNRECOGNIZED_CRITICAL: u16 = 0;
+const ERROR_BAD_REQUEST: u16 = 1;
+
+const PROTOCOL_NTPV4: u16 = 0;
+const AEAD_AES_SIV_CMAC_256: u16 = 15;
+const ALPN: &[u8] = b"ntske/1";
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+const MIN_UNIQUE_ID: usize = 32;
+const MAX_COOKIES: usize = 8;
+// Master keys kept around so cookies survive a couple of rotations.
+const KEPT_KEYS: usize = 3;
+const KE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_KE_REQUEST: usize = 16 * 1024;
+// Pause after a failed accept, e.g. when out of file descriptors.
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// Client-to-server and server-to-client keys of one NTS association.
+#[derive(Clone)]
+pub struct NtsKeys {
+    c2s: [u8; KEY_LEN],
+    s2c: [u8; KEY_LEN],
+}
+
+/// Server secret that cookies are sealed with.
+#[derive(Clone)]
+pub struct MasterKey {
+    pub id: u32,
+    pub key: [u8; KEY_LEN],
+    /// Unix time of creation, in seconds.
+    pub created: i64,
+}
+
+impl MasterKey {
+    fn generate(id: u32, created: i64) -> MasterKey {
+        let mut key = [0; KEY_LEN];
+        rand::thread_rng().fill(&mut key);
+        MasterKey { id, key, created }
+    }
+
+    pub fn from_hex(id: u32, hex: &str, created: i64) -> Option<MasterKey> {
+        let key = decode_hex(hex)?.try_into().ok()?;
+        Some(MasterKey { id, key, created })
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.key.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Context of a verified NTS request needed to seal the response.
+pub struct NtsRequest {
+    keys: NtsKeys,
+    unique_id: Vec<u8>,
+    cookies: usize,
+}
+
+pub enum NtsStatus {
+    /// Not an NTS request.
+    None,
+    Valid(NtsRequest),
+    /// Bad cookie or authenticator, answered with an NTSN kiss carrying the
+    /// unique identifier.
+    Invalid(Vec<u8>),
+}
+
+/// Issues and opens cookies with rotating master keys.
+pub struct CookieJar {
+    keys: Vec<MasterKey>,
+    rotation: i64,
+}
+
+impl CookieJar {
+    /// `keys` are the persisted master keys, oldest first.
+    pub fn new(keys: Vec<MasterKey>, rotation: Duration) -> CookieJar {
+        CookieJar {
+            keys,
+            rotation: rotation.as_secs() as i64,
+        }
+    }
+
+    pub fn keys(&self) -> &[MasterKey] {
+        &self.keys
+    }
+
+    /// Adds a new master key when the newest one is older than the rotation
+    /// period, returns true when the keys changed and should be persisted.
+    pub fn rotate_if_due(&mut self, now: i64) -> bool {
+        let id = match self.keys.last() {
+            Some(newest) if now - newest.created < self.rotation => return false,
+            Some(newest) => newest.id.wrapping_add(1),
+            None => rand::thread_rng().gen(),
+        };
+        self.keys.push(MasterKey::generate(id, now));
+        if self.keys.len() > KEPT_KEYS {
+            self.keys.drain(..self.keys.len() - KEPT_KEYS);
+        }
+        info!("Rotated NTS cookie key, now using key {}", id);
+        true
+    }
+
+    /// Cookie layout: key ID, nonce, then both session keys sealed with the
+    /// master key. Only AES-SIV-CMAC-256 is negotiated so the AEAD ID is
+    /// implied, which keeps cookies a multiple of four bytes long.
+    pub fn make_cookie(&self, keys: &NtsKeys) -> Vec<u8> {
+        let master = self.keys.last().expect("cookie jar has no key");
+        let mut plaintext = Vec::with_capacity(2 * KEY_LEN);
+        plaintext.extend_from_slice(&keys.c2s);
+        plaintext.extend_from_slice(&keys.s2c);
+
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        let mut cipher = Aes128Siv::new_from_slice(&master.key).unwrap();
+        let sealed = cipher.encrypt([&nonce[..]], &plaintext).unwrap();
+
+        let mut cookie = master.id.to_be_bytes().to_vec();
+        cookie.extend_from_slice(&nonce);
+        cookie.extend_from_slice(&sealed);
+        cookie
+    }
+
+    pub fn open_cookie(&self, cookie: &[u8]) -> Option<NtsKeys> {
+        if cookie.len() < 4 + NONCE_LEN {
+            return None;
+        }
+        let id = BigEndian::read_u32(&cookie[0..4]);
+        let master = self.keys.iter().find(|key| key.id == id)?;
+        let nonce = &cookie[4..4 + NONCE_LEN];
+        let mut cipher = Aes128Siv::new_from_slice(&master.key).unwrap();
+        let plaintext = cipher.decrypt([nonce], &cookie[4 + NONCE_LEN..]).ok()?;
+        if plaintext.len() != 2 * KEY_LEN {
+            return None;
+        }
+        Some(NtsKeys {
+            c2s: plaintext[..KEY_LEN].try_into().unwrap(),
+            s2c: plaintext[KEY_LEN..].try_into().unwrap(),
+        })
+    }
+
+    /// Verifies the cookie and authenticator of a request (RFC 8915
+    /// section 5.7).
+    pub fn check(&self, request: &NtpPacket) -> NtsStatus {
+        let fields = &request.extensions;
+        let unique_id = match request.extension(FieldType::UniqueIdentifier) {
+            Some(field) => field.value.clone(),
+            None => return NtsStatus::None,
+        };
+        let cookies: Vec<_> = fields
+            .iter()
+            .filter(|f| f.field_type == FieldType::NtsCookie)
+            .collect();
+        let auth_pos = fields
+            .iter()
+            .position(|f| f.field_type == FieldType::NtsAuthenticator);
+        if cookies.is_empty() && auth_pos.is_none() {
+            return NtsStatus::None;
+        }
+        if unique_id.len() < MIN_UNIQUE_ID || cookies.len() != 1 {
+            return NtsStatus::Invalid(unique_id);
+        }
+        let Some(keys) = self.open_cookie(&cookies[0].value) else {
+            return NtsStatus::Invalid(unique_id);
+        };
+        // The authenticator must be the last field, nothing unauthenticated
+        // may follow it.
+        let Some(auth_pos) = auth_pos.filter(|&pos| pos + 1 == fields.len()) else {
+            return NtsStatus::Invalid(unique_id);
+        };
+
+        let ad = request.encode_prefix(auth_pos);
+        let Some(plaintext) = open_authenticator(&keys.c2s, &ad, &fields[auth_pos]) else {
+            return NtsStatus::Invalid(unique_id);
+        };
+        let Ok(encrypted) = extension::parse_fields(&plaintext) else {
+            return NtsStatus::Invalid(unique_id);
+        };
+
+        let placeholders = fields[..auth_pos]
+            .iter()
+            .chain(encrypted.iter())
+            .filter(|f| f.field_type == FieldType::NtsCookiePlaceholder)
+            .count();
+        NtsStatus::Valid(NtsRequest {
+            keys,
+            unique_id,
+            cookies: (1 + placeholders).min(MAX_COOKIES),
+        })
+    }
+
+    /// Adds the unique identifier and an authenticator holding fresh
+    /// encrypted cookies to the response, after any fields handlers added.
+    pub fn seal(&self, response: &mut NtpPacket, request: &NtsRequest) {
+        response.extensions.push(NtpExtensionField::new(
+            FieldType::UniqueIdentifier,
+            request.unique_id.clone(),
+        ));
+
+        let cookies: Vec<_> = (0..request.cookies)
+            .map(|_| NtpExtensionField::new(FieldType::NtsCookie, self.make_cookie(&request.keys)))
+            .collect();
+        let mut plaintext = vec![];
+        extension::write_fields(&cookies, &mut plaintext);
+        let authenticator = seal_authenticator(&request.keys.s2c, &response.authenticated_data(), &plaintext);
+        response.extensions.push(authenticator);
+    }
+}
+
+/// Turns a response into an NTSN kiss-o'-death echoing the unique identifier.
+pub fn nak(response: &mut NtpPacket, unique_id: Vec<u8>) {
+    response.leap = 3;
+    response.stratum = 0;
+    response.ref_id = u32::from_be_bytes(*b"NTSN");
+    response.extensions = vec![NtpExtensionField::new(FieldType::UniqueIdentifier, unique_id)];
+    response.mac = None;
+}
+
+fn seal_authenticator(key: &[u8; KEY_LEN], ad: &[u8], plaintext: &[u8]) -> NtpExtensionField {
+    let mut nonce = vec![0; NONCE_LEN];
+    rand::thread_rng().fill(&mut nonce[..]);
+    let mut cipher = Aes128Siv::new_from_slice(key).unwrap();
+    let ciphertext = cipher.encrypt([ad, &nonce[..]], plaintext).unwrap();
+    NtpExtensionField::authenticator(&Authenticator { nonce, ciphertext })
+}
+
+fn open_authenticator(key: &[u8; KEY_LEN], ad: &[u8], field: &NtpExtensionField) -> Option<Vec<u8>> {
+    let authenticator = field.to_authenticator()?;
+    if authenticator.nonce.is_empty() {
+        return None;
+    }
+    let mut cipher = Aes128Siv::new_from_slice(key).unwrap();
+    cipher
+        .decrypt([ad, &authenticator.nonce[..]], &authenticator.ciphertext)
+        .ok()
+}
+
+/// NTS-KE listener configuration.
+pub struct KeConfig {
+    /// Addresses and TCP port to listen on.
+    pub listen: ListenConfig,
+    pub cert_file: String,
+    pub key_file: String,
+    /// NTP server and port announced to clients, when not this host.
+    pub ntp_server: Option<String>,
+    pub ntp_port: Option<u16>,
+}
+
+/// Runs the NTS-KE TLS listener handing out cookies for `jar` on every
+/// configured address. Fails only when no address could be bound.
+pub async fn run_ke_server(config: KeConfig, jar: Arc<RwLock<CookieJar>>) -> io::Result<()> {
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config(&config)?));
+    let mut listeners = vec![];
+    for (entry, result) in listen::resolve(&config.listen) {
+        match result.and_then(listen::bind_tcp) {
+            Ok(listener) => {
+                info!("NTS-KE listening on {}", listener.local_addr()?);
+                listeners.push(listener);
+            }
+            Err(e) => error!("Failed to bind NTS-KE address {}: {}", entry, e),
+        }
+    }
+    if listeners.is_empty() {
+        return Err(Error::new(ErrorKind::AddrNotAvailable, "No NTS-KE address could be bound"));
+    }
+
+    let config = Arc::new(config);
+    let tasks: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| tokio::spawn(accept_ke(listener, acceptor.clone(), Arc::clone(&jar), Arc::clone(&config))))
+        .collect();
+    for task in tasks {
+        let _ = task.await;
+    }
+    Ok(())
+}
+
+async fn accept_ke(listener: TcpListener, acceptor: TlsAcceptor, jar: Arc<RwLock<CookieJar>>, config: Arc<KeConfig>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("NTS-KE accept failed: {}", e);
+                sleep(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let jar = Arc::clone(&jar);
+        let config = Arc::clone(&config);
+        tokio::spawn(async move {
+            match timeout(KE_TIMEOUT, handle_ke(stream, acceptor, jar, &config)).await {
+                Ok(Ok(())) => debug!("NTS-KE with {} done", addr),
+                Ok(Err(e)) => debug!("NTS-KE with {} failed: {}", addr, e),
+                Err(_) => debug!("NTS-KE with {} timed out", addr),
+            }
+        });
+    }
+}
+
+fn tls_config(config: &KeConfig) -> io::Result<rustls::ServerConfig> {
+    let mut reader = BufReader::new(File::open(&config.cert_file)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let mut reader = BufReader::new(File::open(&config.key_file)?);
+    let key = rustls_pemfile::read_all(&mut reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No private key found"))?;
+
+    let mut tls = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(tls)
+}
+
+async fn handle_ke(
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+    jar: Arc<RwLock<CookieJar>>,
+    config: &KeConfig,
+) -> io::Result<()> {
+    let mut stream = acceptor.accept(stream).await?;
+
+    let mut protocols = None;
+    let mut aeads = None;
+    let mut error = None;
+    let mut received = 0;
+    loop {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        let record = BigEndian::read_u16(&header[0..2]);
+        let mut body = vec![0; BigEndian::read_u16(&header[2..4]) as usize];
+        received += 4 + body.len();
+        if received > MAX_KE_REQUEST {
+            return Err(Error::new(ErrorKind::InvalidData, "NTS-KE request too large"));
+        }
+        stream.read_exact(&mut body).await?;
+
+        match record & !CRITICAL {
+            RECORD_END => break,
+            RECORD_NEXT_PROTOCOL => protocols = Some(read_u16_list(&body)),
+            RECORD_AEAD => aeads = Some(read_u16_list(&body)),
+            RECORD_ERROR | RECORD_WARNING => error = Some(ERROR_BAD_REQUEST),
+            RECORD_SERVER | RECORD_PORT => {}
+            _ if record & CRITICAL != 0 => error = Some(ERROR_UNRECOGNIZED_CRITICAL),
+            _ => {}
+        }
+    }
+
+    let mut response = vec![];
+    match (error, protocols, aeads) {
+        (None, Some(protocols), Some(aeads)) if aeads.contains(&AEAD_AES_SIV_CMAC_256) => {
+            if !protocols.contains(&PROTOCOL_NTPV4) {
+                write_record(&mut response, RECORD_NEXT_PROTOCOL | CRITICAL, &[]);
+            } else {
+                let (_, connection) = stream.get_ref();
+                let keys = NtsKeys {
+                    c2s: export_key(connection, 0)?,
+                    s2c: export_key(connection, 1)?,
+                };
+                write_record(&mut response, RECORD_NEXT_PROTOCOL | CRITICAL, &PROTOCOL_NTPV4.to_be_bytes());
+                write_record(&mut response, RECORD_AEAD | CRITICAL, &AEAD_AES_SIV_CMAC_256.to_be_bytes());
+                if let Some(server) = &config.ntp_server {
+                    write_record(&mut response, RECORD_SERVER, server.as_bytes());
+                }
+                if let Some(port) = config.ntp_port {
+                    write_record(&mut response, RECORD_PORT, &port.to_be_bytes());
+                }
+                let jar = jar.read().unwrap();
+                for _ in 0..MAX_COOKIES {
+                    write_record(&mut response, RECORD_NEW_COOKIE, &jar.make_cookie(&keys));
+                }
+            }
+        }
+        (error, _, _) => {
+            let code = error.unwrap_or(ERROR_BAD_REQUEST);
+            write_record(&mut response, RECORD_ERROR | CRITICAL, &code.to_be_bytes());
+        }
+    }
+    write_record(&mut response, RECORD_END | CRITICAL, &[]);
+
+    stream.write_all(&response).await?;
+    stream.shutdown().await
+}
+
+fn export_key(connection: &rustls::ServerConnection, direction: u8) -> io::Result<[u8; KEY_LEN]> {
+    connection
+        .export_keying_material([0; KEY_LEN], EXPORTER_LABEL, Some(&exporter_context(direction)))
+        .map_err(Error::other)
+}
+
+/// Exporter context of RFC 8915 section 5.1, direction 0 for the
+/// client-to-server key and 1 for the server-to-client key.
+fn exporter_context(direction: u8) -> Vec<u8> {
+    let mut context = vec![];
+    context.extend_from_slice(&PROTOCOL_NTPV4.to_be_bytes());
+    context.extend_from_slice(&AEAD_AES_SIV_CMAC_256.to_be_bytes());
+    context.push(direction);
+    context
+}
+
+fn read_u16_list(body: &[u8]) -> Vec<u16> {
+    body.chunks_exact(2).map(BigEndian::read_u16).collect()
+}
+
+fn write_record(buf: &mut Vec<u8>, record: u16, body: &[u8]) {
+    buf.extend_from_slice(&record.to_be_bytes());
+    buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    buf.extend_from_slice(body);
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+    use crate::ntp::local_clock::{Clock, SystemClock};
+    use crate::ntp::simulation;
+    use crate::ntp::{NtpAuth, NtpServer, NtpTimestamp};
+
+    const HOUR: i64 = 3600;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/src/ntp/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn jar() -> CookieJar {
+        let mut jar = CookieJar::new(vec![], Duration::from_secs(HOUR as u64));
+        assert!(jar.rotate_if_due(0));
+        jar
+    }
+
+    fn session_keys() -> NtsKeys {
+        NtsKeys {
+            c2s: [1; KEY_LEN],
+            s2c: [2; KEY_LEN],
+        }
+    }
+
+    /// Client side of RFC 8915 section 5.7: unique identifier, cookie,
+    /// placeholders, then the authenticator over all of them.
+    fn protect(request: &mut NtpPacket, unique_id: &[u8], cookie: &[u8], placeholders: usize, c2s: &[u8; KEY_LEN]) {
+        request.extensions.push(NtpExtensionField::new(FieldType::UniqueIdentifier, unique_id.to_vec()));
+        request.extensions.push(NtpExtensionField::new(FieldType::NtsCookie, cookie.to_vec()));
+        for _ in 0..placeholders {
+            request
+                .extensions
+                .push(NtpExtensionField::new(FieldType::NtsCookiePlaceholder, vec![0; cookie.len()]));
+        }
+        let authenticator = seal_authenticator(c2s, &request.authenticated_data(), &[]);
+        request.extensions.push(authenticator);
+    }
+
+    /// Checks the response authenticator and returns the cookies it holds.
+    fn open_response(response: &NtpPacket, unique_id: &[u8], s2c: &[u8; KEY_LEN]) -> Vec<Vec<u8>> {
+        let fields = &response.extensions;
+        let auth_pos = fields.len() - 1;
+        assert_eq!(fields[auth_pos].field_type, FieldType::NtsAuthenticator);
+        assert_eq!(response.extension(FieldType::UniqueIdentifier).unwrap().value, unique_id);
+        let plaintext = open_authenticator(s2c, &response.encode_prefix(auth_pos), &fields[auth_pos]).unwrap();
+        extension::parse_fields(&plaintext)
+            .unwrap()
+            .into_iter()
+            .map(|field| {
+                assert_eq!(field.field_type, FieldType::NtsCookie);
+                field.value
+            })
+            .collect()
+    }
+
+    async fn request() -> NtpPacket {
+        NtpPacket::new_request(SocketAddr::from(([192, 0, 2, 1], 123)), &SystemClock).await
+    }
+
+    fn decoded(packet: &NtpPacket) -> NtpPacket {
+        NtpPacket::decode(&packet.encode(), packet.remote_addr, NtpTimestamp::zero()).unwrap()
+    }
+
+    #[test]
+    fn cookie_round_trip() {
+        let jar = jar();
+        let cookie = jar.make_cookie(&session_keys());
+        // Key ID, nonce, both keys and the SIV tag.
+        assert_eq!(cookie.len(), 4 + NONCE_LEN + 2 * KEY_LEN + 16);
+        assert_ne!(cookie, jar.make_cookie(&session_keys()));
+
+        let keys = jar.open_cookie(&cookie).unwrap();
+        assert_eq!((keys.c2s, keys.s2c), ([1; KEY_LEN], [2; KEY_LEN]));
+
+        let mut tampered = cookie.clone();
+        tampered[30] ^= 1;
+        assert!(jar.open_cookie(&tampered).is_none());
+        assert!(jar.open_cookie(&cookie[..4 + NONCE_LEN - 1]).is_none());
+        assert!(self::jar().open_cookie(&cookie).is_none());
+    }
+
+    #[test]
+    fn cookies_outlive_two_rotations() {
+        let mut jar = jar();
+        let first_id = jar.keys()[0].id;
+        let cookie = jar.make_cookie(&session_keys());
+
+        assert!(!jar.rotate_if_due(HOUR - 1));
+        assert!(jar.rotate_if_due(HOUR));
+        assert!(jar.rotate_if_due(2 * HOUR));
+        let ids: Vec<_> = jar.keys().iter().map(|key| key.id).collect();
+        assert_eq!(ids, [first_id, first_id.wrapping_add(1), first_id.wrapping_add(2)]);
+        // New cookies use the newest key, old ones still open.
+        assert_eq!(jar.make_cookie(&session_keys())[0..4], first_id.wrapping_add(2).to_be_bytes());
+        assert!(jar.open_cookie(&cookie).is_some());
+
+        assert!(jar.rotate_if_due(3 * HOUR));
+        assert_eq!(jar.keys().len(), KEPT_KEYS);
+        assert!(jar.open_cookie(&cookie).is_none());
+
+        // Persisted keys come back the way they were saved.
+        let restored: Vec<_> = jar
+            .keys()
+            .iter()
+            .map(|key| MasterKey::from_hex(key.id, &key.to_hex(), key.created).unwrap())
+            .collect();
+        let restored = CookieJar::new(restored, Duration::from_secs(HOUR as u64));
+        assert!(restored.open_cookie(&jar.make_cookie(&session_keys())).is_some());
+    }
+
+    #[tokio::test]
+    async fn check_and_seal() {
+        let jar = jar();
+        let keys = session_keys();
+        let cookie = jar.make_cookie(&keys);
+        let unique_id = [7; MIN_UNIQUE_ID];
+
+        let mut request = request().await;
+        protect(&mut request, &unique_id, &cookie, 2, &keys.c2s);
+        let request = decoded(&request);
+        let NtsStatus::Valid(nts_request) = jar.check(&request) else {
+            panic!("valid request rejected");
+        };
+        assert_eq!(nts_request.cookies, 3);
+
+        let mut response = decoded(&request);
+        response.mode = 4;
+        response.extensions.clear();
+        jar.seal(&mut response, &nts_request);
+        let cookies = open_response(&decoded(&response), &unique_id, &keys.s2c);
+        assert_eq!(cookies.len(), 3);
+        assert!(cookies.iter().all(|cookie| jar.open_cookie(cookie).is_some()));
+    }
+
+    #[tokio::test]
+    async fn check_rejects() {
+        let jar = jar();
+        let keys = session_keys();
+        let cookie = jar.make_cookie(&keys);
+        let unique_id = [7; MIN_UNIQUE_ID];
+
+        assert!(matches!(jar.check(&request().await), NtsStatus::None));
+
+        let cases: [(&[u8], Vec<u8>, [u8; KEY_LEN]); 3] = [
+            (&unique_id[..MIN_UNIQUE_ID / 2], cookie.clone(), keys.c2s),
+            (&unique_id, jar.make_cookie(&keys)[..40].to_vec(), keys.c2s),
+            (&unique_id, cookie.clone(), keys.s2c),
+        ];
+        for (id, cookie, c2s) in cases {
+            let mut request = request().await;
+            protect(&mut request, id, &cookie, 0, &c2s);
+            match jar.check(&decoded(&request)) {
+                NtsStatus::Invalid(echoed) => assert_eq!(echoed, id),
+                _ => panic!("invalid request accepted"),
+            }
+        }
+
+        // Nothing may follow the authenticator.
+        let mut request = request().await;
+        protect(&mut request, &unique_id, &cookie, 0, &keys.c2s);
+        request
+            .extensions
+            .push(NtpExtensionField::new(FieldType::NtsCookiePlaceholder, vec![0; cookie.len()]));
+        assert!(matches!(jar.check(&decoded(&request)), NtsStatus::Invalid(_)));
+
+        let mut response = decoded(&request);
+        nak(&mut response, unique_id.to_vec());
+        let response = decoded(&response);
+        assert_eq!((response.stratum, &response.ref_id.to_be_bytes()), (0, b"NTSN"));
+        assert_eq!(response.extensions.len(), 1);
+    }
+
+    struct KeResponse {
+        records: Vec<(u16, Vec<u8>)>,
+        keys: NtsKeys,
+    }
+
+    impl KeResponse {
+        fn values(&self, record: u16) -> Vec<&[u8]> {
+            self.records
+                .iter()
+                .filter(|(r, _)| r & !CRITICAL == record)
+                .map(|(_, body)| &body[..])
+                .collect()
+        }
+    }
+
+    async fn key_exchange(addr: SocketAddr, request: &[u8]) -> KeResponse {
+        let mut roots = RootCertStore::empty();
+        let mut reader = BufReader::new(File::open(testdata("nts-ca.pem")).unwrap());
+        for cert in rustls_pemfile::certs(&mut reader).unwrap() {
+            roots.add(&Certificate(cert)).unwrap();
+        }
+        let mut tls = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(tls)).connect(server_name, stream).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+
+        let (_, connection) = stream.get_ref();
+        let mut keys = NtsKeys {
+            c2s: [0; KEY_LEN],
+            s2c: [0; KEY_LEN],
+        };
+        for (direction, key) in [(0, &mut keys.c2s), (1, &mut keys.s2c)] {
+            *key = connection
+                .export_keying_material([0; KEY_LEN], EXPORTER_LABEL, Some(&exporter_context(direction)))
+                .unwrap();
+        }
+
+        let mut records = vec![];
+        let mut rest = &response[..];
+        while rest.len() >= 4 {
+            let len = BigEndian::read_u16(&rest[2..4]) as usize;
+            records.push((BigEndian::read_u16(&rest[0..2]), rest[4..4 + len].to_vec()));
+            rest = &rest[4 + len..];
+        }
+        assert!(rest.is_empty());
+        assert_eq!(records.last(), Some(&(RECORD_END | CRITICAL, vec![])));
+        KeResponse { records, keys }
+    }
+
+    #[tokio::test]
+    async fn loopback_exchange() {
+        let ntp_addr = SocketAddr::from(([127, 0, 0, 1], 12_351));
+        let ke_addr = SocketAddr::from(([127, 0, 0, 1], 12_460));
+        let jar = Arc::new(RwLock::new(jar()));
+
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let auth = Arc::new(RwLock::new(NtpAuth::new(vec![], vec![])));
+        let config = simulation::config(vec![ntp_addr.ip().to_string()], ntp_addr.port());
+        let server = NtpServer::new(false, config, auth, Some(Arc::clone(&jar)), clock).await;
+        server.run().await;
+
+        let config = KeConfig {
+            listen: ListenConfig {
+                addresses: vec![ke_addr.ip().to_string()],
+                port: ke_addr.port(),
+                workers: 1,
+            },
+            cert_file: testdata("nts-server.pem"),
+            key_file: testdata("nts-server.key"),
+            ntp_server: None,
+            ntp_port: Some(ntp_addr.port()),
+        };
+        tokio::spawn(run_ke_server(config, Arc::clone(&jar)));
+        sleep(Duration::from_millis(100)).await;
+
+        // Without an AEAD algorithm the request is refused.
+        let mut ke_request = vec![];
+        write_record(&mut ke_request, RECORD_NEXT_PROTOCOL | CRITICAL, &PROTOCOL_NTPV4.to_be_bytes());
+        write_record(&mut ke_request, RECORD_END | CRITICAL, &[]);
+        let refused = key_exchange(ke_addr, &ke_request).await;
+        assert_eq!(refused.values(RECORD_ERROR), [&ERROR_BAD_REQUEST.to_be_bytes()[..]]);
+
+        let mut ke_request = vec![];
+        write_record(&mut ke_request, RECORD_NEXT_PROTOCOL | CRITICAL, &PROTOCOL_NTPV4.to_be_bytes());
+        write_record(&mut ke_request, RECORD_AEAD, &[0, 30, 0, 15]);
+        write_record(&mut ke_request, RECORD_END | CRITICAL, &[]);
+        let ke = key_exchange(ke_addr, &ke_request).await;
+        assert_eq!(ke.values(RECORD_NEXT_PROTOCOL), [&PROTOCOL_NTPV4.to_be_bytes()[..]]);
+        assert_eq!(ke.values(RECORD_AEAD), [&AEAD_AES_SIV_CMAC_256.to_be_bytes()[..]]);
+        assert_eq!(ke.values(RECORD_PORT), [&ntp_addr.port().to_be_bytes()[..]]);
+        assert!(ke.values(RECORD_SERVER).is_empty());
+        let cookies = ke.values(RECORD_NEW_COOKIE);
+        assert_eq!(cookies.len(), MAX_COOKIES);
+
+        // The cookie carries the keys both sides exported from the session.
+        let keys = jar.read().unwrap().open_cookie(cookies[0]).unwrap();
+        assert_eq!((keys.c2s, keys.s2c), (ke.keys.c2s, ke.keys.s2c));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let unique_id: Vec<u8> = (0..MIN_UNIQUE_ID as u8).collect();
+        let mut request = NtpPacket::new_request(ntp_addr, &SystemClock).await;
+        protect(&mut request, &unique_id, cookies[0], 1, &ke.keys.c2s);
+        socket.send_to(&request.encode(), ntp_addr).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let (len, from) = timeout(Duration::from_secs(2), socket.recv_from(&mut buf)).await.unwrap().unwrap();
+        let response = NtpPacket::decode(&buf[..len], from, NtpTimestamp::zero()).unwrap();
+        assert!(response.is_valid_response(&request));
+        let fresh = open_response(&response, &unique_id, &ke.keys.s2c);
+        assert_eq!(fresh.len(), 2);
+
+        // A fresh cookie works for the next request, a forged authenticator
+        // gets an NTSN kiss.
+        let mut request = NtpPacket::new_request(ntp_addr, &SystemClock).await;
+        protect(&mut request, &unique_id, &fresh[0], 0, &ke.keys.s2c);
+        socket.send_to(&request.encode(), ntp_addr).await.unwrap();
+        let (len, from) = timeout(Duration::from_secs(2), socket.recv_from(&mut buf)).await.unwrap().unwrap();
+        let response = NtpPacket::decode(&buf[..len], from, NtpTimestamp::zero()).unwrap();
+        assert_eq!((response.stratum, &response.ref_id.to_be_bytes()), (0, b"NTSN"));
+        assert_eq!(response.extension(FieldType::UniqueIdentifier).unwrap().value, unique_id);
+    }
+}
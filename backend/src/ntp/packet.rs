@@ -1,11 +1,12 @@
 use std::io;
-use std::io::{Error, ErrorKind};
 
-use byteorder::{BigEndian, ByteOrder};
+use super::{NtpPacket, NtpServerState, NtpMac, NtpExtensionField};
+use super::codec::{self, ParseError};
+use super::extension::FieldType;
 
-use super::{NtpPacket, NtpServerState};
-
-use super::{NtpTimestamp, NtpFracValue};
+use super::{NtpTimestamp, NtpFracValue, NtpClockModel, NtpSyncStatus};
+use super::source::PHI;
+use super::local_clock::Clock;
 use std::net::SocketAddr;
 use tokio::{
     net::UdpSocket,
@@ -30,63 +31,40 @@ pub struct Packet {
     pub orig_ts: NtpTimestamp,
     pub rx_ts: NtpTimestamp,
     pub tx_ts: NtpTimestamp,
+    pub extensions: Vec<NtpExtensionField>,
+    pub mac: Option<NtpMac>,
 }
 
-impl Packet {
-   pub async  fn receive(socket: &UdpSocket) -> io::Result<NtpPacket> {
-        let mut buf = [0; 1024];
-
-        let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+// Room for NTS requests asking for a full set of cookies.
+pub const MAX_PACKET_LEN: usize = 2048;
 
-        let local_ts = NtpTimestamp::now();
+impl Packet {
+    /// Parses a packet received from `addr` at `local_ts`.
+    pub fn decode(buf: &[u8], addr: SocketAddr, local_ts: NtpTimestamp) -> Result<NtpPacket, ParseError> {
+        codec::decode(buf, addr, local_ts)
+    }
 
-        if len < 48 {
-            return Err(Error::new(ErrorKind::UnexpectedEof, "Packet too short"));
-        }
+    pub async fn send(&self, socket: &UdpSocket) -> io::Result<usize> {
+        socket.send_to(&self.encode(), self.remote_addr).await
+    }
 
-        let leap = buf[0] >> 6;
-        let version = (buf[0] >> 3) & 0x7;
-        let mode = buf[0] & 0x7;
+    pub fn encode(&self) -> Vec<u8> {
+        codec::encode(self)
+    }
 
-        if version < 1 || version > 4 {
-            return Err(Error::new(ErrorKind::Other, "Unsupported version"));
-        }
+    /// First extension field of the given type.
+    pub fn extension(&self, field_type: FieldType) -> Option<&NtpExtensionField> {
+        self.extensions.iter().find(|field| field.field_type == field_type)
+    }
 
-        Ok(NtpPacket{
-            remote_addr: addr,
-            local_ts: local_ts,
-            leap: leap,
-            version: version,
-            mode: mode,
-            stratum: buf[1],
-            poll: buf[2] as i8,
-            precision: buf[3] as i8,
-            delay: NtpFracValue::read(&buf[4..8]),
-            dispersion: NtpFracValue::read(&buf[8..12]),
-            ref_id: BigEndian::read_u32(&buf[12..16]),
-            ref_ts: NtpTimestamp::read(&buf[16..24]),
-            orig_ts: NtpTimestamp::read(&buf[24..32]),
-            rx_ts: NtpTimestamp::read(&buf[32..40]),
-            tx_ts: NtpTimestamp::read(&buf[40..48]),
-        })
+    /// Header and extension fields, the part of the packet covered by the MAC.
+    pub fn authenticated_data(&self) -> Vec<u8> {
+        self.encode_prefix(self.extensions.len())
     }
 
-    pub async fn send(&self, socket: &UdpSocket) -> io::Result<usize> {
-        let mut buf = [0; 48];
-
-        buf[0] = self.leap << 6 | self.version << 3 | self.mode;
-        buf[1] = self.stratum;
-        buf[2] = self.poll as u8;
-        buf[3] = self.precision as u8;
-        self.delay.write(&mut buf[4..8]);
-        self.dispersion.write(&mut buf[8..12]);
-        BigEndian::write_u32(&mut buf[12..16], self.ref_id);
-        self.ref_ts.write(&mut buf[16..24]);
-        self.orig_ts.write(&mut buf[24..32]);
-        self.rx_ts.write(&mut buf[32..40]);
-        self.tx_ts.write(&mut buf[40..48]);
-
-        socket.send_to(&buf, self.remote_addr).await
+    /// Header followed by the first `fields` extension fields.
+    pub fn encode_prefix(&self, fields: usize) -> Vec<u8> {
+        codec::encode_prefix(self, fields)
     }
 
     pub fn is_request(&self) -> bool {
@@ -94,11 +72,13 @@ impl Packet {
             (self.mode == 0 && self.version == 1 && self.remote_addr.port() != 123)
     }
 
-    pub fn make_response(&self, state: &NtpServerState) -> Option<NtpPacket> {
+    pub fn make_response(&self, state: &NtpServerState, clock: &dyn Clock) -> Option<NtpPacket> {
         if !self.is_request() {
             return None;
         }
 
+        let tx_ts = state.clock.convert(clock.now());
+
         Some(NtpPacket{
             remote_addr: self.remote_addr,
             local_ts: NtpTimestamp::zero(),
@@ -109,19 +89,56 @@ impl Packet {
             poll: self.poll,
             precision: state.precision,
             delay: state.delay,
-            dispersion: state.dispersion,
+            dispersion: state.root_dispersion(&tx_ts),
             ref_id: state.ref_id,
             ref_ts: state.ref_ts,
             orig_ts: self.tx_ts,
-            rx_ts: self.local_ts,
-            tx_ts: NtpTimestamp::now(),
+            rx_ts: state.clock.convert(self.local_ts),
+            tx_ts,
+            extensions: vec![],
+            mac: None,
         })
     }
 
-    pub async fn new_request(remote_addr: SocketAddr) -> NtpPacket {
+    /// Kiss-o'-Death reply carrying `code` in the reference ID (RFC 5905
+    /// section 7.4).
+    pub fn make_kiss(&self, state: &NtpServerState, code: &[u8; 4], clock: &dyn Clock) -> Option<NtpPacket> {
+        let mut response = self.make_response(state, clock)?;
+        response.leap = 3;
+        response.stratum = 0;
+        response.ref_id = u32::from_be_bytes(*code);
+        Some(response)
+    }
+
+    /// Unsolicited mode 5 packet for broadcast clients.
+    pub fn make_broadcast(state: &NtpServerState, remote_addr: SocketAddr, poll: i8, clock: &dyn Clock) -> NtpPacket {
+        let tx_ts = state.clock.convert(clock.now());
+
+        NtpPacket{
+            remote_addr,
+            local_ts: NtpTimestamp::zero(),
+            leap: state.leap,
+            version: 4,
+            mode: 5,
+            stratum: state.stratum,
+            poll,
+            precision: state.precision,
+            delay: state.delay,
+            dispersion: state.root_dispersion(&tx_ts),
+            ref_id: state.ref_id,
+            ref_ts: state.ref_ts,
+            orig_ts: NtpTimestamp::zero(),
+            rx_ts: NtpTimestamp::zero(),
+            tx_ts,
+            extensions: vec![],
+            mac: None,
+        }
+    }
+
+    pub async fn new_request(remote_addr: SocketAddr, clock: &dyn Clock) -> NtpPacket {
         NtpPacket{
             remote_addr: remote_addr,
-            local_ts: NtpTimestamp::now(),
+            local_ts: clock.now(),
             leap: 0,
             version: 4,
             mode: 3,
@@ -135,6 +152,8 @@ impl Packet {
             orig_ts: NtpTimestamp::zero(),
             rx_ts: NtpTimestamp::zero(),
             tx_ts: NtpTimestamp::random(),
+            extensions: vec![],
+            mac: None,
         }
     }
 
@@ -153,6 +172,13 @@ impl Packet {
             ref_ts: self.ref_ts,
             dispersion: self.dispersion,
             delay: self.delay,
+            clock: NtpClockModel::new(),
+            sync: if self.leap == 3 {
+                NtpSyncStatus::Unsynchronized
+            } else {
+                NtpSyncStatus::Synchronized
+            },
+            dispersion_rate: PHI,
         }
     }
 
@@ -0,0 +1,330 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use super::source::PHI;
+use super::{NtpSample, NtpTimestamp};
+
+// Poll interval bounds, log2 seconds (RFC 5905 MINPOLL and MAXPOLL).
+pub const MIN_POLL: i8 = 3;
+pub const MAX_POLL: i8 = 17;
+// Clock filter depth (RFC 5905 section 10).
+const FILTER_SIZE: usize = 8;
+// Consecutive good samples needed before the poll interval is doubled.
+const POLL_HYSTERESIS: u32 = 4;
+// Offsets or jitter above these make the peer poll faster again.
+const POLL_OFFSET_LIMIT: f64 = 0.128;
+const POLL_JITTER_LIMIT: f64 = 0.02;
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct FilterSample {
+    pub offset: f64,
+    pub delay: f64,
+    pub dispersion: f64,
+    /// System time the offset was measured at.
+    #[serde(skip)]
+    local_ts: NtpTimestamp,
+    /// Number of the response among all of this peer's.
+    #[serde(skip)]
+    seq: u64,
+}
+
+/// State kept for one configured upstream server.
+#[derive(Debug, Clone, Serialize)]
+pub struct Peer {
+    pub url: String,
+    pub addr: Option<SocketAddr>,
+    /// Symmetric key used to authenticate to this server.
+    pub key_id: Option<u32>,
+    /// Shift register of the last eight polls, 1 bits are answers.
+    pub reach: u8,
+    /// Current poll interval, log2 seconds.
+    pub poll: i8,
+    pub offset: f64,
+    pub delay: f64,
+    pub dispersion: f64,
+    pub jitter: f64,
+    filter: VecDeque<FilterSample>,
+    #[serde(skip)]
+    last_sample: Option<NtpSample>,
+    #[serde(skip)]
+    minpoll: i8,
+    #[serde(skip)]
+    maxpoll: i8,
+    #[serde(skip)]
+    good_polls: u32,
+    #[serde(skip)]
+    responses: u64,
+    /// Response number of the filter sample last passed on.
+    #[serde(skip)]
+    reported: Option<u64>,
+}
+
+impl Peer {
+    /// `cycle` is the configured starting poll period in milliseconds.
+    pub fn new(url: String, cycle: u32, minpoll: i8, maxpoll: i8) -> Peer {
+        let minpoll = minpoll.clamp(MIN_POLL, MAX_POLL);
+        let maxpoll = maxpoll.clamp(minpoll, MAX_POLL);
+        let start = (cycle.max(1000) as f64 / 1000.0).log2().round() as i8;
+        Peer {
+            url,
+            addr: None,
+            key_id: None,
+            reach: 0,
+            poll: start.clamp(minpoll, maxpoll),
+            offset: 0.0,
+            delay: 0.0,
+            dispersion: 0.0,
+            jitter: 0.0,
+            filter: VecDeque::with_capacity(FILTER_SIZE),
+            last_sample: None,
+            minpoll,
+            maxpoll,
+            good_polls: 0,
+            responses: 0,
+            reported: None,
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(1 << self.poll.max(0))
+    }
+
+    pub fn is_reachable(&self) -> bool {
+        self.reach != 0
+    }
+
+    /// Best sample of the clock filter with its own measurement time, None
+    /// when it was passed on before: like RFC 5905 only newer samples than
+    /// the last one used are, an old offset is never paired with a new time.
+    pub fn sample(&mut self) -> Option<NtpSample> {
+        let mut sample = self.last_sample?;
+        let best = self.best()?;
+        if self.reported.is_some_and(|seq| best.seq <= seq) {
+            return None;
+        }
+        self.reported = Some(best.seq);
+        sample.local_ts = best.local_ts;
+        sample.poll = Some(self.poll_interval());
+        sample.offset = self.offset;
+        sample.quality.delay = self.delay;
+        sample.quality.dispersion = self.dispersion;
+        sample.quality.jitter = self.jitter;
+        Some(sample)
+    }
+
+    pub fn on_response(&mut self, sample: NtpSample) {
+        self.reach = self.reach << 1 | 1;
+        self.last_sample = Some(sample);
+
+        // Older entries age at PHI per poll interval.
+        let age = PHI * self.poll_interval().as_secs_f64();
+        for entry in self.filter.iter_mut() {
+            entry.dispersion += age;
+        }
+        if self.filter.len() == FILTER_SIZE {
+            self.filter.pop_back();
+        }
+        self.responses += 1;
+        self.filter.push_front(FilterSample {
+            offset: sample.offset,
+            delay: sample.quality.delay,
+            dispersion: sample.quality.dispersion,
+            local_ts: sample.local_ts,
+            seq: self.responses,
+        });
+        self.apply_filter();
+        self.adapt_poll();
+    }
+
+    pub fn on_timeout(&mut self) {
+        self.reach <<= 1;
+        self.good_polls = 0;
+        if self.reach == 0 {
+            // Nothing heard for eight polls or ever, back off one step at a
+            // time so a server that was down at boot is found again soon.
+            self.poll = (self.poll + 1).min(self.maxpoll);
+        } else {
+            self.poll = (self.poll - 1).max(self.minpoll);
+        }
+    }
+
+    // The minimum-delay sample is the most trustworthy one; jitter is the
+    // RMS of the other offsets against it.
+    fn apply_filter(&mut self) {
+        if let Some(best) = self.best() {
+            self.offset = best.offset;
+            self.delay = best.delay;
+            self.dispersion = best.dispersion;
+
+            let others = self.filter.len() - 1;
+            if others > 0 {
+                let sum: f64 = self
+                    .filter
+                    .iter()
+                    .map(|entry| (entry.offset - best.offset).powi(2))
+                    .sum();
+                self.jitter = (sum / others as f64).sqrt();
+            }
+        }
+    }
+
+    fn best(&self) -> Option<FilterSample> {
+        self.filter
+            .iter()
+            .min_by(|a, b| a.delay.total_cmp(&b.delay))
+            .copied()
+    }
+
+    fn adapt_poll(&mut self) {
+        if self.offset.abs() > POLL_OFFSET_LIMIT || self.jitter > POLL_JITTER_LIMIT {
+            self.good_polls = 0;
+            self.poll = (self.poll - 1).max(self.minpoll);
+            return;
+        }
+
+        self.good_polls += 1;
+        if self.good_polls >= POLL_HYSTERESIS {
+            self.good_polls = 0;
+            self.poll = (self.poll + 1).min(self.maxpoll);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntp::{NtpSampleQuality, NtpSource, NtpTimestamp};
+
+    fn sample(offset: f64, delay: f64) -> NtpSample {
+        sample_at(offset, delay, 0)
+    }
+
+    fn sample_at(offset: f64, delay: f64, secs: u64) -> NtpSample {
+        NtpSample {
+            source: NtpSource::Ntp {
+                addr: SocketAddr::from(([192, 0, 2, 1], 123)),
+                stratum: 1,
+                leap: 0,
+            },
+            offset,
+            local_ts: NtpTimestamp::from_unix_secs(1_700_000_000 + secs),
+            quality: NtpSampleQuality {
+                delay,
+                dispersion: 1e-3,
+                ..Default::default()
+            },
+            poll: None,
+        }
+    }
+
+    #[test]
+    fn reach_register() {
+        let mut peer = Peer::new(String::from("peer"), 16_000, 4, 10);
+        assert!(!peer.is_reachable());
+        peer.on_response(sample(0.0, 0.01));
+        peer.on_timeout();
+        peer.on_response(sample(0.0, 0.01));
+        assert_eq!(peer.reach, 0b101);
+
+        for _ in 0..7 {
+            peer.on_timeout();
+        }
+        assert_eq!(peer.reach, 0b1000_0000);
+        assert!(peer.is_reachable());
+        peer.on_timeout();
+        assert!(!peer.is_reachable());
+        assert_eq!(peer.poll, 5);
+    }
+
+    #[test]
+    fn unreachable_backs_off_gradually() {
+        let mut peer = Peer::new(String::from("peer"), 1000, 3, 10);
+        let mut polls = vec![];
+        for _ in 0..9 {
+            peer.on_timeout();
+            polls.push(peer.poll);
+        }
+        assert_eq!(polls, [4, 5, 6, 7, 8, 9, 10, 10, 10]);
+
+        // The first answer starts over from there.
+        peer.on_response(sample(0.0, 0.01));
+        peer.on_timeout();
+        assert_eq!(peer.poll, 9);
+    }
+
+    #[test]
+    fn filter_prefers_lowest_delay() {
+        // A fixed 64 s poll ages older entries by PHI * 64 per response.
+        let mut peer = Peer::new(String::from("peer"), 64_000, 6, 6);
+        peer.on_response(sample(0.010, 0.05));
+        peer.on_response(sample(0.002, 0.01));
+        peer.on_response(sample(0.004, 0.03));
+        assert_eq!((peer.offset, peer.delay), (0.002, 0.01));
+        assert!((peer.dispersion - (1e-3 + PHI * 64.0)).abs() < 1e-12);
+        // RMS of 0.008 and 0.002 over the two other samples.
+        assert!((peer.jitter - 34e-6f64.sqrt()).abs() < 1e-12);
+
+        // The best sample stays until seven newer ones push it out.
+        for _ in 0..6 {
+            peer.on_response(sample(0.1, 0.2));
+        }
+        assert_eq!(peer.offset, 0.002);
+        peer.on_response(sample(0.1, 0.2));
+        assert_eq!((peer.offset, peer.delay), (0.004, 0.03));
+        assert_eq!(peer.filter.len(), FILTER_SIZE);
+    }
+
+    #[test]
+    fn samples_keep_their_own_time() {
+        let mut peer = Peer::new(String::from("peer"), 64_000, 6, 6);
+        peer.on_response(sample_at(0.002, 0.01, 0));
+        let first = peer.sample().unwrap();
+        assert_eq!((first.offset, first.local_ts), (0.002, NtpTimestamp::from_unix_secs(1_700_000_000)));
+
+        // The best offset is still the old one, it is not passed on again.
+        peer.on_response(sample_at(0.1, 0.2, 64));
+        assert!(peer.sample().is_none());
+
+        peer.on_response(sample_at(0.003, 0.005, 128));
+        let next = peer.sample().unwrap();
+        assert_eq!((next.offset, next.local_ts), (0.003, NtpTimestamp::from_unix_secs(1_700_000_128)));
+        assert!(peer.sample().is_none());
+    }
+
+    #[test]
+    fn poll_adapts() {
+        let mut peer = Peer::new(String::from("peer"), 16_000, 4, 6);
+        assert_eq!(peer.poll_interval(), Duration::from_secs(16));
+        let mut polls = vec![];
+        for _ in 0..12 {
+            peer.on_response(sample(0.0, 0.01));
+            polls.push(peer.poll);
+        }
+        assert_eq!(polls, [4, 4, 4, 5, 5, 5, 5, 6, 6, 6, 6, 6]);
+        assert_eq!(peer.sample().unwrap().poll, Some(Duration::from_secs(64)));
+
+        // A large offset or a lost answer speeds polling up again.
+        peer.on_response(sample(0.2, 0.001));
+        assert_eq!(peer.poll, 5);
+        peer.on_timeout();
+        peer.on_timeout();
+        assert_eq!(peer.poll, 4);
+    }
+
+    #[test]
+    fn poll_bounds() {
+        let peer = Peer::new(String::from("peer"), 1000, -5, 40);
+        assert_eq!((peer.minpoll, peer.maxpoll, peer.poll), (MIN_POLL, MAX_POLL, MIN_POLL));
+        let peer = Peer::new(String::from("peer"), 1000, 10, 5);
+        assert_eq!((peer.minpoll, peer.maxpoll, peer.poll), (10, 10, 10));
+
+        let mut peer = Peer::new(String::from("peer"), 1000, 3, 17);
+        for _ in 0..20 {
+            peer.on_timeout();
+        }
+        assert_eq!(peer.poll_interval(), Duration::from_secs(1 << 17));
+    }
+}
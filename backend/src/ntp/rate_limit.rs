@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use super::shard::{Sharded, SHARDS};
+
+// How often a full table is swept for idle clients.
+const PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RateDecision {
+    Pass,
+    /// Over the limit, answer with a RATE kiss-o'-death.
+    Kod,
+    Drop,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub enable: bool,
+    /// Sustained average interval between requests of one client.
+    pub interval: Duration,
+    /// Requests a client may send back to back.
+    pub burst: u32,
+    /// Send RATE kisses instead of silently dropping.
+    pub kod: bool,
+    /// Clients tracked individually, the rest share a few buckets.
+    pub table_size: usize,
+}
+
+/// Counters reported by the REST API.
+#[derive(Debug, Copy, Clone, Default, Serialize, ToSchema)]
+pub struct RateStats {
+    pub passed: u64,
+    pub dropped: u64,
+    pub kod: u64,
+    /// Requests of untracked clients, limited through the shared bucket.
+    pub overflow: u64,
+    pub clients: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    last_kod: Option<Instant>,
+}
+
+impl Bucket {
+    fn new(burst: f64, now: Instant) -> Bucket {
+        Bucket {
+            tokens: burst,
+            last: now,
+            last_kod: None,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+    }
+}
+
+/// Token bucket per client address with a bounded table, in the spirit of
+/// ntpd's `limited` and `kod` restrictions. The table is sharded by
+/// address, each shard with its own share of the size and overflow bucket.
+pub struct RateLimiter {
+    tables: Sharded<Table>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, now: Instant) -> RateLimiter {
+        let config = per_shard(config);
+        RateLimiter {
+            tables: Sharded::new(|| Table::new(config.clone(), now)),
+        }
+    }
+
+    pub fn set_config(&self, config: RateLimitConfig, now: Instant) {
+        let config = per_shard(config);
+        self.tables.for_each(|table| table.set_config(config.clone(), now));
+    }
+
+    pub fn stats(&self) -> RateStats {
+        let mut total = RateStats::default();
+        self.tables.for_each(|table| {
+            let stats = table.stats();
+            total.passed += stats.passed;
+            total.dropped += stats.dropped;
+            total.kod += stats.kod;
+            total.overflow += stats.overflow;
+            total.clients += stats.clients;
+        });
+        total
+    }
+
+    pub fn check(&self, addr: IpAddr, now: Instant) -> RateDecision {
+        self.tables.get(&addr).check(addr, now)
+    }
+}
+
+fn per_shard(config: RateLimitConfig) -> RateLimitConfig {
+    RateLimitConfig {
+        table_size: config.table_size.div_ceil(SHARDS),
+        ..config
+    }
+}
+
+struct Table {
+    config: RateLimitConfig,
+    clients: HashMap<IpAddr, Bucket>,
+    overflow: Bucket,
+    last_purge: Instant,
+    stats: RateStats,
+}
+
+impl Table {
+    fn new(config: RateLimitConfig, now: Instant) -> Table {
+        Table {
+            overflow: Bucket::new(config.burst as f64, now),
+            config,
+            clients: HashMap::new(),
+            last_purge: now,
+            stats: RateStats::default(),
+        }
+    }
+
+    fn set_config(&mut self, config: RateLimitConfig, now: Instant) {
+        self.clients.clear();
+        self.overflow = Bucket::new(config.burst as f64, now);
+        self.config = config;
+    }
+
+    fn stats(&self) -> RateStats {
+        RateStats {
+            clients: self.clients.len(),
+            ..self.stats
+        }
+    }
+
+    fn rate(&self) -> f64 {
+        1.0 / self.config.interval.as_secs_f64().max(1e-3)
+    }
+
+    fn check(&mut self, addr: IpAddr, now: Instant) -> RateDecision {
+        if !self.config.enable {
+            self.stats.passed += 1;
+            return RateDecision::Pass;
+        }
+        let rate = self.rate();
+        let burst = self.config.burst.max(1) as f64;
+
+        if !self.clients.contains_key(&addr) && self.clients.len() >= self.config.table_size {
+            self.purge(rate, burst, now);
+        }
+        let tracked = self.clients.len() < self.config.table_size || self.clients.contains_key(&addr);
+        let bucket = if tracked {
+            self.clients.entry(addr).or_insert_with(|| Bucket::new(burst, now))
+        } else {
+            self.stats.overflow += 1;
+            &mut self.overflow
+        };
+
+        bucket.refill(rate, burst, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            self.stats.passed += 1;
+            return RateDecision::Pass;
+        }
+
+        // Kisses are rate limited as well, at most one per refill interval.
+        let kod_due = bucket
+            .last_kod
+            .is_none_or(|at| now.saturating_duration_since(at) >= self.config.interval);
+        if self.config.kod && kod_due {
+            bucket.last_kod = Some(now);
+            self.stats.kod += 1;
+            RateDecision::Kod
+        } else {
+            self.stats.dropped += 1;
+            RateDecision::Drop
+        }
+    }
+
+    /// Forgets clients whose bucket has refilled, they behave like new ones.
+    fn purge(&mut self, rate: f64, burst: f64, now: Instant) {
+        if now.saturating_duration_since(self.last_purge) < PURGE_INTERVAL {
+            return;
+        }
+        self.last_purge = now;
+        self.clients.retain(|_, bucket| {
+            let idle = now.saturating_duration_since(bucket.last).as_secs_f64();
+            bucket.tokens + idle * rate < burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(kod: bool, table_size: usize) -> RateLimitConfig {
+        RateLimitConfig {
+            enable: true,
+            interval: Duration::from_secs(2),
+            burst: 3,
+            kod,
+            table_size,
+        }
+    }
+
+    fn addr(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let start = Instant::now();
+        let mut table = Table::new(config(false, 8), start);
+        for _ in 0..3 {
+            assert_eq!(table.check(addr(1), start), RateDecision::Pass);
+        }
+        assert_eq!(table.check(addr(1), start), RateDecision::Drop);
+        // Other clients have their own bucket.
+        assert_eq!(table.check(addr(2), start), RateDecision::Pass);
+
+        // One token per interval, never more than the burst.
+        assert_eq!(table.check(addr(1), start + Duration::from_secs(1)), RateDecision::Drop);
+        assert_eq!(table.check(addr(1), start + Duration::from_secs(2)), RateDecision::Pass);
+        assert_eq!(table.check(addr(1), start + Duration::from_secs(2)), RateDecision::Drop);
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(table.check(addr(1), later), RateDecision::Pass);
+        }
+        assert_eq!(table.check(addr(1), later), RateDecision::Drop);
+
+        let stats = table.stats();
+        assert_eq!((stats.passed, stats.dropped, stats.kod, stats.clients), (8, 4, 0, 2));
+    }
+
+    #[test]
+    fn kisses_are_limited() {
+        let start = Instant::now();
+        let mut table = Table::new(config(true, 8), start);
+        for _ in 0..3 {
+            table.check(addr(1), start);
+        }
+        assert_eq!(table.check(addr(1), start), RateDecision::Kod);
+        assert_eq!(table.check(addr(1), start), RateDecision::Drop);
+        assert_eq!(table.check(addr(1), start + Duration::from_millis(1500)), RateDecision::Drop);
+
+        // A kiss again one interval after the last, when still over the limit.
+        let next = start + Duration::from_millis(2500);
+        assert_eq!(table.check(addr(1), next), RateDecision::Pass);
+        assert_eq!(table.check(addr(1), next), RateDecision::Kod);
+        assert_eq!(table.stats().kod, 2);
+    }
+
+    #[test]
+    fn full_table_overflows_into_shared_bucket() {
+        let start = Instant::now();
+        let mut table = Table::new(config(false, 1), start);
+        assert_eq!(table.check(addr(1), start), RateDecision::Pass);
+        for last in 2..5 {
+            assert_eq!(table.check(addr(last), start), RateDecision::Pass);
+        }
+        assert_eq!(table.check(addr(5), start), RateDecision::Drop);
+        assert_eq!(table.stats().overflow, 4);
+
+        // Once its bucket refilled, the tracked client is purged for a new one.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(table.check(addr(5), later), RateDecision::Pass);
+        assert_eq!(table.stats().overflow, 4);
+        assert_eq!(table.stats().clients, 1);
+    }
+
+    #[test]
+    fn disabled_passes_everything() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(RateLimitConfig { enable: false, ..config(true, 8) }, start);
+        for _ in 0..100 {
+            assert_eq!(limiter.check(addr(1), start), RateDecision::Pass);
+        }
+        limiter.set_config(config(true, 8), start);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(addr(1), start), RateDecision::Pass);
+        }
+        assert_eq!(limiter.check(addr(1), start), RateDecision::Kod);
+
+        let stats = limiter.stats();
+        assert_eq!((stats.passed, stats.kod, stats.clients), (103, 1, 1));
+    }
+}
@@ -1,5 +1,4 @@
 use crate::ntp::timestamp::{self, Timestamp};
-use chrono::{TimeZone, Utc};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 use std::env;
@@ -27,10 +26,11 @@ pub struct MonitorSender {
 
 impl MonitorSender {
     pub async fn print_oled(&self) -> Result<()> {
-        let datetime = Utc.timestamp(self.actial.ts as i64, 0);
+        let actual = self.actial.date();
+        let datetime = actual.to_datetime();
         let banch = OledPacket {
-            gps: format!(" {} sec ago", self.actial.ts - self.last_gps.ts),
-            ntp: format!(" {} sec ago", self.actial.ts - self.last_ntp.ts),
+            gps: format!(" {:.0} sec ago", actual.diff_to_sec(&self.last_gps.date())),
+            ntp: format!(" {:.0} sec ago", actual.diff_to_sec(&self.last_ntp.date())),
             time: format!(" {}", datetime),
         };
         let host = env::var("DISPLAY_HOST")
@@ -86,7 +86,7 @@ impl MonitorSender {
         if enable {
             let banch = CmdPacket {
                 cmd: String::from("set"),
-                ts: format!("{:?}", self.actial.ts),
+                ts: format!("{:.3}", self.actial.to_unix_secs()),
             };
             let mut stream = TcpStream::connect(server_address).await.unwrap();
 
@@ -123,7 +123,7 @@ impl MonitorSender {
         if enable {
             let banch = CmdPacket {
                 cmd: String::from("get"),
-                ts: format!("{:?}", self.actial.ts),
+                ts: format!("{:.3}", self.actial.to_unix_secs()),
             };
             let mut stream = TcpStream::connect(server_address).await.unwrap();
 
@@ -143,9 +143,4 @@ impl MonitorSender {
             Ok(0)
         }
     }
-    pub  async fn get_json(&self)->Result<String>{
-
-
-        Ok(serde_json::to_string_pretty(&self).unwrap())
-    } 
 }
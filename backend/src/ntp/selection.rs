@@ -0,0 +1,396 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+// Clustering stops once this many survivors are left (RFC 5905 NMIN).
+const MIN_SURVIVORS: usize = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, ToSchema)]
+pub enum CandidateStatus {
+    /// Not eligible: not qualified yet, stale or unsynchronized.
+    Rejected,
+    /// Outside the intersection interval.
+    Falseticker,
+    /// Dropped by the clustering algorithm.
+    Outlier,
+    /// Passed clustering, contributes to the combined offset when it has
+    /// the system peer's priority. Fallbacks stand by with this status.
+    Survivor,
+    /// Survivor chosen as system peer.
+    SystemPeer,
+}
+
+/// A source as seen by the selection algorithms, all values in seconds.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Candidate {
+    pub name: String,
+    pub offset: f64,
+    pub root_distance: f64,
+    pub jitter: f64,
+    pub stratum: u8,
+    pub priority: u8,
+    /// Coarse source like ntpd's local clock, kept out of intersection and
+    /// clustering and only selected when no other candidate survives.
+    pub fallback: bool,
+    pub status: CandidateStatus,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Selection {
+    /// Index of the system peer in `candidates`.
+    pub system_peer: Option<usize>,
+    /// Weighted offset of the survivors sharing the system peer's priority.
+    pub offset: f64,
+    pub jitter: f64,
+    pub candidates: Vec<Candidate>,
+}
+
+/// Runs intersection, clustering and combining over the candidates whose
+/// status is not `Rejected` (RFC 5905 section 11.2).
+pub fn select(mut candidates: Vec<Candidate>) -> Selection {
+    let (fallbacks, eligible): (Vec<usize>, Vec<usize>) = (0..candidates.len())
+        .filter(|&i| candidates[i].status != CandidateStatus::Rejected)
+        .partition(|&i| candidates[i].fallback);
+
+    let truechimers = intersect(&candidates, &eligible);
+    for &i in &eligible {
+        candidates[i].status = if truechimers.contains(&i) {
+            CandidateStatus::Survivor
+        } else {
+            CandidateStatus::Falseticker
+        };
+    }
+
+    let mut survivors = cluster(&mut candidates, truechimers);
+    if survivors.is_empty() {
+        survivors = fallbacks;
+    }
+    if survivors.is_empty() {
+        return Selection {
+            system_peer: None,
+            offset: 0.0,
+            jitter: 0.0,
+            candidates,
+        };
+    }
+
+    // Lower priority value wins, then the smaller root distance.
+    let system_peer = *survivors
+        .iter()
+        .min_by(|&&a, &&b| {
+            let (a, b) = (&candidates[a], &candidates[b]);
+            a.priority
+                .cmp(&b.priority)
+                .then(a.root_distance.total_cmp(&b.root_distance))
+        })
+        .unwrap();
+    candidates[system_peer].status = CandidateStatus::SystemPeer;
+
+    // Lower priority sources only back up the system peer, a coarse RTC
+    // inside the intersection must not pull the offset away from GPS.
+    let priority = candidates[system_peer].priority;
+    let combined: Vec<usize> = survivors
+        .into_iter()
+        .filter(|&i| candidates[i].priority == priority)
+        .collect();
+    let (offset, jitter) = combine(&candidates, &combined, system_peer);
+    Selection {
+        system_peer: Some(system_peer),
+        offset,
+        jitter,
+        candidates,
+    }
+}
+
+// Marzullo-style intersection: find the smallest number of falsetickers f
+// such that at least n - f correctness intervals overlap, and keep the
+// candidates whose interval touches that overlap (as ntpd does).
+fn intersect(candidates: &[Candidate], eligible: &[usize]) -> Vec<usize> {
+    let n = eligible.len();
+    if n == 0 {
+        return Vec::new();
+    }
+
+    // (edge, kind) with kind -1 for a lower and +1 for an upper edge.
+    let mut edges: Vec<(f64, i32)> = Vec::with_capacity(n * 2);
+    for &i in eligible {
+        let c = &candidates[i];
+        edges.push((c.offset - c.root_distance, -1));
+        edges.push((c.offset + c.root_distance, 1));
+    }
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let mut allowed = 0;
+    while 2 * allowed < n {
+        let needed = (n - allowed) as i32;
+
+        let mut low = None;
+        let mut count = 0;
+        for &(edge, kind) in &edges {
+            count -= kind;
+            if count >= needed {
+                low = Some(edge);
+                break;
+            }
+        }
+        let mut high = None;
+        count = 0;
+        for &(edge, kind) in edges.iter().rev() {
+            count += kind;
+            if count >= needed {
+                high = Some(edge);
+                break;
+            }
+        }
+
+        if let (Some(low), Some(high)) = (low, high) {
+            if low <= high {
+                return eligible
+                    .iter()
+                    .copied()
+                    .filter(|&i| {
+                        let c = &candidates[i];
+                        c.offset + c.root_distance >= low && c.offset - c.root_distance <= high
+                    })
+                    .collect();
+            }
+        }
+        allowed += 1;
+    }
+    Vec::new()
+}
+
+// Repeatedly drops the survivor with the largest selection jitter while
+// that jitter exceeds the smallest peer jitter.
+fn cluster(candidates: &mut [Candidate], mut survivors: Vec<usize>) -> Vec<usize> {
+    while survivors.len() > MIN_SURVIVORS {
+        let mut worst = 0;
+        let mut worst_jitter = f64::MIN;
+        for (pos, &i) in survivors.iter().enumerate() {
+            let jitter = selection_jitter(candidates, &survivors, i);
+            if jitter > worst_jitter {
+                worst = pos;
+                worst_jitter = jitter;
+            }
+        }
+        let min_peer_jitter = survivors
+            .iter()
+            .map(|&i| candidates[i].jitter)
+            .fold(f64::MAX, f64::min);
+        if worst_jitter <= min_peer_jitter {
+            break;
+        }
+        let removed = survivors.remove(worst);
+        candidates[removed].status = CandidateStatus::Outlier;
+    }
+    survivors
+}
+
+fn selection_jitter(candidates: &[Candidate], survivors: &[usize], index: usize) -> f64 {
+    if survivors.len() < 2 {
+        return 0.0;
+    }
+    let offset = candidates[index].offset;
+    let sum: f64 = survivors
+        .iter()
+        .map(|&j| (candidates[j].offset - offset).powi(2))
+        .sum();
+    (sum / (survivors.len() - 1) as f64).sqrt()
+}
+
+// Offsets are averaged with weights 1 / root distance; the system jitter
+// combines the system peer's jitter with the spread of the survivors.
+fn combine(candidates: &[Candidate], survivors: &[usize], system_peer: usize) -> (f64, f64) {
+    let mut weights = 0.0;
+    let mut offset = 0.0;
+    for &i in survivors {
+        let weight = 1.0 / candidates[i].root_distance.max(1e-9);
+        weights += weight;
+        offset += weight * candidates[i].offset;
+    }
+    offset /= weights;
+
+    let peer = &candidates[system_peer];
+    let mut spread = 0.0;
+    for &i in survivors {
+        let weight = 1.0 / candidates[i].root_distance.max(1e-9);
+        spread += weight * (candidates[i].offset - peer.offset).powi(2);
+    }
+    let jitter = (peer.jitter.powi(2) + spread / weights).sqrt();
+    (offset, jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CandidateStatus::*;
+
+    // offset, root distance, jitter, priority, rejected
+    type Input = (f64, f64, f64, u8, bool);
+
+    struct Case {
+        name: &'static str,
+        input: Vec<Input>,
+        status: Vec<CandidateStatus>,
+        system_peer: Option<usize>,
+        offset: f64,
+        jitter: f64,
+    }
+
+    fn candidates(input: &[Input]) -> Vec<Candidate> {
+        input
+            .iter()
+            .enumerate()
+            .map(|(i, &(offset, root_distance, jitter, priority, rejected))| Candidate {
+                name: format!("source {}", i),
+                offset,
+                root_distance,
+                jitter,
+                stratum: 1,
+                priority,
+                fallback: false,
+                status: if rejected { Rejected } else { Survivor },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn select_cases() {
+        let cases = [
+            Case {
+                name: "nothing eligible",
+                input: vec![(0.0, 0.01, 0.001, 1, true)],
+                status: vec![Rejected],
+                system_peer: None,
+                offset: 0.0,
+                jitter: 0.0,
+            },
+            Case {
+                // Three intervals meet in [-0.008, 0.008], the fourth is far off.
+                name: "falseticker",
+                input: vec![
+                    (0.0, 0.01, 0.001, 1, false),
+                    (0.002, 0.01, 0.001, 1, false),
+                    (-0.002, 0.01, 0.001, 1, false),
+                    (1.0, 0.01, 0.001, 1, false),
+                ],
+                status: vec![SystemPeer, Survivor, Survivor, Falseticker],
+                system_peer: Some(0),
+                offset: 0.0,
+                // sqrt(0.001^2 + (0.002^2 + 0.002^2) / 3)
+                jitter: (1e-6 + 8e-6 / 3.0f64).sqrt(),
+            },
+            Case {
+                name: "no majority",
+                input: vec![(0.0, 0.01, 0.001, 1, false), (1.0, 0.01, 0.001, 1, false)],
+                status: vec![Falseticker, Falseticker],
+                system_peer: None,
+                offset: 0.0,
+                jitter: 0.0,
+            },
+            Case {
+                name: "rejected sources do not vote",
+                input: vec![
+                    (0.0, 0.01, 0.001, 1, false),
+                    (1.0, 0.01, 0.001, 1, true),
+                    (1.0, 0.01, 0.001, 1, true),
+                ],
+                status: vec![SystemPeer, Rejected, Rejected],
+                system_peer: Some(0),
+                offset: 0.0,
+                jitter: 0.001,
+            },
+            Case {
+                // All three intervals share [-0.0003, 0.0007]; GPS wins on
+                // priority and neither NTP nor the RTC moves the offset.
+                name: "priority before distance",
+                input: vec![
+                    (0.3, 0.5, 0.1, 3, false),
+                    (0.0002, 0.0005, 0.0001, 2, false),
+                    (0.0, 0.001, 0.00001, 1, false),
+                ],
+                status: vec![Survivor, Survivor, SystemPeer],
+                system_peer: Some(2),
+                offset: 0.0,
+                jitter: 0.00001,
+            },
+            Case {
+                // Weights 100 and 50: (100 * 0.001 + 50 * 0.004) / 150.
+                name: "weighted by root distance",
+                input: vec![(0.004, 0.02, 0.001, 2, false), (0.001, 0.01, 0.001, 2, false)],
+                status: vec![Survivor, SystemPeer],
+                system_peer: Some(1),
+                offset: 0.002,
+                // sqrt(0.001^2 + 50 * 0.003^2 / 150)
+                jitter: 0.002,
+            },
+            Case {
+                // 0.02 has the largest selection jitter, then -0.001
+                // (sqrt(7.25e-6 / 3) against sqrt(5.25e-6 / 3) for 0.001).
+                name: "clustering",
+                input: vec![
+                    (0.0, 0.05, 0.0001, 1, false),
+                    (0.001, 0.05, 0.0001, 1, false),
+                    (-0.001, 0.05, 0.0001, 1, false),
+                    (0.0005, 0.05, 0.0001, 1, false),
+                    (0.02, 0.05, 0.0001, 1, false),
+                ],
+                status: vec![SystemPeer, Survivor, Outlier, Survivor, Outlier],
+                system_peer: Some(0),
+                offset: 0.0005,
+                // sqrt(0.0001^2 + (0.001^2 + 0.0005^2) / 3)
+                jitter: (1e-8 + 1.25e-6 / 3.0f64).sqrt(),
+            },
+        ];
+
+        for case in cases {
+            let selection = select(candidates(&case.input));
+            let status: Vec<_> = selection.candidates.iter().map(|c| c.status).collect();
+            assert_eq!(status, case.status, "{}", case.name);
+            assert_eq!(selection.system_peer, case.system_peer, "{}", case.name);
+            assert!((selection.offset - case.offset).abs() < 1e-12, "{}: offset {}", case.name, selection.offset);
+            assert!((selection.jitter - case.jitter).abs() < 1e-12, "{}: jitter {}", case.name, selection.jitter);
+        }
+    }
+
+    #[test]
+    fn coarse_fallback() {
+        // A locked GPS and an RTC 1.5 s off do not intersect, yet GPS must
+        // stay selected.
+        let mut input = candidates(&[(0.0, 0.001, 0.00001, 1, false), (1.5, 0.5, 0.1, 3, false)]);
+        input[1].fallback = true;
+        let selection = select(input.clone());
+        let status: Vec<_> = selection.candidates.iter().map(|c| c.status).collect();
+        assert_eq!(status, [SystemPeer, Survivor]);
+        assert_eq!((selection.offset, selection.jitter), (0.0, 0.00001));
+
+        // Without GPS the RTC stands in.
+        input[0].status = Rejected;
+        let selection = select(input.clone());
+        assert_eq!(selection.system_peer, Some(1));
+        assert_eq!((selection.offset, selection.jitter), (1.5, 0.1));
+
+        // So it does when the other sources are all falsetickers.
+        let mut input = candidates(&[(0.0, 0.01, 0.001, 2, false), (1.0, 0.01, 0.001, 2, false), (1.5, 0.5, 0.1, 3, false)]);
+        input[2].fallback = true;
+        let selection = select(input);
+        let status: Vec<_> = selection.candidates.iter().map(|c| c.status).collect();
+        assert_eq!(status, [Falseticker, Falseticker, SystemPeer]);
+    }
+
+    #[test]
+    fn intersection_keeps_touching_intervals() {
+        // Four intervals meet only at 0.0; the one ending there still counts,
+        // the one starting at 0.011 does not.
+        let input = [
+            (0.005, 0.005, 0.0, 1, false),
+            (0.01, 0.01, 0.0, 1, false),
+            (-0.01, 0.01, 0.0, 1, false),
+            (0.021, 0.01, 0.0, 1, false),
+            (0.0, 0.03, 0.0, 1, false),
+        ];
+        let candidates = candidates(&input);
+        assert_eq!(intersect(&candidates, &[0, 1, 2, 3, 4]), [0, 1, 2, 4]);
+        assert_eq!(intersect(&candidates, &[3]), [3]);
+        assert!(intersect(&candidates, &[]).is_empty());
+    }
+}
@@ -1,120 +1,770 @@
 
-use std::sync::Arc;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 
 
 use tokio::sync::Mutex;
 use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
 
 
 
 use super::NtpPacket;
-use super::NtpServerState;
+use super::{NtpServerState, NtpStateCell};
 use super::NtpTimestamp;
 use super::NtpFracValue;
+use super::NtpSample;
+use super::NtpClockModel;
+use super::{NtpSource, NtpSources};
+use super::{NtpSyncMachine, NtpSyncStatus, NtpServerStatus, NtpServerConfig};
+use super::NtpHoldover;
+use super::{NtpAuth, NtpMac};
+use super::auth::AuthStatus;
+use super::nts::{self, CookieJar, NtsStatus};
+use super::acl::{Acl, AclAction};
+use super::listen::{self, BindStatus, ListenConfig};
+use super::broadcast::{BroadcastConfig, Broadcaster};
+use super::control::{self, ControlRequest, Snapshot};
+use super::cmdmon::{self, CommandRequest, CommandStats};
+use super::codec::{ParseErrorCounters, ParseErrorStats};
+use super::interleaved::TimestampLog;
+use super::local_clock::Clock;
+use super::packet::MAX_PACKET_LEN;
+use super::timestamping::{self, TimestampMode, TimestampedSocket};
+use super::rate_limit::{RateDecision, RateLimitConfig, RateLimiter, RateStats};
+use super::source::{PHI, UNSYNC_STRATUM};
+use super::selection::Selection;
 
 pub struct Server {
-    state: Arc<Mutex<NtpServerState>>,
-    sockets: Arc<Mutex<Vec<Arc<Mutex<UdpSocket>>>>>,
+    state: Arc<NtpStateCell>,
+    sources: Arc<Mutex<NtpSources>>,
+    sync: Arc<Mutex<NtpSyncMachine>>,
+    listeners: Arc<Mutex<Listeners>>,
+    cmdmon: Arc<Mutex<Listeners>>,
+    commands: Arc<Mutex<CommandStats>>,
+    timestamps: Arc<TimestampLog>,
+    parse_errors: Arc<ParseErrorCounters>,
+    broadcaster: Mutex<Broadcaster>,
+    auth: Arc<RwLock<NtpAuth>>,
+    nts: Option<Arc<RwLock<CookieJar>>>,
+    rate_limiter: Arc<RateLimiter>,
+    acl: Arc<RwLock<Acl>>,
+    clock: Arc<dyn Clock>,
     debug: bool,
+    unsync_silent: bool,
+}
+
+/// Bound sockets with the tasks serving them.
+struct Listeners {
+    config: ListenConfig,
+    active: Vec<Listener>,
+    failed: Vec<BindStatus>,
+    next_id: u32,
+}
+
+struct Listener {
+    entry: String,
+    addr: SocketAddr,
+    timestamping: Option<TimestampMode>,
+    /// One task per worker socket.
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// Shared handles every request processing task works with. What NTP
+/// requests touch is read-locked or sharded by client, so workers do not
+/// serialize on it.
+#[derive(Clone)]
+pub struct RequestContext {
+    state: Arc<NtpStateCell>,
+    sources: Arc<Mutex<NtpSources>>,
+    sync: Arc<Mutex<NtpSyncMachine>>,
+    auth: Arc<RwLock<NtpAuth>>,
+    nts: Option<Arc<RwLock<CookieJar>>>,
+    rate_limiter: Arc<RateLimiter>,
+    acl: Arc<RwLock<Acl>>,
+    commands: Arc<Mutex<CommandStats>>,
+    timestamps: Arc<TimestampLog>,
+    parse_errors: Arc<ParseErrorCounters>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RequestContext {
+    /// Applies access control, rate limiting and authentication to a request
+    /// and builds the response, if any should be sent.
+    fn respond(&self, request: &NtpPacket, state: &NtpServerState) -> Option<NtpPacket> {
+        if !request.is_request() {
+            return None;
+        }
+
+        let action = self.acl.read().unwrap().lookup(&request.remote_addr.ip());
+        if action == AclAction::Deny {
+            debug!("Denied request from {}", request.remote_addr);
+            return None;
+        }
+
+        let decision = self.rate_limiter.check(request.remote_addr.ip(), self.clock.instant());
+        match decision {
+            RateDecision::Pass => {}
+            RateDecision::Kod => {
+                debug!("Sending RATE kiss to {}", request.remote_addr);
+                return request.make_kiss(state, b"RATE", &*self.clock);
+            }
+            RateDecision::Drop => return None,
+        }
+
+        if action == AclAction::KodDeny {
+            debug!("Sending DENY kiss to {}", request.remote_addr);
+            return request.make_kiss(state, b"DENY", &*self.clock);
+        }
+
+        let nts_status = match &self.nts {
+            Some(jar) => jar.read().unwrap().check(request),
+            None => NtsStatus::None,
+        };
+        let auth = self.auth.read().unwrap();
+        let status = auth.check(request);
+        if status == AuthStatus::None
+            && !matches!(nts_status, NtsStatus::Valid(_))
+            && (action == AclAction::RequireAuth || auth.is_required(&request.remote_addr.ip()))
+        {
+            debug!("Dropping unauthenticated request from {}", request.remote_addr);
+            return None;
+        }
+
+        let mut response = request.make_response(state, &*self.clock)?;
+        if let Some(tx_ts) = self.timestamps.previous_transmit(request) {
+            response.orig_ts = request.rx_ts;
+            response.tx_ts = tx_ts;
+        }
+        match status {
+            AuthStatus::Valid(key_id) => {
+                if let Some(key) = auth.key(key_id) {
+                    key.sign(&mut response);
+                }
+            }
+            AuthStatus::Invalid => {
+                debug!("Sending crypto-NAK to {}", request.remote_addr);
+                response.mac = Some(NtpMac::crypto_nak());
+            }
+            AuthStatus::None => {}
+        }
+        drop(auth);
+
+        if let Some(jar) = &self.nts {
+            match nts_status {
+                NtsStatus::Valid(nts_request) => jar.read().unwrap().seal(&mut response, &nts_request),
+                NtsStatus::Invalid(unique_id) => {
+                    debug!("Sending NTS NAK to {}", response.remote_addr);
+                    nts::nak(&mut response, unique_id);
+                }
+                NtsStatus::None => {}
+            }
+        }
+        Some(response)
+    }
+
+    /// Records when a response left, for interleaved replies to the next
+    /// request of the same client.
+    fn sent(&self, response: &NtpPacket, tx_ts: NtpTimestamp) {
+        if response.mode == 4 && response.stratum != 0 {
+            self.timestamps.record(response.remote_addr.ip(), response.rx_ts, tx_ts);
+        }
+    }
+
+    /// Monitoring queries are not authenticated, so only networks the access
+    /// rules explicitly allow may send them, within their rate limit.
+    fn may_query(&self, addr: SocketAddr) -> bool {
+        if !self.acl.read().unwrap().may_query(&addr.ip()) {
+            debug!("Refused monitoring query from {}", addr);
+            return false;
+        }
+        self.rate_limiter.check(addr.ip(), self.clock.instant()) == RateDecision::Pass
+    }
+
+    async fn snapshot(&self) -> Snapshot {
+        let status = Server::collect_status(&self.sources, &self.sync, &self.state, &*self.clock).await;
+        let sources = self.sources.lock().await.reports();
+        let state = self.state.load().state;
+        let local = self.clock.now();
+        Snapshot {
+            now: state.clock.convert(local),
+            local,
+            state,
+            status,
+            sources,
+        }
+    }
+
+    /// Answers a mode 6 control query.
+    async fn control(&self, buf: &[u8], addr: SocketAddr) -> Vec<Vec<u8>> {
+        if !self.may_query(addr) {
+            return vec![];
+        }
+
+        let request = match ControlRequest::parse(buf) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Ignoring control message from {}: {}", addr, e);
+                return vec![];
+            }
+        };
+        control::respond(&request, &self.snapshot().await)
+    }
+
+    /// Answers a chrony monitoring request.
+    async fn command(&self, buf: &[u8], addr: SocketAddr) -> Option<Vec<u8>> {
+        if !self.may_query(addr) {
+            self.commands.lock().await.drops += 1;
+            return None;
+        }
+
+        let request = match CommandRequest::parse(buf) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Ignoring command from {}: {}", addr, e);
+                return None;
+            }
+        };
+        let snapshot = self.snapshot().await;
+        let rate = self.rate_limiter.stats();
+        let mut commands = self.commands.lock().await;
+        commands.hits += 1;
+        cmdmon::respond(&request, &snapshot, &rate, &commands)
+    }
 }
 
 impl Server {
-    pub async fn new(local_addrs: Vec<String>, debug: bool) -> Server {
+    pub async fn new(
+        debug: bool,
+        config: NtpServerConfig,
+        auth: Arc<RwLock<NtpAuth>>,
+        nts: Option<Arc<RwLock<CookieJar>>>,
+        clock: Arc<dyn Clock>,
+    ) -> Server {
         let state = NtpServerState {
-            leap: 0,
-            stratum: 0,
+            leap: NtpSource::None.leap(),
+            stratum: NtpSource::None.stratum(),
             precision: 0,
-            ref_id: 0,
+            ref_id: NtpSource::None.ref_id(),
             ref_ts: NtpTimestamp::zero(),
             dispersion: NtpFracValue::zero(),
             delay: NtpFracValue::zero(),
+            clock: NtpClockModel::new(),
+            sync: NtpSyncStatus::Unsynchronized,
+            dispersion_rate: PHI,
         };
 
-        let mut sockets = vec![];
-
-        for addr in local_addrs {
-            let socket = UdpSocket::bind(format!("{}:{}", addr, 123)).await.unwrap();
-            debug!("{:?}", socket);
-            sockets.push(Arc::new(Mutex::new(socket)));
-        }
-
         Server {
-            state: Arc::new(Mutex::new(state)),
-            sockets: Arc::new(Mutex::new(sockets)),
+            state: Arc::new(NtpStateCell::new(state)),
+            sources: Arc::new(Mutex::new(NtpSources::new(config.priority, Arc::clone(&clock)))),
+            sync: Arc::new(Mutex::new(NtpSyncMachine::new(
+                config.holdover_timeout,
+                NtpHoldover::new(config.holdover_drift, config.holdover_max_error),
+                Arc::clone(&clock),
+            ))),
+            listeners: Arc::new(Mutex::new(Listeners {
+                config: config.listen,
+                active: vec![],
+                failed: vec![],
+                next_id: 0,
+            })),
+            cmdmon: Arc::new(Mutex::new(Listeners {
+                config: config.cmdmon,
+                active: vec![],
+                failed: vec![],
+                next_id: 0,
+            })),
+            commands: Arc::new(Mutex::new(CommandStats::default())),
+            timestamps: Arc::new(TimestampLog::new(config.interleaved)),
+            parse_errors: Arc::new(ParseErrorCounters::default()),
+            broadcaster: Mutex::new(Broadcaster::new(config.broadcast)),
+            auth,
+            nts,
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limit, clock.instant())),
+            acl: Arc::new(RwLock::new(config.acl)),
+            clock,
             debug: debug,
+            unsync_silent: config.unsync_silent,
         }
     }
 
     pub async fn process_requests(
         thread_id: u32,
         debug: bool,
-        socket: Arc<Mutex<UdpSocket>>,
-        state: Arc<Mutex<NtpServerState>>,
+        unsync_silent: bool,
+        mut socket: TimestampedSocket,
+        context: RequestContext,
     ) {
-        let mut last_update = NtpTimestamp::now();
-        let mut cached_state: NtpServerState;
-        cached_state = *state.lock().await;
+        let mut version = context.state.load().version;
 
-        info!("Server thread #{} started", thread_id);
-        let socket = socket.lock().await;
+        info!("Server thread #{} started with {} timestamping", thread_id, socket.mode());
         loop {
-            match NtpPacket::receive(&socket).await {
-                Ok(request) => {
-                    if debug {
-                        info!("Thread #{} received {:?}", thread_id, request);
+            let datagrams = match socket.recv_batch().await {
+                Ok(datagrams) => datagrams,
+                Err(e) => {
+                    error!("Thread #{} failed to receive packet: {}", thread_id, e);
+                    continue;
+                }
+            };
+
+            // Replies of the whole batch go out together, NTP responses are
+            // kept for the interleaved log.
+            let mut replies = vec![];
+            let mut responses = vec![];
+            for datagram in datagrams {
+                if control::is_control(datagram.data) {
+                    for fragment in context.control(datagram.data, datagram.addr).await {
+                        replies.push((fragment, datagram.addr));
+                        responses.push(None);
                     }
+                    continue;
+                }
 
-                    if request.local_ts.diff_to_sec(&last_update).abs() > 0.1 {
-                        cached_state = *state.lock().await;
-                        last_update = request.local_ts;
+                let request = match NtpPacket::decode(datagram.data, datagram.addr, datagram.local_ts) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        context.parse_errors.count(e);
                         if debug {
-                            info!("Thread #{} updated its state", thread_id);
+                            debug!("Thread #{} dropped packet from {}: {}", thread_id, datagram.addr, e);
                         }
+                        continue;
                     }
+                };
+                if debug {
+                    trace!("Thread #{} received {:?}", thread_id, request);
+                }
+
+                let current = context.state.load();
+                if debug && current.version != version {
+                    trace!("Thread #{} sees state version {}", thread_id, current.version);
+                }
+                version = current.version;
+
+                if unsync_silent && current.state.sync == NtpSyncStatus::Unsynchronized {
+                    continue;
+                }
 
-                    match request.make_response(&cached_state) {
-                        Some(response) => match response.send(&socket).await {
-                            Ok(_) => {
-                                info!("Thread #{} sent {:?}", thread_id, response);
-                            }
-                            Err(e) => error!(
-                                "Thread #{} failed to send packet to {}: {}",
-                                thread_id, response.remote_addr, e
-                            ),
-                        },
-                        None => {}
+                if let Some(response) = context.respond(&request, &current.state) {
+                    replies.push((response.encode(), response.remote_addr));
+                    responses.push(Some(response));
+                }
+            }
+            if replies.is_empty() {
+                continue;
+            }
+
+            let results = socket.send_batch(&replies).await;
+            let state = context.state.load().state;
+            for ((result, (_, addr)), response) in results.into_iter().zip(&replies).zip(responses) {
+                match (result, response) {
+                    (Ok(tx_ts), Some(response)) => {
+                        context.sent(&response, state.clock.convert(tx_ts));
+                        trace!("Thread #{} sent {:?}", thread_id, response);
                     }
+                    (Ok(_), None) => {}
+                    (Err(e), _) => error!("Thread #{} failed to send packet to {}: {}", thread_id, addr, e),
                 }
+            }
+        }
+    }
+
+    pub async fn process_commands(socket: UdpSocket, context: RequestContext) {
+        let mut buf = [0; MAX_PACKET_LEN];
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
                 Err(e) => {
-                    error!("Thread #{} failed to receive packet: {}", thread_id, e);
+                    error!("Failed to receive command: {}", e);
+                    continue;
+                }
+            };
+            if let Some(reply) = context.command(&buf[..len], addr).await {
+                if let Err(e) = socket.send_to(&reply, addr).await {
+                    error!("Failed to send command reply to {}: {}", addr, e);
+                }
+            }
+        }
+    }
+
+    /// Feeds a sample from any source; the clock only follows the combined
+    /// offset of the selected sources, and only when the system peer itself
+    /// delivered a new sample. GPS updates also train the holdover frequency.
+    pub async fn update_state(&mut self, sample: NtpSample) {
+        self.sources.lock().await.seen(sample);
+        let selected = Server::reselect(&self.sources, &self.sync, &self.state, &*self.clock).await;
+        if !sample.source.same_as(&selected) {
+            return;
+        }
+
+        let (offset, jitter) = {
+            let sources = self.sources.lock().await;
+            let selection = sources.selection();
+            (selection.offset, selection.jitter)
+        };
+        let frequency = {
+            let mut state = self.state.lock().await;
+            let reference = sample.local_ts.add_sec(offset);
+            if sample.source == NtpSource::Rtc {
+                state.clock.coarse_update(reference, sample.local_ts);
+            } else {
+                state.clock.update(reference, sample.local_ts);
+            }
+            let jitter = (jitter.powi(2) + state.clock.jitter().powi(2)).sqrt();
+            state.set_reference(reference, sample.quality, jitter);
+            state.clock.frequency()
+        };
+        if sample.source == NtpSource::Gps {
+            self.sync.lock().await.learn(frequency);
+        }
+    }
+
+    /// Current time of the served timescale.
+    pub async fn now(&self) -> NtpTimestamp {
+        self.state.load().state.clock.convert(self.clock.now())
+    }
+
+    /// Keys and authentication policy, shared with the client.
+    pub fn auth(&self) -> Arc<RwLock<NtpAuth>> {
+        Arc::clone(&self.auth)
+    }
+
+    pub async fn selection(&self) -> Selection {
+        self.sources.lock().await.selection().clone()
+    }
+
+    async fn reselect(
+        sources: &Mutex<NtpSources>,
+        sync: &Mutex<NtpSyncMachine>,
+        state: &NtpStateCell,
+        clock: &dyn Clock,
+    ) -> NtpSource {
+        let (selected, distance) = {
+            let mut sources = sources.lock().await;
+            let selected = sources.select();
+            let selection = sources.selection();
+            let distance = selection
+                .system_peer
+                .map_or(0.0, |index| selection.candidates[index].root_distance);
+            (selected, distance)
+        };
+        let mut sync = sync.lock().await;
+        let previous = sync.status();
+        let source = sync.advance(selected, distance);
+        let mut state = state.lock().await;
+
+        if previous != NtpSyncStatus::Holdover && sync.status() == NtpSyncStatus::Holdover {
+            if let Some(frequency) = sync.holdover().frequency() {
+                info!("Extrapolating with learned frequency {:.3} ppm", frequency * 1e6);
+                state.clock.set_frequency(frequency, clock.now());
+            }
+        }
+
+        let mut stratum = source.stratum();
+        if sync.is_degraded() {
+            stratum = (stratum + 1).min(UNSYNC_STRATUM - 1);
+            if state.stratum != stratum && state.ref_id == source.ref_id() {
+                warn!("Holdover error above threshold, advertising stratum {}", stratum);
+            }
+        }
+        if state.ref_id != source.ref_id() {
+            info!("Time source changed to {:?}", source);
+        }
+        state.leap = source.leap();
+        state.stratum = stratum;
+        state.ref_id = source.ref_id();
+        state.sync = sync.status();
+        state.dispersion_rate = match sync.status() {
+            NtpSyncStatus::Holdover => sync.holdover().dispersion_rate(),
+            _ => PHI,
+        };
+        // In holdover nothing is followed, not even an RTC standing by.
+        if sync.status() == NtpSyncStatus::Synchronized {
+            selected
+        } else {
+            NtpSource::None
+        }
+    }
+
+    pub async fn rate_stats(&self) -> RateStats {
+        self.rate_limiter.stats()
+    }
+
+    /// Packets dropped as malformed since start.
+    pub fn parse_errors(&self) -> ParseErrorStats {
+        self.parse_errors.stats()
+    }
+
+    pub async fn set_rate_limit(&self, config: RateLimitConfig) {
+        self.rate_limiter.set_config(config, self.clock.instant());
+    }
+
+    pub async fn set_acl(&self, acl: Acl) {
+        *self.acl.write().unwrap() = acl;
+    }
+
+    pub async fn status(&self) -> NtpServerStatus {
+        Server::collect_status(&self.sources, &self.sync, &self.state, &*self.clock).await
+    }
+
+    async fn collect_status(
+        sources: &Mutex<NtpSources>,
+        sync: &Mutex<NtpSyncMachine>,
+        state: &NtpStateCell,
+        clock: &dyn Clock,
+    ) -> NtpServerStatus {
+        let (offset, jitter) = {
+            let sources = sources.lock().await;
+            let selection = sources.selection();
+            (selection.offset, selection.jitter)
+        };
+        let sync = sync.lock().await;
+        let state = state.load().state;
+
+        NtpServerStatus {
+            sync: sync.status(),
+            source: sync.advertised(),
+            stratum: state.stratum,
+            leap: state.leap,
+            offset,
+            jitter,
+            root_delay: state.delay.to_secs(),
+            root_dispersion: state.root_dispersion(&state.clock.convert(clock.now())).to_secs(),
+            holdover_secs: sync.holdover_secs(),
+            holdover_error: sync.holdover_error(),
+            holdover_frequency_ppm: sync.holdover().frequency().map(|f| f * 1e6),
+        }
+    }
+
+    fn context(&self) -> RequestContext {
+        RequestContext {
+            state: Arc::clone(&self.state),
+            sources: Arc::clone(&self.sources),
+            sync: Arc::clone(&self.sync),
+            auth: Arc::clone(&self.auth),
+            nts: self.nts.clone(),
+            rate_limiter: Arc::clone(&self.rate_limiter),
+            acl: Arc::clone(&self.acl),
+            commands: Arc::clone(&self.commands),
+            timestamps: Arc::clone(&self.timestamps),
+            parse_errors: Arc::clone(&self.parse_errors),
+            clock: Arc::clone(&self.clock),
+        }
+    }
+
+    /// Binds the configured NTP addresses.
+    pub async fn set_listen(&self, config: ListenConfig) -> Vec<BindStatus> {
+        let debug = self.debug;
+        let unsync_silent = self.unsync_silent;
+        let context = self.context();
+        let mut listeners = self.listeners.lock().await;
+        Server::rebind(&mut listeners, config, true, |id, socket, mode| {
+            let context = context.clone();
+            let socket = TimestampedSocket::new(socket, mode, Arc::clone(&context.clock));
+            tokio::spawn(async move {
+                Server::process_requests(id, debug, unsync_silent, socket, context).await;
+            })
+        })
+        .await
+    }
+
+    /// Binds the addresses chrony's monitoring protocol is served on.
+    pub async fn set_cmdmon(&self, config: ListenConfig) -> Vec<BindStatus> {
+        let context = self.context();
+        let mut listeners = self.cmdmon.lock().await;
+        Server::rebind(&mut listeners, config, false, |_, socket, _| {
+            tokio::spawn(Server::process_commands(socket, context.clone()))
+        })
+        .await
+    }
+
+    /// Sockets whose address is still listed keep running, sockets no longer
+    /// listed are closed, and addresses that failed before are tried again.
+    /// With `timestamping` new sockets get kernel timestamps where available.
+    async fn rebind(
+        listeners: &mut Listeners,
+        config: ListenConfig,
+        timestamping: bool,
+        spawn: impl Fn(u32, UdpSocket, TimestampMode) -> JoinHandle<()>,
+    ) -> Vec<BindStatus> {
+        let resolved = listen::resolve(&config);
+        let workers = config.workers();
+
+        let mut kept = vec![];
+        for listener in listeners.active.drain(..) {
+            let wanted = resolved.iter().any(|(_, result)| matches!(result, Ok(addr) if *addr == listener.addr));
+            let running = listener.tasks.len() == workers && listener.tasks.iter().all(|task| !task.is_finished());
+            if wanted && running {
+                kept.push(listener);
+            } else {
+                info!("Closing sockets {}", listener.addr);
+                for task in listener.tasks {
+                    task.abort();
+                    // Awaiting the aborted task releases its socket before rebinding.
+                    let _ = task.await;
                 }
             }
         }
+
+        let mut failed = vec![];
+        for (entry, result) in resolved {
+            let addr = match result {
+                Ok(addr) => addr,
+                Err(e) => {
+                    error!("Failed to resolve listen address {}: {}", entry, e);
+                    failed.push(BindStatus::failed(&entry, None, &e));
+                    continue;
+                }
+            };
+            if let Some(listener) = kept.iter_mut().find(|listener| listener.addr == addr) {
+                listener.entry = entry;
+                continue;
+            }
+            let sockets: io::Result<Vec<UdpSocket>> = (0..workers).map(|_| listen::bind(addr, workers > 1)).collect();
+            match sockets {
+                Ok(sockets) => {
+                    info!("Listening on {} with {} workers", addr, workers);
+                    let mut modes = vec![];
+                    let mut tasks = vec![];
+                    for socket in sockets {
+                        let mode = if timestamping {
+                            timestamping::enable(&socket)
+                        } else {
+                            TimestampMode::Userspace
+                        };
+                        listeners.next_id += 1;
+                        tasks.push(spawn(listeners.next_id, socket, mode));
+                        modes.push(mode);
+                    }
+                    let timestamping = modes.into_iter().min().filter(|_| timestamping);
+                    kept.push(Listener { entry, addr, timestamping, tasks });
+                }
+                Err(e) => {
+                    error!("Failed to bind socket {}: {}", addr, e);
+                    failed.push(BindStatus::failed(&entry, Some(addr), &e));
+                }
+            }
+        }
+
+        listeners.config = config;
+        listeners.active = kept;
+        listeners.failed = failed;
+        Server::bind_status(listeners)
     }
 
-    pub async fn update_state(&mut self, timestamp: NtpTimestamp) {
-        let mut state = self.state.lock().await;
+    pub async fn listen_status(&self) -> Vec<BindStatus> {
+        Server::bind_status(&*self.listeners.lock().await)
+    }
+
+    fn bind_status(listeners: &Listeners) -> Vec<BindStatus> {
+        listeners
+            .active
+            .iter()
+            .map(|listener| BindStatus::bound(&listener.entry, listener.addr, listener.timestamping))
+            .chain(listeners.failed.iter().cloned())
+            .collect()
+    }
 
-        state.ref_ts = timestamp;
+    /// Weakest timestamping among the NTP sockets, none when none is bound.
+    pub async fn timestamping(&self) -> Option<TimestampMode> {
+        self.listeners
+            .lock()
+            .await
+            .active
+            .iter()
+            .filter_map(|listener| listener.timestamping)
+            .min()
+    }
 
-        state.dispersion.increment();
+    /// Restarts broadcasting with a new configuration.
+    pub async fn set_broadcast(&self, config: BroadcastConfig) {
+        self.broadcaster
+            .lock()
+            .await
+            .start(config, Arc::clone(&self.state), Arc::clone(&self.auth), Arc::clone(&self.clock));
     }
 
     pub async fn run(&self) {
-        let mut threads = vec![];
-        let mut id = 0;
-        let quit = false;
+        let config = self.listeners.lock().await.config.clone();
+        self.set_listen(config).await;
+        let config = self.cmdmon.lock().await.config.clone();
+        self.set_cmdmon(config).await;
+        let config = self.broadcaster.lock().await.config().clone();
+        self.set_broadcast(config).await;
+
+        let sources = Arc::clone(&self.sources);
+        let sync = Arc::clone(&self.sync);
+        let state = Arc::clone(&self.state);
+        let clock = Arc::clone(&self.clock);
+        tokio::spawn(async move {
+            loop {
+                clock.sleep(Duration::from_secs(1)).await;
+                Server::reselect(&sources, &sync, &state, &*clock).await;
+            }
+        });
+    }
+}
 
-        for socket in self.sockets.lock().await.iter() {
-            id = id + 1;
-            let state = Arc::clone(&self.state);
-            let debug = self.debug;
-            let cloned_socket = Arc::clone(socket);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            threads.push(tokio::spawn(async move {
-                Server::process_requests(id, debug, cloned_socket, state).await;
-            }));
+    fn listeners(config: &ListenConfig) -> Listeners {
+        Listeners {
+            config: config.clone(),
+            active: vec![],
+            failed: vec![],
+            next_id: 0,
         }
     }
+
+    async fn rebind(listeners: &mut Listeners, config: &ListenConfig) -> Vec<BindStatus> {
+        Server::rebind(listeners, config.clone(), false, |_, socket, _| {
+            tokio::spawn(async move {
+                let _socket = socket;
+                std::future::pending::<()>().await;
+            })
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn rebinds_changed_addresses_only() {
+        let mut config = ListenConfig {
+            addresses: vec![String::from("127.0.0.1")],
+            port: 12_352,
+            workers: 0,
+        };
+        let mut listeners = listeners(&config);
+        let status = rebind(&mut listeners, &config).await;
+        assert!(status.iter().all(|status| status.error.is_none()));
+        let workers = config.workers() as u32;
+        assert_eq!(listeners.next_id, workers);
+
+        // Unchanged with workers resolved to the CPU count, nothing rebinds.
+        rebind(&mut listeners, &config).await;
+        assert_eq!(listeners.next_id, workers);
+
+        // An added address binds new sockets, the listed one keeps its own.
+        config.addresses.push(String::from("127.0.0.2"));
+        let status = rebind(&mut listeners, &config).await;
+        assert_eq!(status.len(), 2);
+        assert!(status.iter().all(|status| status.error.is_none()));
+        assert_eq!(listeners.next_id, 2 * workers);
+
+        // A removed address closes its sockets only.
+        config.addresses.remove(0);
+        let status = rebind(&mut listeners, &config).await;
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].address, "127.0.0.2:12352");
+        assert_eq!(listeners.next_id, 2 * workers);
+        assert!(listen::bind(SocketAddr::from(([127, 0, 0, 1], 12_352)), false).is_ok());
+
+        // A new worker count rebinds.
+        config.workers = workers as usize + 1;
+        rebind(&mut listeners, &config).await;
+        assert_eq!(listeners.next_id, 3 * workers + 1);
+    }
 }
@@ -1,4 +1,20 @@
-use super::{NtpTimestamp, NtpFracValue};
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use serde::Serialize;
+use tokio::sync::{Mutex, MutexGuard};
+
+use super::{NtpTimestamp, NtpFracValue, NtpClockModel, NtpSource, NtpSyncStatus};
+use super::NtpSampleQuality;
+use super::source::SourcePriority;
+use super::rate_limit::RateLimitConfig;
+use super::acl::Acl;
+use super::listen::ListenConfig;
+use super::broadcast::BroadcastConfig;
+
+// Root dispersion advertised before any reference has been seen.
+const MAX_DISPERSION: f64 = 16.0;
 
 #[derive(Copy, Clone)]
 pub struct ServerState {
@@ -9,4 +25,232 @@ pub struct ServerState {
     pub ref_ts: NtpTimestamp,
     pub dispersion: NtpFracValue,
     pub delay: NtpFracValue,
-}
\ No newline at end of file
+    pub clock: NtpClockModel,
+    pub sync: NtpSyncStatus,
+    /// Rate at which the root dispersion grows since the last update.
+    pub dispersion_rate: f64,
+}
+
+impl ServerState {
+    /// Records an update from the advertised source and recomputes the root
+    /// delay and dispersion from its error budget (RFC 5905 section 11.2).
+    /// `jitter` is the system jitter of the clock model.
+    pub fn set_reference(&mut self, ref_ts: NtpTimestamp, quality: NtpSampleQuality, jitter: f64) {
+        self.ref_ts = ref_ts;
+        self.delay = NtpFracValue::from_secs(quality.root_delay + quality.delay);
+        self.dispersion = NtpFracValue::from_secs(
+            quality.root_dispersion
+                + quality.dispersion
+                + (quality.jitter.powi(2) + jitter.powi(2)).sqrt(),
+        );
+    }
+
+    /// Root dispersion at `now`, grown at PHI since the last update, or at
+    /// the holdover rate once the source was lost.
+    pub fn root_dispersion(&self, now: &NtpTimestamp) -> NtpFracValue {
+        if self.ref_ts == NtpTimestamp::zero() {
+            return NtpFracValue::from_secs(MAX_DISPERSION);
+        }
+        let age = now.diff_to_sec(&self.ref_ts).max(0.0);
+        self.dispersion + NtpFracValue::from_secs(self.dispersion_rate * age)
+    }
+}
+
+/// Server state with the number of updates it has seen.
+#[derive(Copy, Clone)]
+pub struct VersionedState {
+    pub version: u64,
+    pub state: ServerState,
+}
+
+/// Server state shared by the request workers and the tasks updating it.
+/// Readers take a consistent copy without locking (seqlock), writers are
+/// serialized and publish all their changes at once when done.
+pub struct StateCell {
+    current: AtomicCell<VersionedState>,
+    writer: Mutex<()>,
+}
+
+impl StateCell {
+    pub fn new(state: ServerState) -> StateCell {
+        StateCell {
+            current: AtomicCell::new(VersionedState { version: 0, state }),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Latest published state, never blocks on writers.
+    pub fn load(&self) -> VersionedState {
+        self.current.load()
+    }
+
+    /// Starts an update, published when the guard is dropped.
+    pub async fn lock(&self) -> StateGuard<'_> {
+        let writer = self.writer.lock().await;
+        let current = self.current.load();
+        StateGuard {
+            cell: self,
+            _writer: writer,
+            version: current.version,
+            state: current.state,
+        }
+    }
+}
+
+pub struct StateGuard<'a> {
+    cell: &'a StateCell,
+    _writer: MutexGuard<'a, ()>,
+    version: u64,
+    state: ServerState,
+}
+
+impl Deref for StateGuard<'_> {
+    type Target = ServerState;
+
+    fn deref(&self) -> &ServerState {
+        &self.state
+    }
+}
+
+impl DerefMut for StateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut ServerState {
+        &mut self.state
+    }
+}
+
+impl Drop for StateGuard<'_> {
+    fn drop(&mut self) {
+        self.cell.current.store(VersionedState {
+            version: self.version + 1,
+            state: self.state,
+        });
+    }
+}
+
+/// Server synchronization state as reported by the REST API.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerStatus {
+    pub sync: NtpSyncStatus,
+    pub source: NtpSource,
+    pub stratum: u8,
+    pub leap: u8,
+    /// Combined offset of the selected sources against the system clock.
+    pub offset: f64,
+    pub jitter: f64,
+    pub root_delay: f64,
+    pub root_dispersion: f64,
+    pub holdover_secs: Option<u64>,
+    /// Estimated time error accumulated in holdover, in seconds.
+    pub holdover_error: Option<f64>,
+    /// Oscillator frequency error learned while locked to GPS, in ppm.
+    pub holdover_frequency_ppm: Option<f64>,
+}
+
+pub struct ServerConfig {
+    pub unsync_silent: bool,
+    /// Answer interleaved requests with the previous transmit time.
+    pub interleaved: bool,
+    pub holdover_timeout: Duration,
+    pub priority: SourcePriority,
+    /// Holdover error growth on top of the learned stability, in s/s.
+    pub holdover_drift: f64,
+    /// Holdover error in seconds after which the stratum is stepped down.
+    pub holdover_max_error: f64,
+    pub rate_limit: RateLimitConfig,
+    pub acl: Acl,
+    pub listen: ListenConfig,
+    /// Addresses for chrony's monitoring protocol, none when disabled.
+    pub cmdmon: ListenConfig,
+    pub broadcast: BroadcastConfig,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn state() -> ServerState {
+        ServerState {
+            leap: NtpSource::None.leap(),
+            stratum: NtpSource::None.stratum(),
+            precision: 0,
+            ref_id: NtpSource::None.ref_id(),
+            ref_ts: NtpTimestamp::zero(),
+            dispersion: NtpFracValue::zero(),
+            delay: NtpFracValue::zero(),
+            clock: NtpClockModel::new(),
+            sync: NtpSyncStatus::Unsynchronized,
+            dispersion_rate: 0.0,
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_on_drop() {
+        let cell = StateCell::new(state());
+        assert_eq!(cell.load().version, 0);
+
+        let mut guard = cell.lock().await;
+        guard.stratum = 1;
+        guard.ref_id = 0x4750_5300;
+        // Readers keep seeing the old state until the update is done.
+        assert_eq!(cell.load().version, 0);
+        assert_eq!(cell.load().state.stratum, NtpSource::None.stratum());
+        drop(guard);
+
+        let loaded = cell.load();
+        assert_eq!(loaded.version, 1);
+        assert_eq!((loaded.state.stratum, loaded.state.ref_id), (1, 0x4750_5300));
+
+        // Writers start from the latest published state.
+        cell.lock().await.leap = 1;
+        let loaded = cell.load();
+        assert_eq!(loaded.version, 2);
+        assert_eq!((loaded.state.leap, loaded.state.stratum), (1, 1));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn readers_see_whole_updates() {
+        const UPDATES: u32 = 2000;
+        let cell = Arc::new(StateCell::new(ServerState {
+            ref_id: 0,
+            ref_ts: NtpTimestamp::from_unix_secs(0),
+            ..state()
+        }));
+
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let cell = Arc::clone(&cell);
+                tokio::spawn(async move {
+                    for _ in 0..UPDATES {
+                        let mut state = cell.lock().await;
+                        state.ref_id += 1;
+                        state.delay = NtpFracValue::from_secs(state.ref_id as f64);
+                        state.ref_ts = NtpTimestamp::from_unix_secs(state.ref_id as u64);
+                    }
+                })
+            })
+            .collect();
+        let reader = {
+            let cell = Arc::clone(&cell);
+            std::thread::spawn(move || {
+                let mut last = 0;
+                loop {
+                    let VersionedState { version, state } = cell.load();
+                    assert!(version >= last);
+                    last = version;
+                    assert_eq!(state.ref_id as u64, version);
+                    assert_eq!(state.delay.to_secs(), version as f64);
+                    assert_eq!(state.ref_ts, NtpTimestamp::from_unix_secs(version));
+                    if version == 2 * UPDATES as u64 {
+                        break;
+                    }
+                }
+            })
+        };
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        reader.join().unwrap();
+    }
+}
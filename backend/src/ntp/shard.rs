@@ -0,0 +1,36 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+
+// Independently locked parts per-client state is split into.
+pub const SHARDS: usize = 16;
+
+/// Per-client state split by address over separately locked shards, so
+/// workers serving different clients rarely wait for each other. Locks are
+/// only held for a lookup and never across an await.
+pub struct Sharded<T> {
+    shards: Vec<Mutex<T>>,
+    hasher: RandomState,
+}
+
+impl<T> Sharded<T> {
+    pub fn new(mut make: impl FnMut() -> T) -> Sharded<T> {
+        Sharded {
+            shards: (0..SHARDS).map(|_| Mutex::new(make())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// The shard holding the state of `addr`.
+    pub fn get(&self, addr: &IpAddr) -> MutexGuard<'_, T> {
+        let index = self.hasher.hash_one(addr) as usize % SHARDS;
+        self.shards[index].lock().unwrap()
+    }
+
+    pub fn for_each(&self, mut f: impl FnMut(&mut T)) {
+        for shard in &self.shards {
+            f(&mut shard.lock().unwrap());
+        }
+    }
+}
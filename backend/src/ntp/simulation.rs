@@ -0,0 +1,227 @@
+//! Runs the server on a simulated clock: hours of source loss, drift and
+//! outages go by in a fraction of a second.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+
+use super::acl::Acl;
+use super::broadcast::BroadcastConfig;
+use super::client;
+use super::listen::ListenConfig;
+use super::local_clock::{Clock, ManualClock, OffsetClock, SystemClock};
+use super::rate_limit::RateLimitConfig;
+use super::{NtpAuth, NtpSample, NtpSampleQuality, NtpServer, NtpServerConfig, NtpSource, NtpSourcePriority};
+use super::{NtpSyncStatus, NtpTimestamp};
+
+const HOUR: u64 = 3600;
+
+pub(super) fn config(addresses: Vec<String>, port: u16) -> NtpServerConfig {
+    NtpServerConfig {
+        unsync_silent: false,
+        interleaved: false,
+        holdover_timeout: Duration::from_secs(4 * HOUR),
+        priority: NtpSourcePriority { gps: 1, ntp: 2, rtc: 3 },
+        holdover_drift: 1e-6,
+        holdover_max_error: 1e-2,
+        rate_limit: RateLimitConfig {
+            enable: false,
+            interval: Duration::from_secs(1),
+            burst: 1,
+            kod: false,
+            table_size: 1,
+        },
+        acl: Acl::new(&[]).unwrap(),
+        listen: ListenConfig {
+            addresses,
+            port,
+            workers: 1,
+        },
+        cmdmon: ListenConfig {
+            addresses: vec![],
+            port: 0,
+            workers: 1,
+        },
+        broadcast: BroadcastConfig {
+            enable: false,
+            destinations: vec![],
+            port: 0,
+            interval: Duration::from_secs(64),
+            key_id: None,
+            ttl: 1,
+        },
+    }
+}
+
+async fn start(clock: Arc<dyn Clock>, config: NtpServerConfig) -> NtpServer {
+    let auth = Arc::new(RwLock::new(NtpAuth::new(vec![], vec![])));
+    let server = NtpServer::new(false, config, auth, None, clock).await;
+    server.run().await;
+    server
+}
+
+/// Server without sockets on a manual clock starting at `start`.
+async fn simulated() -> (NtpServer, Arc<ManualClock>) {
+    let clock = Arc::new(ManualClock::new(NtpTimestamp::from_unix_secs(1_700_000_000)));
+    let server = start(clock.clone(), config(vec![], 0)).await;
+    (server, clock)
+}
+
+/// What a perfect reference reads, however the local clock drifts.
+fn true_time(clock: &ManualClock) -> NtpTimestamp {
+    NtpTimestamp::from_unix_secs(1_700_000_000).add_sec(clock.elapsed().as_secs_f64())
+}
+
+/// Moves the clock a second at a time, letting the server tasks run after
+/// each step; `tick` is called with the seconds passed so far.
+async fn advance(clock: &ManualClock, secs: u64, mut tick: impl FnMut(u64) -> Option<NtpSample>, server: &mut NtpServer) {
+    for second in 1..=secs {
+        clock.advance(Duration::from_secs(1));
+        for _ in 0..4 {
+            tokio::task::yield_now().await;
+        }
+        if let Some(sample) = tick(second) {
+            server.update_state(sample).await;
+        }
+    }
+}
+
+fn gps(clock: &ManualClock) -> NtpSample {
+    NtpSample::from_reference(NtpSource::Gps, true_time(clock), NtpSampleQuality::gps(None, 1e-3), clock)
+}
+
+fn upstream() -> NtpSource {
+    NtpSource::Ntp {
+        addr: SocketAddr::from(([192, 0, 2, 1], 123)),
+        stratum: 1,
+        leap: 0,
+    }
+}
+
+fn ntp(clock: &ManualClock) -> NtpSample {
+    NtpSample::from_reference(
+        upstream(),
+        true_time(clock),
+        NtpSampleQuality {
+            delay: 0.01,
+            dispersion: 1e-3,
+            ..Default::default()
+        },
+        clock,
+    )
+}
+
+fn rtc(clock: &ManualClock) -> NtpSample {
+    NtpSample::from_rtc(true_time(clock).unix_seconds() as u64, clock)
+}
+
+/// Served minus true time, in seconds.
+async fn error(server: &NtpServer, clock: &ManualClock) -> f64 {
+    server.now().await.diff_to_sec(&true_time(clock))
+}
+
+#[tokio::test]
+async fn gps_loss_goes_through_holdover() {
+    let (mut server, clock) = simulated().await;
+    advance(&clock, 60, |_| Some(gps(&clock)), &mut server).await;
+    let status = server.status().await;
+    assert_eq!((status.sync, status.source, status.stratum), (NtpSyncStatus::Synchronized, NtpSource::Gps, 1));
+
+    // GPS times out after 10 s, the server keeps advertising it.
+    advance(&clock, 5, |_| None, &mut server).await;
+    assert_eq!(server.status().await.sync, NtpSyncStatus::Synchronized);
+    advance(&clock, 10, |_| None, &mut server).await;
+    let status = server.status().await;
+    assert_eq!((status.sync, status.source, status.stratum), (NtpSyncStatus::Holdover, NtpSource::Gps, 1));
+
+    // The error bound grows at 1 ppm and passes 10 ms after 10000 s.
+    advance(&clock, 2 * HOUR, |_| None, &mut server).await;
+    assert_eq!(server.status().await.stratum, 1);
+    advance(&clock, HOUR, |_| None, &mut server).await;
+    let status = server.status().await;
+    assert_eq!((status.sync, status.stratum), (NtpSyncStatus::Holdover, 2));
+    assert!(status.holdover_secs.unwrap() > 3 * HOUR);
+
+    advance(&clock, HOUR, |_| None, &mut server).await;
+    let status = server.status().await;
+    assert_eq!((status.sync, status.source, status.stratum), (NtpSyncStatus::Unsynchronized, NtpSource::None, 16));
+}
+
+#[tokio::test]
+async fn holdover_follows_learned_drift() {
+    let (mut server, clock) = simulated().await;
+    // The local oscillator runs 20 ppm fast.
+    clock.set_drift(20e-6);
+    advance(&clock, 2 * HOUR, |_| Some(gps(&clock)), &mut server).await;
+    assert!(error(&server, &clock).await.abs() < 1e-4);
+
+    advance(&clock, HOUR, |_| None, &mut server).await;
+    let status = server.status().await;
+    assert_eq!(status.sync, NtpSyncStatus::Holdover);
+    assert!((status.holdover_frequency_ppm.unwrap() + 20.0).abs() < 0.5);
+    // Free running the error would be 72 ms by now.
+    assert!(error(&server, &clock).await.abs() < 1e-4);
+}
+
+#[tokio::test]
+async fn upstream_outage_falls_back_to_rtc() {
+    let (mut server, clock) = simulated().await;
+    let both = |second: u64| match second % 30 {
+        0 => Some(rtc(&clock)),
+        15 => Some(ntp(&clock)),
+        _ => None,
+    };
+    advance(&clock, 600, both, &mut server).await;
+    let status = server.status().await;
+    assert_eq!((status.source, status.stratum), (upstream(), 2));
+
+    // The upstream server is only dropped after 2048 s of silence.
+    let rtc_only = |second: u64| second.is_multiple_of(30).then(|| rtc(&clock));
+    advance(&clock, 2000, rtc_only, &mut server).await;
+    assert_eq!(server.status().await.source, upstream());
+    advance(&clock, 100, rtc_only, &mut server).await;
+    let status = server.status().await;
+    assert_eq!((status.sync, status.source), (NtpSyncStatus::Holdover, upstream()));
+
+    // Holdover is preferred until it times out, its error bound is still
+    // below the RTC's.
+    advance(&clock, 4 * HOUR - 200, rtc_only, &mut server).await;
+    assert_eq!(server.status().await.sync, NtpSyncStatus::Holdover);
+    advance(&clock, 200, rtc_only, &mut server).await;
+    let status = server.status().await;
+    assert_eq!((status.sync, status.source, status.stratum), (NtpSyncStatus::Synchronized, NtpSource::Rtc, 10));
+
+    // Back after three samples.
+    advance(&clock, 90, both, &mut server).await;
+    assert_eq!(server.status().await.source, upstream());
+}
+
+#[tokio::test]
+async fn served_time_survives_clock_step() {
+    let (mut server, clock) = simulated().await;
+    advance(&clock, 30, |_| Some(gps(&clock)), &mut server).await;
+    assert!(error(&server, &clock).await.abs() < 1e-6);
+
+    clock.step(5.0);
+    assert!((error(&server, &clock).await - 5.0).abs() < 1e-6);
+    advance(&clock, 1, |_| Some(gps(&clock)), &mut server).await;
+    assert!(error(&server, &clock).await.abs() < 1e-6);
+}
+
+#[tokio::test]
+async fn client_measures_server_offset() {
+    let clock = Arc::new(OffsetClock::new(100.0));
+    let mut server = start(clock.clone(), config(vec![String::from("127.0.0.1")], 12_350)).await;
+    for _ in 0..3 {
+        let sample = NtpSample::from_reference(NtpSource::Gps, clock.now(), NtpSampleQuality::gps(None, 1e-3), &*clock);
+        server.update_state(sample).await;
+    }
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], 12_350));
+    let (sample, _) = client::query(addr, None, None, &SystemClock).await.unwrap();
+    assert!((sample.offset - 100.0).abs() < 0.01, "offset {}", sample.offset);
+
+    let (sample, _) = client::query(addr, None, None, &OffsetClock::new(100.0)).await.unwrap();
+    assert!(sample.offset.abs() < 0.01, "offset {}", sample.offset);
+}
@@ -0,0 +1,386 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use md5::{Digest, Md5};
+use serde::Serialize;
+
+use super::local_clock::Clock;
+use super::selection::{select, Candidate, CandidateStatus, Selection};
+use super::NtpTimestamp;
+
+// A source is dropped from selection when it has been silent this long.
+const GPS_TIMEOUT: Duration = Duration::from_secs(10);
+// NTP sources get as many polls as the reach register spans; samples that
+// do not say how often they come use the timeout of a 256 s poll.
+const NTP_TIMEOUT_POLLS: u32 = 8;
+const NTP_TIMEOUT: Duration = Duration::from_secs(2048);
+const RTC_TIMEOUT: Duration = Duration::from_secs(60);
+// Consecutive samples a source must deliver before it can be selected.
+const QUALIFY_SAMPLES: u32 = 3;
+
+// Advertised when only the local RTC keeps time (chrony's `local stratum`).
+pub const RTC_STRATUM: u8 = 10;
+pub const UNSYNC_STRATUM: u8 = 16;
+pub const LEAP_ALARM: u8 = 3;
+// Frequency tolerance used to grow dispersion between updates (RFC 5905).
+pub const PHI: f64 = 15e-6;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum Source {
+    None,
+    Gps,
+    Ntp { addr: SocketAddr, stratum: u8, leap: u8 },
+    Rtc,
+}
+
+impl Source {
+    pub fn stratum(&self) -> u8 {
+        match self {
+            Source::None => UNSYNC_STRATUM,
+            Source::Gps => 1,
+            Source::Ntp { stratum, .. } => stratum.saturating_add(1).min(UNSYNC_STRATUM),
+            Source::Rtc => RTC_STRATUM,
+        }
+    }
+
+    pub fn ref_id(&self) -> u32 {
+        match self {
+            Source::None => u32::from_be_bytes(*b"INIT"),
+            Source::Gps => u32::from_be_bytes(*b"GPS\0"),
+            Source::Ntp { addr, .. } => addr_ref_id(&addr.ip()),
+            Source::Rtc => u32::from_be_bytes(*b"LOCL"),
+        }
+    }
+
+    /// Whether both values describe the same physical source.
+    pub fn same_as(&self, other: &Source) -> bool {
+        match (self, other) {
+            (Source::Ntp { addr: a, .. }, Source::Ntp { addr: b, .. }) => a == b,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Source::None => String::from("NONE"),
+            Source::Gps => String::from("GPS"),
+            Source::Ntp { addr, .. } => addr.to_string(),
+            Source::Rtc => String::from("RTC"),
+        }
+    }
+
+    pub fn leap(&self) -> u8 {
+        match self {
+            Source::None => LEAP_ALARM,
+            Source::Ntp { leap, .. } => *leap,
+            _ => 0,
+        }
+    }
+}
+
+/// Error budget of a single sample, all values in seconds.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SampleQuality {
+    pub delay: f64,
+    pub dispersion: f64,
+    pub jitter: f64,
+    pub root_delay: f64,
+    pub root_dispersion: f64,
+}
+
+impl SampleQuality {
+    /// gpsd takes the TPV time from the sentence the receiver sends after
+    /// each fix, so a sample carries the receiver's output latency.
+    /// `dispersion` is the configured bound on that latency; `ept`, gpsd's
+    /// estimated timestamp error at 95% confidence, raises it when larger.
+    pub fn gps(ept: Option<f64>, dispersion: f64) -> SampleQuality {
+        SampleQuality {
+            dispersion: ept.map_or(dispersion, |ept| ept.max(dispersion)),
+            ..Default::default()
+        }
+    }
+
+    /// The RTC only has whole-second resolution.
+    pub fn rtc() -> SampleQuality {
+        SampleQuality {
+            dispersion: 0.5,
+            ..Default::default()
+        }
+    }
+}
+
+/// One measurement of a reference against the system clock.
+#[derive(Debug, Copy, Clone)]
+pub struct Sample {
+    pub source: Source,
+    /// Reference minus system time, in seconds.
+    pub offset: f64,
+    /// System time at which `offset` was measured.
+    pub local_ts: NtpTimestamp,
+    pub quality: SampleQuality,
+    /// Time until the source is polled again, for polled sources.
+    pub poll: Option<Duration>,
+}
+
+impl Sample {
+    /// Builds a sample from a reference timestamp received just now.
+    pub fn from_reference(source: Source, reference: NtpTimestamp, quality: SampleQuality, clock: &dyn Clock) -> Sample {
+        let local_ts = clock.now();
+        Sample {
+            source,
+            offset: reference.diff_to_sec(&local_ts),
+            local_ts,
+            quality,
+            poll: None,
+        }
+    }
+
+    /// Builds a sample from an RTC reading in whole Unix seconds.
+    pub fn from_rtc(unix_secs: u64, clock: &dyn Clock) -> Sample {
+        // Readings are truncated, so the true time is half a second later
+        // on average.
+        let reference = NtpTimestamp::from_unix_secs(unix_secs).add_sec(0.5);
+        Sample::from_reference(Source::Rtc, reference, SampleQuality::rtc(), clock)
+    }
+
+    pub fn reference_ts(&self) -> NtpTimestamp {
+        self.local_ts.add_sec(self.offset)
+    }
+}
+
+/// Reference ID of an upstream server as defined by RFC 5905: the IPv4
+/// address itself, or the first four octets of the MD5 of an IPv6 address.
+pub fn addr_ref_id(ip: &IpAddr) -> u32 {
+    match ip {
+        IpAddr::V4(v4) => u32::from(*v4),
+        IpAddr::V6(v6) => {
+            let digest = Md5::digest(v6.octets());
+            u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+        }
+    }
+}
+
+/// Preference of each kind of source when choosing the system peer;
+/// lower values win.
+#[derive(Debug, Copy, Clone)]
+pub struct SourcePriority {
+    pub gps: u8,
+    pub ntp: u8,
+    pub rtc: u8,
+}
+
+struct Entry {
+    sample: Sample,
+    at: Instant,
+    samples: u32,
+    interval: Option<Duration>,
+}
+
+/// Latest state of one source, as reported by the monitoring protocols.
+#[derive(Debug, Copy, Clone)]
+pub struct SourceReport {
+    pub source: Source,
+    pub status: CandidateStatus,
+    pub offset: f64,
+    pub quality: SampleQuality,
+    /// Time since the last sample.
+    pub age: Duration,
+    /// Time between the last two samples.
+    pub interval: Option<Duration>,
+    /// Consecutive samples received without timing out.
+    pub samples: u32,
+    pub fresh: bool,
+}
+
+impl Entry {
+    fn timeout(&self) -> Duration {
+        match self.sample.source {
+            Source::Gps => GPS_TIMEOUT,
+            Source::Rtc => RTC_TIMEOUT,
+            _ => self.sample.poll.map_or(NTP_TIMEOUT, |poll| poll * NTP_TIMEOUT_POLLS),
+        }
+    }
+
+    fn age(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.at)
+    }
+
+    fn is_fresh(&self, now: Instant) -> bool {
+        self.age(now) <= self.timeout()
+    }
+
+    fn root_distance(&self, now: Instant) -> f64 {
+        let quality = &self.sample.quality;
+        (quality.root_delay + quality.delay) / 2.0
+            + quality.root_dispersion
+            + quality.dispersion
+            + quality.jitter
+            + PHI * self.age(now).as_secs_f64()
+    }
+}
+
+/// Latest sample of every source that has emitted events.
+pub struct Sources {
+    entries: Vec<Entry>,
+    priority: SourcePriority,
+    selection: Selection,
+    clock: Arc<dyn Clock>,
+}
+
+impl Sources {
+    pub fn new(priority: SourcePriority, clock: Arc<dyn Clock>) -> Sources {
+        Sources {
+            entries: Vec::new(),
+            priority,
+            selection: select(Vec::new()),
+            clock,
+        }
+    }
+
+    pub fn seen(&mut self, sample: Sample) {
+        let now = self.clock.instant();
+        let position = self
+            .entries
+            .iter()
+            .position(|entry| entry.sample.source.same_as(&sample.source));
+        match position {
+            Some(index) => {
+                let entry = &mut self.entries[index];
+                entry.samples = if entry.is_fresh(now) { entry.samples + 1 } else { 1 };
+                entry.interval = Some(entry.age(now));
+                entry.sample = sample;
+                entry.at = now;
+            }
+            None => self.entries.push(Entry {
+                sample,
+                at: now,
+                samples: 1,
+                interval: None,
+            }),
+        }
+    }
+
+    /// Runs source selection and returns the system peer.
+    pub fn select(&mut self) -> Source {
+        let now = self.clock.instant();
+        let candidates = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let source = entry.sample.source;
+                let eligible = entry.is_fresh(now)
+                    && entry.samples >= QUALIFY_SAMPLES
+                    && source.stratum() < UNSYNC_STRATUM
+                    && source.leap() != LEAP_ALARM;
+                Candidate {
+                    name: source.name(),
+                    offset: entry.sample.offset,
+                    root_distance: entry.root_distance(now),
+                    jitter: entry.sample.quality.jitter,
+                    stratum: source.stratum(),
+                    priority: match source {
+                        Source::Gps => self.priority.gps,
+                        Source::Rtc => self.priority.rtc,
+                        _ => self.priority.ntp,
+                    },
+                    fallback: source == Source::Rtc,
+                    status: if eligible {
+                        CandidateStatus::Survivor
+                    } else {
+                        CandidateStatus::Rejected
+                    },
+                }
+            })
+            .collect();
+
+        self.selection = select(candidates);
+        match self.selection.system_peer {
+            Some(index) => self.entries[index].sample.source,
+            None => Source::None,
+        }
+    }
+
+    pub fn selection(&self) -> &Selection {
+        &self.selection
+    }
+
+    /// All known sources in the order they were first seen, with their
+    /// status from the last selection.
+    pub fn reports(&self) -> Vec<SourceReport> {
+        let now = self.clock.instant();
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let source = entry.sample.source;
+                let status = self
+                    .selection
+                    .candidates
+                    .get(index)
+                    .filter(|candidate| candidate.name == source.name())
+                    .map_or(CandidateStatus::Rejected, |candidate| candidate.status);
+                SourceReport {
+                    source,
+                    status,
+                    offset: entry.sample.offset,
+                    quality: entry.sample.quality,
+                    age: entry.age(now),
+                    interval: entry.interval,
+                    samples: entry.samples,
+                    fresh: entry.is_fresh(now),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntp::local_clock::ManualClock;
+
+    fn upstream(clock: &ManualClock, poll: Option<Duration>) -> Sample {
+        let source = Source::Ntp {
+            addr: SocketAddr::from(([192, 0, 2, 1], 123)),
+            stratum: 1,
+            leap: 0,
+        };
+        Sample {
+            poll,
+            ..Sample::from_reference(source, clock.now(), SampleQuality::default(), clock)
+        }
+    }
+
+    #[test]
+    fn advertised_fields() {
+        let v4 = Source::Ntp { addr: SocketAddr::from(([192, 0, 2, 1], 123)), stratum: 2, leap: 1 };
+        assert_eq!((v4.stratum(), v4.ref_id(), v4.leap()), (3, 0xc000_0201, 1));
+        let v6 = Source::Ntp { addr: "[2001:db8::1]:123".parse().unwrap(), stratum: 15, leap: 0 };
+        assert_eq!(v6.stratum(), UNSYNC_STRATUM);
+        let digest = Md5::digest("2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        assert_eq!(v6.ref_id().to_be_bytes(), digest[..4]);
+
+        assert_eq!((Source::Gps.stratum(), Source::Gps.ref_id().to_be_bytes(), Source::Gps.leap()), (1, *b"GPS\0", 0));
+        assert_eq!((Source::Rtc.stratum(), Source::Rtc.ref_id().to_be_bytes(), Source::Rtc.leap()), (RTC_STRATUM, *b"LOCL", 0));
+        assert_eq!((Source::None.stratum(), Source::None.ref_id().to_be_bytes(), Source::None.leap()), (UNSYNC_STRATUM, *b"INIT", LEAP_ALARM));
+    }
+
+    #[test]
+    fn ntp_timeout_follows_poll() {
+        let priority = SourcePriority { gps: 1, ntp: 2, rtc: 3 };
+        for (poll, timeout) in [(Some(Duration::from_secs(4096)), 8 * 4096), (Some(Duration::from_secs(8)), 64), (None, 2048)] {
+            let clock = Arc::new(ManualClock::new(NtpTimestamp::from_unix_secs(1_700_000_000)));
+            let mut sources = Sources::new(priority, clock.clone());
+            for _ in 0..QUALIFY_SAMPLES {
+                sources.seen(upstream(&clock, poll));
+            }
+            assert_ne!(sources.select(), Source::None);
+
+            clock.advance(Duration::from_secs(timeout));
+            assert_ne!(sources.select(), Source::None, "poll {:?}", poll);
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(sources.select(), Source::None, "poll {:?}", poll);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use super::local_clock::Clock;
+use super::{NtpHoldover, NtpSource};
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub enum SyncStatus {
+    Unsynchronized,
+    Synchronized,
+    Holdover,
+}
+
+/// Tracks whether the server may claim to be synchronized.
+///
+/// The server starts unsynchronized, becomes synchronized once a qualified
+/// source is selected and keeps advertising that source for
+/// `holdover_timeout` after it is lost before raising the alarm again.
+/// Holdover is preferred over the RTC while its error bound is the smaller.
+pub struct SyncMachine {
+    status: SyncStatus,
+    last_source: NtpSource,
+    lost_at: Option<Instant>,
+    holdover_timeout: Duration,
+    holdover: NtpHoldover,
+    clock: Arc<dyn Clock>,
+}
+
+impl SyncMachine {
+    pub fn new(holdover_timeout: Duration, holdover: NtpHoldover, clock: Arc<dyn Clock>) -> SyncMachine {
+        SyncMachine {
+            status: SyncStatus::Unsynchronized,
+            last_source: NtpSource::None,
+            lost_at: None,
+            holdover_timeout,
+            holdover,
+            clock,
+        }
+    }
+
+    /// Time since the last selected source was lost.
+    fn lost_for(&self) -> Option<Duration> {
+        self.lost_at
+            .map(|at| self.clock.instant().saturating_duration_since(at))
+    }
+
+    pub fn holdover(&self) -> &NtpHoldover {
+        &self.holdover
+    }
+
+    /// Feeds the clock frequency while locked to GPS.
+    pub fn learn(&mut self, frequency: f64) {
+        self.holdover.learn(frequency);
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        self.status
+    }
+
+    /// Source currently advertised to clients.
+    pub fn advertised(&self) -> NtpSource {
+        self.last_source
+    }
+
+    /// Seconds since the last selected source was lost, while in holdover.
+    pub fn holdover_secs(&self) -> Option<u64> {
+        self.lost_for().map(|lost| lost.as_secs())
+    }
+
+    /// Estimated time error accumulated since the source was lost.
+    pub fn holdover_error(&self) -> Option<f64> {
+        self.lost_for()
+            .map(|lost| self.holdover.estimated_error(lost.as_secs_f64()))
+    }
+
+    /// True once the holdover error bound exceeds the configured threshold.
+    pub fn is_degraded(&self) -> bool {
+        self.lost_for()
+            .is_some_and(|lost| self.holdover.is_degraded(lost.as_secs_f64()))
+    }
+
+    /// Advances the machine with the currently selected source and its root
+    /// distance, returns the source that should be advertised to clients.
+    pub fn advance(&mut self, selected: NtpSource, distance: f64) -> NtpSource {
+        if selected != NtpSource::None && !self.prefers_holdover(selected, distance) {
+            if self.status != SyncStatus::Synchronized {
+                info!("Synchronized to {:?}", selected);
+            }
+            self.status = SyncStatus::Synchronized;
+            self.last_source = selected;
+            self.lost_at = None;
+            return selected;
+        }
+
+        match self.status {
+            SyncStatus::Synchronized => {
+                warn!("Lost {:?}, entering holdover", self.last_source);
+                self.status = SyncStatus::Holdover;
+                self.lost_at = Some(self.clock.instant());
+                self.last_source
+            }
+            SyncStatus::Holdover => {
+                if self.lost_for().is_some_and(|lost| lost > self.holdover_timeout) {
+                    warn!("Holdover expired, clients are now alarmed");
+                    self.status = SyncStatus::Unsynchronized;
+                    self.last_source = NtpSource::None;
+                    self.lost_at = None;
+                    NtpSource::None
+                } else {
+                    self.last_source
+                }
+            }
+            SyncStatus::Unsynchronized => NtpSource::None,
+        }
+    }
+
+    /// Whether to keep extrapolating the lost source rather than follow the
+    /// coarse RTC, whose error bound is `distance`.
+    fn prefers_holdover(&self, selected: NtpSource, distance: f64) -> bool {
+        if selected != NtpSource::Rtc || matches!(self.last_source, NtpSource::Rtc | NtpSource::None) {
+            return false;
+        }
+        match self.status {
+            SyncStatus::Synchronized => true,
+            SyncStatus::Holdover => self.lost_for().is_some_and(|lost| {
+                lost <= self.holdover_timeout && self.holdover.estimated_error(lost.as_secs_f64()) < distance
+            }),
+            SyncStatus::Unsynchronized => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntp::local_clock::ManualClock;
+    use crate::ntp::NtpTimestamp;
+
+    const TIMEOUT: Duration = Duration::from_secs(3600);
+
+    fn machine() -> (SyncMachine, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new(NtpTimestamp::from_unix_secs(1_700_000_000)));
+        let machine = SyncMachine::new(TIMEOUT, NtpHoldover::new(1e-6, 1e-3), clock.clone());
+        (machine, clock)
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// Synchronizes to GPS with a learned frequency, so holdover errors grow
+    /// at the 1 ppm drift rate.
+    fn locked() -> (SyncMachine, Arc<ManualClock>) {
+        let (mut machine, clock) = machine();
+        for _ in 0..16 {
+            machine.learn(5e-6);
+        }
+        assert_eq!(machine.advance(NtpSource::Gps, 1e-3), NtpSource::Gps);
+        (machine, clock)
+    }
+
+    #[test]
+    fn synchronizes_on_selection() {
+        let (mut machine, _) = machine();
+        assert_eq!(machine.advance(NtpSource::None, 0.0), NtpSource::None);
+        assert_eq!(machine.status(), SyncStatus::Unsynchronized);
+
+        assert_eq!(machine.advance(NtpSource::Gps, 1e-3), NtpSource::Gps);
+        assert_eq!((machine.status(), machine.advertised()), (SyncStatus::Synchronized, NtpSource::Gps));
+        assert_eq!(machine.holdover_secs(), None);
+    }
+
+    #[test]
+    fn holdover_until_timeout() {
+        let (mut machine, clock) = locked();
+        assert_eq!(machine.advance(NtpSource::None, 0.0), NtpSource::Gps);
+        assert_eq!(machine.status(), SyncStatus::Holdover);
+
+        clock.advance(secs(1800));
+        assert_eq!(machine.advance(NtpSource::None, 0.0), NtpSource::Gps);
+        assert_eq!(machine.holdover_secs(), Some(1800));
+        assert!((machine.holdover_error().unwrap() - 1.8e-3).abs() < 1e-9);
+        assert!(machine.is_degraded());
+
+        clock.advance(TIMEOUT - secs(1800) + secs(1));
+        assert_eq!(machine.advance(NtpSource::None, 0.0), NtpSource::None);
+        assert_eq!((machine.status(), machine.advertised()), (SyncStatus::Unsynchronized, NtpSource::None));
+        assert_eq!(machine.holdover_secs(), None);
+    }
+
+    #[test]
+    fn holdover_ends_when_a_source_returns() {
+        let (mut machine, clock) = locked();
+        machine.advance(NtpSource::None, 0.0);
+        clock.advance(secs(60));
+        let upstream = NtpSource::Ntp {
+            addr: "192.0.2.1:123".parse().unwrap(),
+            stratum: 1,
+            leap: 0,
+        };
+        assert_eq!(machine.advance(upstream, 0.01), upstream);
+        assert_eq!(machine.status(), SyncStatus::Synchronized);
+        assert_eq!(machine.holdover_secs(), None);
+    }
+
+    #[test]
+    fn holdover_beats_rtc_while_its_bound_is_smaller() {
+        let (mut machine, clock) = locked();
+        // Losing GPS with the RTC still selected.
+        assert_eq!(machine.advance(NtpSource::Rtc, 2e-3), NtpSource::Gps);
+        assert_eq!(machine.status(), SyncStatus::Holdover);
+
+        clock.advance(secs(1000));
+        assert_eq!(machine.advance(NtpSource::Rtc, 2e-3), NtpSource::Gps);
+        assert_eq!(machine.status(), SyncStatus::Holdover);
+
+        // 2.5 ms of holdover error against the RTC's 2 ms.
+        clock.advance(secs(1500));
+        assert_eq!(machine.advance(NtpSource::Rtc, 2e-3), NtpSource::Rtc);
+        assert_eq!(machine.status(), SyncStatus::Synchronized);
+        assert_eq!(machine.holdover_secs(), None);
+    }
+
+    #[test]
+    fn rtc_after_holdover_timeout() {
+        let (mut machine, clock) = locked();
+        machine.advance(NtpSource::Rtc, 0.5);
+        clock.advance(TIMEOUT);
+        assert_eq!(machine.advance(NtpSource::Rtc, 0.5), NtpSource::Gps);
+        clock.advance(secs(1));
+        assert_eq!(machine.advance(NtpSource::Rtc, 0.5), NtpSource::Rtc);
+        assert_eq!(machine.status(), SyncStatus::Synchronized);
+    }
+
+    #[test]
+    fn rtc_without_a_lost_source() {
+        let (mut machine, _) = machine();
+        assert_eq!(machine.advance(NtpSource::Rtc, 0.5), NtpSource::Rtc);
+        assert_eq!(machine.status(), SyncStatus::Synchronized);
+        // Nothing better was lost, the RTC is simply followed.
+        assert_eq!(machine.advance(NtpSource::Rtc, 0.5), NtpSource::Rtc);
+    }
+}
@@ -1,13 +1,21 @@
 use std::time::SystemTime;
 
 use byteorder::{BigEndian, ByteOrder};
+use chrono::{DateTime, TimeZone, Utc};
 use rand::random;
-use serde::{Deserialize, Serialize};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
 
- #[derive(Debug, Copy, Clone)]
- #[derive( Serialize, Deserialize)]
- pub struct Timestamp {
- pub ts: u64,
+use super::NtpDateTime;
+
+// Dates within half an era of the 2036 rollover, 1968 to 2104.
+const PIVOT: NtpDateTime = NtpDateTime::new(1 << 32, 0);
+
+/// NTP timestamp format: 32.32 fixed-point seconds since the start of the
+/// current era (1900, then 2036). See `NtpDateTime` for the full date.
+#[derive(Debug, Copy, Clone, Deserialize)]
+pub struct Timestamp {
+    pub ts: u64,
 }
 
 impl Timestamp {
@@ -15,12 +23,77 @@ impl Timestamp {
         Self{ ts }
     }
     pub fn now() -> Timestamp {
-        let now = SystemTime::now();
-        let dur = now.duration_since(std::time::UNIX_EPOCH).unwrap();
-        let secs = dur.as_secs() + 2208988800; // 1900 epoch
-        let nanos = dur.subsec_nanos();
+        NtpDateTime::now().timestamp()
+    }
+
+    /// Unix seconds plus a binary fraction in units of 2^-32 s.
+    pub fn from_unix(secs: i64, fraction: u32) -> Timestamp {
+        NtpDateTime::from_unix(secs, fraction).timestamp()
+    }
+
+    pub fn from_unix_secs(secs: u64) -> Timestamp {
+        Timestamp::from_unix(secs as i64, 0)
+    }
+
+    pub fn from_unix_nanos(secs: i64, nanos: u32) -> Timestamp {
+        NtpDateTime::from_unix_nanos(secs, nanos).timestamp()
+    }
+
+    pub fn from_datetime<Tz: TimeZone>(datetime: &DateTime<Tz>) -> Timestamp {
+        Timestamp::from_unix_nanos(datetime.timestamp(), datetime.timestamp_subsec_nanos())
+    }
+
+    /// Parses an RFC 3339 date such as gpsd's `2023-09-01T12:00:00.250Z`.
+    pub fn from_rfc3339(value: &str) -> Option<Timestamp> {
+        DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|datetime| Timestamp::from_datetime(&datetime))
+    }
+
+    pub fn seconds(&self) -> u32 {
+        (self.ts >> 32) as u32
+    }
+
+    pub fn fraction(&self) -> u32 {
+        self.ts as u32
+    }
+
+    /// The date within 68 years of `pivot`, which must be known to that
+    /// precision, e.g. the local clock when the timestamp was received.
+    pub fn date_near(&self, pivot: &NtpDateTime) -> NtpDateTime {
+        let offset = self.seconds().wrapping_sub(pivot.era_offset()) as i32;
+        NtpDateTime::new(pivot.seconds() + offset as i64, self.fraction())
+    }
+
+    /// The date between 1968 and 2104 (RFC 4330 section 3), or the prime
+    /// epoch for the zero timestamp, which stands for an unknown time.
+    pub fn date(&self) -> NtpDateTime {
+        if self.ts == 0 {
+            return NtpDateTime::default();
+        }
+        self.date_near(&PIVOT)
+    }
+
+    pub fn unix_seconds(&self) -> i64 {
+        self.date().unix_seconds()
+    }
+
+    pub fn subsec_nanos(&self) -> u32 {
+        fraction_to_nanos(self.fraction())
+    }
 
-        Timestamp{ts: (secs << 32) + (nanos as f64 * 4.294967296) as u64}
+    pub fn to_unix_secs(self) -> f64 {
+        self.date().to_unix_secs()
+    }
+
+    pub fn to_datetime(self) -> DateTime<Utc> {
+        self.date().to_datetime()
+    }
+
+    // Nothing in the server needs std time yet.
+    #[allow(dead_code)]
+    pub fn to_system_time(self) -> SystemTime {
+        self.date().to_system_time()
     }
 
     pub fn zero() -> Timestamp {
@@ -31,10 +104,16 @@ impl Timestamp {
         Timestamp{ts: random()}
     }
 
+    /// Difference modulo an era, right across the 2036 rollover as long as
+    /// the timestamps are less than 68 years apart; compare dates otherwise.
     pub fn diff_to_sec(&self, ts: &Timestamp) -> f64 {
         (self.ts.wrapping_sub(ts.ts)) as i64 as f64 / 4294967296.0
     }
 
+    pub fn add_sec(&self, sec: f64) -> Timestamp {
+        Timestamp{ts: self.ts.wrapping_add((sec * 4294967296.0) as i64 as u64)}
+    }
+
     pub fn read(buf: &[u8]) -> Timestamp {
         Timestamp{ts: BigEndian::read_u64(buf)}
     }
@@ -44,8 +123,54 @@ impl Timestamp {
     }
 }
 
+pub(super) fn nanos_to_fraction(nanos: u32) -> u32 {
+    (((nanos as u64) << 32) / 1_000_000_000) as u32
+}
+
+pub(super) fn fraction_to_nanos(fraction: u32) -> u32 {
+    ((fraction as u64 * 1_000_000_000) >> 32) as u32
+}
+
+// Besides the raw value the API gets the era, Unix seconds and a readable
+// UTC date.
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Timestamp", 4)?;
+        state.serialize_field("ts", &self.ts)?;
+        state.serialize_field("era", &self.date().era())?;
+        state.serialize_field("unix", &self.to_unix_secs())?;
+        state.serialize_field("utc", &self.to_datetime().to_rfc3339())?;
+        state.end()
+    }
+}
+
 impl PartialEq for Timestamp {
     fn eq(&self, other: &Timestamp) -> bool {
         self.ts == other.ts
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn system_time_round_trip() {
+        let cases = [
+            (UNIX_EPOCH + Duration::from_millis(1_700_000_000_250), 0),
+            // Half a second into era 1, the whole second is the zero
+            // timestamp, which stands for an unknown time.
+            (UNIX_EPOCH + Duration::from_millis(2_085_978_496_500), 1),
+            // 2050-06-01T00:00:00.5Z.
+            (UNIX_EPOCH + Duration::from_millis(2_537_654_400_500), 1),
+        ];
+        for (time, era) in cases {
+            let ts = NtpDateTime::from_system_time(time).timestamp();
+            assert_eq!(ts.date().era(), era);
+            assert_eq!(ts.to_system_time(), time);
+        }
+        assert_eq!(Timestamp::from_unix(2_085_978_496, 0).seconds(), 0);
+    }
+}
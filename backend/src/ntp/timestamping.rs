@@ -0,0 +1,497 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::net::UdpSocket;
+use utoipa::ToSchema;
+
+use super::local_clock::Clock;
+use super::packet::MAX_PACKET_LEN;
+use super::NtpTimestamp;
+
+// Datagrams moved per system call.
+const BATCH_SIZE: usize = 32;
+
+/// Where packet timestamps come from, from worst to best.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampMode {
+    /// Read after the socket call returned, including scheduling latency.
+    Userspace,
+    /// Receive timestamps taken by the kernel (`SO_TIMESTAMPNS`).
+    KernelReceive,
+    /// Receive and software transmit timestamps taken by the kernel
+    /// (`SO_TIMESTAMPING`).
+    KernelTransmit,
+}
+
+impl fmt::Display for TimestampMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TimestampMode::Userspace => "userspace",
+            TimestampMode::KernelReceive => "kernel receive",
+            TimestampMode::KernelTransmit => "kernel receive and transmit",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Enables the best timestamping the kernel offers on `socket`.
+pub fn enable(socket: &UdpSocket) -> TimestampMode {
+    #[cfg(target_os = "linux")]
+    {
+        linux::enable(socket)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = socket;
+        TimestampMode::Userspace
+    }
+}
+
+/// Datagram received together with the time it arrived.
+pub struct Datagram<'a> {
+    pub data: &'a [u8],
+    pub addr: SocketAddr,
+    pub local_ts: NtpTimestamp,
+}
+
+/// UDP socket returning when packets actually arrived and left. Timestamps
+/// the kernel does not deliver are read from the local clock instead.
+/// On Linux datagrams are moved in batches (`recvmmsg`/`sendmmsg`).
+pub struct TimestampedSocket {
+    socket: UdpSocket,
+    mode: TimestampMode,
+    buffers: Vec<[u8; MAX_PACKET_LEN]>,
+    // Datagrams sent, the kernel numbers transmit timestamps the same way.
+    sent: u32,
+    clock: Arc<dyn Clock>,
+}
+
+impl TimestampedSocket {
+    pub fn new(socket: UdpSocket, mode: TimestampMode, clock: Arc<dyn Clock>) -> TimestampedSocket {
+        TimestampedSocket {
+            socket,
+            mode,
+            buffers: vec![[0; MAX_PACKET_LEN]; BATCH_SIZE],
+            sent: 0,
+            clock,
+        }
+    }
+
+    pub fn mode(&self) -> TimestampMode {
+        self.mode
+    }
+
+    /// Waits for datagrams and returns all that are queued, up to a batch.
+    pub async fn recv_batch(&mut self) -> io::Result<Vec<Datagram<'_>>> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            use tokio::io::Interest;
+
+            let fd = self.socket.as_raw_fd();
+            let buffers = &mut self.buffers;
+            let received = self
+                .socket
+                .async_io(Interest::READABLE, || linux::receive(fd, buffers))
+                .await?;
+            let now = self.clock.now();
+            let clock = &self.clock;
+            Ok(received
+                .into_iter()
+                .zip(self.buffers.iter())
+                .filter_map(|((len, addr, ts), buf)| {
+                    Some(Datagram {
+                        data: &buf[..len],
+                        addr: addr?,
+                        local_ts: ts.map_or(now, |ts| clock.system_to_local(ts)),
+                    })
+                })
+                .collect())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let (len, addr) = self.socket.recv_from(&mut self.buffers[0]).await?;
+            let local_ts = self.clock.now();
+            Ok(vec![Datagram {
+                data: &self.buffers[0][..len],
+                addr,
+                local_ts,
+            }])
+        }
+    }
+
+    /// Sends datagrams and returns when each of them left.
+    pub async fn send_batch(&mut self, datagrams: &[(Vec<u8>, SocketAddr)]) -> Vec<io::Result<NtpTimestamp>> {
+        let mut results = Vec::with_capacity(datagrams.len());
+        // Datagrams sent and the number the kernel gave their timestamps.
+        let mut sent = vec![];
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            use tokio::io::Interest;
+
+            let fd = self.socket.as_raw_fd();
+            while results.len() < datagrams.len() {
+                let pending = &datagrams[results.len()..];
+                match self
+                    .socket
+                    .async_io(Interest::WRITABLE, || linux::send(fd, pending))
+                    .await
+                {
+                    Ok(count) => {
+                        let now = self.clock.now();
+                        for _ in 0..count {
+                            sent.push((results.len(), self.sent));
+                            self.sent = self.sent.wrapping_add(1);
+                            results.push(Ok(now));
+                        }
+                    }
+                    // Only the first datagram failed, go on with the rest.
+                    Err(e) => results.push(Err(e)),
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        for (data, addr) in datagrams {
+            let result = self.socket.send_to(data, *addr).await;
+            if result.is_ok() {
+                sent.push((results.len(), self.sent));
+                self.sent = self.sent.wrapping_add(1);
+            }
+            results.push(result.map(|_| self.clock.now()));
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.mode == TimestampMode::KernelTransmit {
+            use std::os::unix::io::AsRawFd;
+
+            // Timestamps are queued once the driver took the packets, which
+            // usually happens before the send returns.
+            for _ in 0..2 {
+                for (id, ts) in linux::transmit_timestamps(self.socket.as_raw_fd()) {
+                    if let Some(position) = sent.iter().position(|(_, sent_id)| *sent_id == id) {
+                        results[sent[position].0] = Ok(self.clock.system_to_local(ts));
+                        sent.swap_remove(position);
+                    }
+                }
+                if sent.is_empty() {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        }
+        results
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::ptr;
+
+    use libc::{c_int, c_uint, c_void};
+    use tokio::net::UdpSocket;
+
+    use super::TimestampMode;
+    use crate::ntp::packet::MAX_PACKET_LEN;
+    use crate::ntp::NtpTimestamp;
+
+    const CONTROL_LEN: usize = 256;
+
+    // Aligned for the control message headers.
+    type Control = [u64; CONTROL_LEN / 8];
+
+    pub fn enable(socket: &UdpSocket) -> TimestampMode {
+        let fd = socket.as_raw_fd();
+        let flags = libc::SOF_TIMESTAMPING_SOFTWARE
+            | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+            | libc::SOF_TIMESTAMPING_TX_SOFTWARE
+            | libc::SOF_TIMESTAMPING_OPT_ID
+            | libc::SOF_TIMESTAMPING_OPT_TSONLY;
+        if set_option(fd, libc::SO_TIMESTAMPING, flags).is_ok() {
+            return TimestampMode::KernelTransmit;
+        }
+        if set_option(fd, libc::SO_TIMESTAMPNS, 1).is_ok() {
+            return TimestampMode::KernelReceive;
+        }
+        TimestampMode::Userspace
+    }
+
+    fn set_option(fd: RawFd, name: c_int, value: c_uint) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                &value as *const c_uint as *const c_void,
+                mem::size_of::<c_uint>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Receives the queued datagrams into `buffers`, with their source and
+    /// kernel receive timestamp if there is one.
+    #[allow(clippy::type_complexity)]
+    pub fn receive(
+        fd: RawFd,
+        buffers: &mut [[u8; MAX_PACKET_LEN]],
+    ) -> io::Result<Vec<(usize, Option<SocketAddr>, Option<NtpTimestamp>)>> {
+        let count = buffers.len();
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; count];
+        let mut controls: Vec<Control> = vec![[0; CONTROL_LEN / 8]; count];
+        let mut iovs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .zip(controls.iter_mut())
+            .map(|((iov, addr), control)| libc::mmsghdr {
+                msg_hdr: header(iov, Some(addr), control),
+                msg_len: 0,
+            })
+            .collect();
+
+        let received = unsafe { libc::recvmmsg(fd, headers.as_mut_ptr(), count as c_uint, 0, ptr::null_mut()) };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(headers[..received as usize]
+            .iter()
+            .zip(addrs.iter())
+            .map(|(header, addr)| (header.msg_len as usize, source(addr), timestamp(&header.msg_hdr)))
+            .collect())
+    }
+
+    /// Sends datagrams from the front of `datagrams`, returning how many
+    /// went out. Fails only when the first one could not be sent.
+    pub fn send(fd: RawFd, datagrams: &[(Vec<u8>, SocketAddr)]) -> io::Result<usize> {
+        let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> =
+            datagrams.iter().map(|(_, addr)| sockaddr(addr)).collect();
+        let mut iovs: Vec<libc::iovec> = datagrams
+            .iter()
+            .map(|(data, _)| libc::iovec {
+                iov_base: data.as_ptr() as *mut c_void,
+                iov_len: data.len(),
+            })
+            .collect();
+        let mut headers: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iov, (addr, len))| {
+                let mut header: libc::msghdr = unsafe { mem::zeroed() };
+                header.msg_name = addr as *mut libc::sockaddr_storage as *mut c_void;
+                header.msg_namelen = *len;
+                header.msg_iov = iov;
+                header.msg_iovlen = 1;
+                libc::mmsghdr { msg_hdr: header, msg_len: 0 }
+            })
+            .collect();
+
+        let sent = unsafe { libc::sendmmsg(fd, headers.as_mut_ptr(), headers.len() as c_uint, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    /// Drains the error queue, returning the transmit timestamps in it with
+    /// the number of the datagram each belongs to.
+    pub fn transmit_timestamps(fd: RawFd) -> Vec<(u32, NtpTimestamp)> {
+        let mut timestamps = vec![];
+        loop {
+            let mut iov = libc::iovec {
+                iov_base: ptr::null_mut(),
+                iov_len: 0,
+            };
+            let mut control: Control = [0; CONTROL_LEN / 8];
+            let mut header = header(&mut iov, None, &mut control);
+            let result = unsafe { libc::recvmsg(fd, &mut header, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+            if result < 0 {
+                return timestamps;
+            }
+            if let (Some(id), Some(ts)) = (transmit_id(&header), timestamp(&header)) {
+                timestamps.push((id, ts));
+            }
+        }
+    }
+
+    fn header(iov: &mut libc::iovec, addr: Option<&mut libc::sockaddr_storage>, control: &mut Control) -> libc::msghdr {
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        if let Some(addr) = addr {
+            header.msg_name = addr as *mut libc::sockaddr_storage as *mut c_void;
+            header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        }
+        header.msg_iov = iov;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr() as *mut c_void;
+        header.msg_controllen = CONTROL_LEN as _;
+        header
+    }
+
+    fn source(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match addr.ss_family as c_int {
+            libc::AF_INET => {
+                let addr = unsafe { *(addr as *const _ as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { *(addr as *const _ as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    u16::from_be(addr.sin6_port),
+                    u32::from_be(addr.sin6_flowinfo),
+                    addr.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+
+    fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    /// Control messages as (level, type, data).
+    fn control_messages(header: &libc::msghdr) -> Vec<(c_int, c_int, &[u8])> {
+        let mut messages = vec![];
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(header);
+            while !cmsg.is_null() {
+                let control = &*cmsg;
+                let offset = libc::CMSG_DATA(cmsg) as usize - cmsg as usize;
+                let len = (control.cmsg_len as usize).saturating_sub(offset);
+                let data = std::slice::from_raw_parts(libc::CMSG_DATA(cmsg), len);
+                messages.push((control.cmsg_level, control.cmsg_type, data));
+                cmsg = libc::CMSG_NXTHDR(header, cmsg);
+            }
+        }
+        messages
+    }
+
+    fn timestamp(header: &libc::msghdr) -> Option<NtpTimestamp> {
+        control_messages(header).into_iter().find_map(|(level, kind, data)| {
+            if level != libc::SOL_SOCKET {
+                return None;
+            }
+            // SCM_TIMESTAMPING carries three, the first is the software one.
+            let ts = match kind {
+                libc::SCM_TIMESTAMPNS | libc::SCM_TIMESTAMPING => read_timespec(data)?,
+                _ => return None,
+            };
+            if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+                return None;
+            }
+            // time_t is 32 bits wide on some targets.
+            #[allow(clippy::unnecessary_cast)]
+            Some(NtpTimestamp::from_unix_nanos(ts.tv_sec as i64, ts.tv_nsec as u32))
+        })
+    }
+
+    /// Number of the datagram a transmit timestamp belongs to.
+    fn transmit_id(header: &libc::msghdr) -> Option<u32> {
+        control_messages(header).into_iter().find_map(|(level, kind, data)| {
+            let error = match (level, kind) {
+                (libc::SOL_IP, libc::IP_RECVERR) | (libc::SOL_IPV6, libc::IPV6_RECVERR) => {
+                    if data.len() < mem::size_of::<libc::sock_extended_err>() {
+                        return None;
+                    }
+                    unsafe { ptr::read_unaligned(data.as_ptr() as *const libc::sock_extended_err) }
+                }
+                _ => return None,
+            };
+            (error.ee_errno == libc::ENOMSG as u32 && error.ee_origin == libc::SO_EE_ORIGIN_TIMESTAMPING)
+                .then_some(error.ee_data)
+        })
+    }
+
+    fn read_timespec(data: &[u8]) -> Option<libc::timespec> {
+        if data.len() < mem::size_of::<libc::timespec>() {
+            return None;
+        }
+        Some(unsafe { ptr::read_unaligned(data.as_ptr() as *const libc::timespec) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntp::local_clock::SystemClock;
+
+    async fn socket() -> TimestampedSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mode = enable(&socket);
+        TimestampedSocket::new(socket, mode, Arc::new(SystemClock))
+    }
+
+    fn close(ts: NtpTimestamp, before: NtpTimestamp, after: NtpTimestamp) -> bool {
+        ts.diff_to_sec(&before) >= -1e-3 && after.diff_to_sec(&ts) >= -1e-3
+    }
+
+    #[tokio::test]
+    async fn loopback_batches() {
+        let mut sender = socket().await;
+        let mut receiver = socket().await;
+        #[cfg(target_os = "linux")]
+        assert_ne!(receiver.mode(), TimestampMode::Userspace);
+        let from = sender.socket.local_addr().unwrap();
+        let to = receiver.socket.local_addr().unwrap();
+
+        let before = NtpTimestamp::now();
+        let datagrams: Vec<_> = (0..3u8).map(|i| (vec![i; 48], to)).collect();
+        let sent = sender.send_batch(&datagrams).await;
+        assert_eq!(sent.len(), 3);
+
+        let mut received = vec![];
+        while received.len() < 3 {
+            for datagram in receiver.recv_batch().await.unwrap() {
+                assert_eq!(datagram.addr, from);
+                received.push((datagram.data.to_vec(), datagram.local_ts));
+            }
+        }
+        let after = NtpTimestamp::now();
+
+        for (i, (result, (data, local_ts))) in sent.into_iter().zip(received).enumerate() {
+            assert!(close(result.unwrap(), before, after));
+            assert_eq!(data, vec![i as u8; 48]);
+            assert!(close(local_ts, before, after));
+        }
+    }
+}
@@ -1,8 +1,18 @@
+use std::collections::HashMap;
+
+use std::time::Duration;
+
 use crate::http::interfaces::Iapi;
+use crate::ntp::acl::AclRule;
+use crate::ntp::listen::ListenConfig;
+use crate::ntp::peer::{MAX_POLL, MIN_POLL};
+use crate::ntp::broadcast::BroadcastConfig;
+use crate::ntp::rate_limit::RateLimitConfig;
 
 use super::interfaces::IStore;
 use async_trait::async_trait;
 use rocket::data::N;
+use serde::de::{Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, BufReader};
@@ -14,6 +24,23 @@ pub struct Settings {
     pub gps: Gps,
     pub display: Display,
     pub rtc: RTC,
+    #[serde(default)]
+    pub server: Server,
+    #[serde(default)]
+    pub listen: Listen,
+    #[serde(default)]
+    pub broadcast: Broadcast,
+    #[serde(default)]
+    pub cmdmon: Cmdmon,
+    #[serde(default)]
+    pub auth: Auth,
+    #[serde(default)]
+    pub nts: Nts,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    /// Access rules for NTP clients, the most specific prefix wins.
+    #[serde(default)]
+    pub acl: Vec<AclRule>,
 }
 
 impl Settings {
@@ -23,13 +50,27 @@ impl Settings {
                 server_list: vec!["0.ru.pool.ntp.org:123".to_string()],
                 enable: true,
                 cycle: 5000,
+                minpoll: default_minpoll(),
+                maxpoll: default_maxpoll(),
+                interleaved: false,
+            },
+            gps: Gps {
+                enable: true,
+                dispersion_ms: default_gps_dispersion_ms(),
             },
-            gps: Gps { enable: true },
             display: Display { enable: true },
             rtc: RTC {
                 enable: true,
                 cycle: 10000,
             },
+            server: Server::default(),
+            listen: Listen::default(),
+            broadcast: Broadcast::default(),
+            cmdmon: Cmdmon::default(),
+            auth: Auth::default(),
+            nts: Nts::default(),
+            rate_limit: RateLimit::default(),
+            acl: vec![],
         }
     }
 }
@@ -60,6 +101,14 @@ impl Iapi for Settings {
         self.ntp = settings.ntp.clone();
         self.gps = settings.gps.clone();
         self.rtc = settings.rtc.clone();
+        self.server = settings.server.clone();
+        self.listen = settings.listen.clone();
+        self.broadcast = settings.broadcast.clone();
+        self.cmdmon = settings.cmdmon.clone();
+        self.auth = settings.auth.clone();
+        self.set_nts(settings.nts.clone());
+        self.rate_limit = settings.rate_limit.clone();
+        self.acl = settings.acl.clone();
     }
 
     fn set_ntp(&mut self, ntp: Ntp) {
@@ -77,16 +126,114 @@ impl Iapi for Settings {
     fn set_rtc(&mut self, rtc: RTC) {
         self.rtc = rtc.clone();
     }
+
+    fn get_server(&self) -> Server {
+        self.server.clone()
+    }
+
+    fn set_server(&mut self, server: Server) {
+        self.server = server.clone();
+    }
+
+    fn get_listen(&self) -> Listen {
+        self.listen.clone()
+    }
+
+    fn set_listen(&mut self, listen: Listen) {
+        self.listen = listen.clone();
+    }
+
+    fn get_broadcast(&self) -> Broadcast {
+        self.broadcast.clone()
+    }
+
+    fn set_broadcast(&mut self, broadcast: Broadcast) {
+        self.broadcast = broadcast.clone();
+    }
+
+    fn get_cmdmon(&self) -> Cmdmon {
+        self.cmdmon.clone()
+    }
+
+    fn set_cmdmon(&mut self, cmdmon: Cmdmon) {
+        self.cmdmon = cmdmon.clone();
+    }
+
+    fn get_auth(&self) -> Auth {
+        self.auth.clone()
+    }
+
+    fn set_auth(&mut self, auth: Auth) {
+        self.auth = auth.clone();
+    }
+
+    fn get_nts(&self) -> Nts {
+        self.nts.clone()
+    }
+
+    fn set_nts(&mut self, nts: Nts) {
+        let cookie_keys = self.nts.cookie_keys.clone();
+        self.nts = Nts { cookie_keys, ..nts };
+    }
+
+    fn set_cookie_keys(&mut self, keys: Vec<CookieKey>) {
+        self.nts.cookie_keys = keys;
+    }
+
+    fn get_rate_limit(&self) -> RateLimit {
+        self.rate_limit.clone()
+    }
+
+    fn set_rate_limit(&mut self, rate_limit: RateLimit) {
+        self.rate_limit = rate_limit.clone();
+    }
+
+    fn get_acl(&self) -> Vec<AclRule> {
+        self.acl.clone()
+    }
+
+    fn set_acl(&mut self, acl: Vec<AclRule>) {
+        self.acl = acl;
+    }
 }
 #[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct Ntp {
     pub server_list: Vec<String>,
     pub enable: bool,
+    /// Initial poll period in milliseconds.
     pub cycle: u32,
+    /// Poll interval bounds, log2 seconds, limited to 3 (8 s) to 17 (36 h).
+    #[serde(default = "default_minpoll", deserialize_with = "deserialize_poll")]
+    pub minpoll: i8,
+    #[serde(default = "default_maxpoll", deserialize_with = "deserialize_poll")]
+    pub maxpoll: i8,
+    /// Request interleaved responses for accurate server transmit times.
+    #[serde(default)]
+    pub interleaved: bool,
+}
+
+fn default_minpoll() -> i8 {
+    3
+}
+
+fn default_maxpoll() -> i8 {
+    10
+}
+
+fn deserialize_poll<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<i8, D::Error> {
+    Ok(i64::deserialize(deserializer)?.clamp(MIN_POLL.into(), MAX_POLL.into()) as i8)
 }
 #[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct Gps {
     pub enable: bool,
+    /// Dispersion of a GPS sample in milliseconds: how far the receiver's
+    /// time report may lag the fix. gpsd's ept raises it when larger.
+    #[serde(default = "default_gps_dispersion_ms")]
+    pub dispersion_ms: f64,
+}
+
+fn default_gps_dispersion_ms() -> f64 {
+    1.0
 }
 #[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct Display {
@@ -97,6 +244,269 @@ pub struct RTC {
     pub enable: bool,
     pub cycle: u32,
 }
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct Server {
+    /// Do not answer clients at all while unsynchronized.
+    pub unsync_silent: bool,
+    /// Seconds to keep advertising a lost source before raising the alarm.
+    pub holdover_timeout: u32,
+    /// System peer preference per kind of source, lower values win.
+    #[serde(default = "default_gps_priority")]
+    pub gps_priority: u8,
+    #[serde(default = "default_ntp_priority")]
+    pub ntp_priority: u8,
+    #[serde(default = "default_rtc_priority")]
+    pub rtc_priority: u8,
+    /// Assumed oscillator drift in holdover on top of the learned
+    /// frequency stability, in ppm.
+    #[serde(default = "default_holdover_drift_ppm")]
+    pub holdover_drift_ppm: f64,
+    /// Estimated holdover error in milliseconds after which the advertised
+    /// stratum is stepped down.
+    #[serde(default = "default_holdover_max_error_ms")]
+    pub holdover_max_error_ms: f64,
+    /// Answer interleaved requests with accurate transmit timestamps.
+    #[serde(default = "default_interleaved")]
+    pub interleaved: bool,
+}
+
+fn default_gps_priority() -> u8 {
+    1
+}
+
+fn default_ntp_priority() -> u8 {
+    2
+}
+
+fn default_rtc_priority() -> u8 {
+    3
+}
+
+fn default_holdover_drift_ppm() -> f64 {
+    1.0
+}
+
+fn default_holdover_max_error_ms() -> f64 {
+    10.0
+}
+
+fn default_interleaved() -> bool {
+    true
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            unsync_silent: false,
+            holdover_timeout: 3600,
+            gps_priority: default_gps_priority(),
+            ntp_priority: default_ntp_priority(),
+            rtc_priority: default_rtc_priority(),
+            holdover_drift_ppm: default_holdover_drift_ppm(),
+            holdover_max_error_ms: default_holdover_max_error_ms(),
+            interleaved: default_interleaved(),
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+#[serde(default)]
+pub struct Listen {
+    /// IP addresses or interface names the NTP server binds to.
+    pub addresses: Vec<String>,
+    pub port: u16,
+    /// Sockets serving each address, 0 for one per CPU.
+    pub workers: usize,
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Self {
+            addresses: vec![String::from("0.0.0.0"), String::from("::")],
+            port: 123,
+            workers: 0,
+        }
+    }
+}
+
+impl Listen {
+    pub fn config(&self) -> ListenConfig {
+        ListenConfig {
+            addresses: self.addresses.clone(),
+            port: self.port,
+            workers: self.workers,
+        }
+    }
+}
+/// chrony command protocol for `chronyc tracking`, `sources`,
+/// `sourcestats` and `serverstats`.
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+#[serde(default)]
+pub struct Cmdmon {
+    pub enable: bool,
+    pub addresses: Vec<String>,
+    pub port: u16,
+}
+
+impl Default for Cmdmon {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            addresses: vec![String::from("0.0.0.0"), String::from("::")],
+            port: 323,
+        }
+    }
+}
+
+impl Cmdmon {
+    pub fn config(&self) -> ListenConfig {
+        ListenConfig {
+            addresses: if self.enable { self.addresses.clone() } else { vec![] },
+            port: self.port,
+            workers: 1,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+#[serde(default)]
+pub struct Broadcast {
+    pub enable: bool,
+    /// Broadcast or multicast addresses such as `192.168.1.255`,
+    /// `224.0.1.1%eth0` or `ff05::101`, or interface names.
+    pub destinations: Vec<String>,
+    pub port: u16,
+    /// Seconds between packets.
+    pub interval: u32,
+    /// Key ID to sign the packets with.
+    pub key_id: Option<u32>,
+    /// TTL or hop limit of multicast packets.
+    pub ttl: u32,
+}
+
+impl Default for Broadcast {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            destinations: vec![],
+            port: 123,
+            interval: 64,
+            key_id: None,
+            ttl: 1,
+        }
+    }
+}
+
+impl Broadcast {
+    pub fn config(&self) -> BroadcastConfig {
+        BroadcastConfig {
+            enable: self.enable,
+            destinations: self.destinations.clone(),
+            port: self.port,
+            interval: Duration::from_secs(self.interval as u64),
+            key_id: self.key_id,
+            ttl: self.ttl,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+#[serde(default)]
+pub struct Auth {
+    /// ntpd style keys file, one `id type secret` per line.
+    pub keys_file: String,
+    /// Client networks (CIDR) that are only answered when authenticated.
+    pub required_networks: Vec<String>,
+    /// Key ID to authenticate with, per `ntp.server_list` entry.
+    pub server_keys: HashMap<String, u32>,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            keys_file: String::from("config/ntp.keys"),
+            required_networks: vec![],
+            server_keys: HashMap::new(),
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+#[serde(default)]
+pub struct Nts {
+    pub enable: bool,
+    /// TCP port of the NTS-KE listener, bound on the NTP listen addresses.
+    pub ke_port: u16,
+    /// PEM certificate chain and private key for NTS-KE.
+    pub cert_file: String,
+    pub key_file: String,
+    /// NTP server and port announced to clients, empty for this host.
+    pub ntp_server: String,
+    pub ntp_port: u16,
+    /// Hours between cookie key rotations.
+    pub rotation_hours: u32,
+    /// Cookie master keys, managed by the server and never set through the API.
+    pub cookie_keys: Vec<CookieKey>,
+}
+
+impl Default for Nts {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            ke_port: 4460,
+            cert_file: String::from("config/nts.crt"),
+            key_file: String::from("config/nts.key"),
+            ntp_server: String::new(),
+            ntp_port: 123,
+            rotation_hours: 24,
+            cookie_keys: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+pub struct CookieKey {
+    pub id: u32,
+    /// Hex encoded AES-SIV key.
+    pub key: String,
+    /// Unix time of creation, in seconds.
+    pub created: i64,
+}
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+#[serde(default)]
+pub struct RateLimit {
+    pub enable: bool,
+    /// Average seconds between requests allowed per client.
+    pub interval: f64,
+    /// Requests a client may send back to back.
+    pub burst: u32,
+    /// Answer clients over the limit with a RATE kiss instead of dropping.
+    pub kod: bool,
+    /// Clients tracked individually, the rest share one limit.
+    pub table_size: usize,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            interval: 2.0,
+            burst: 16,
+            kod: true,
+            table_size: 4096,
+        }
+    }
+}
+
+impl RateLimit {
+    pub fn config(&self) -> RateLimitConfig {
+        RateLimitConfig {
+            enable: self.enable,
+            // Out of range intervals (inf, too large) fall back to the default.
+            interval: Duration::try_from_secs_f64(self.interval.max(0.0))
+                .unwrap_or_else(|_| Duration::from_secs_f64(RateLimit::default().interval)),
+            burst: self.burst,
+            kod: self.kod,
+            table_size: self.table_size,
+        }
+    }
+}
 pub struct Keeper {
     file: String,
     folder: String,
@@ -151,3 +561,18 @@ async fn file_exists(path: &str) -> bool {
         Err(_) => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_interval() {
+        let config = |interval| RateLimit { interval, ..RateLimit::default() }.config().interval;
+        assert_eq!(config(0.5), Duration::from_millis(500));
+        assert_eq!(config(-1.0), Duration::ZERO);
+        assert_eq!(config(f64::NAN), Duration::ZERO);
+        assert_eq!(config(f64::INFINITY), Duration::from_secs(2));
+        assert_eq!(config(1e300), Duration::from_secs(2));
+    }
+}
@@ -115,9 +115,9 @@ export const getMonitoring = async (callbackIn: callbackMonitoring): Promise<voi
             if (data) {
                 console.log("Request GET Monitoring:", data)
                 let payload:IMonitoring ={
-                    last_ntp: data.last_ntp.ts,
-                    last_gps: data.last_gps.ts,
-                    actial: data.actial.ts,
+                    last_ntp: Math.floor(data.last_ntp.unix),
+                    last_gps: Math.floor(data.last_gps.unix),
+                    actial: Math.floor(data.actial.unix),
                     satilite: data.satilite
                 }
                 if (callbackIn) callbackIn(payload);